@@ -0,0 +1,94 @@
+/*! Benchmarks of literal search over a log of several megabytes: line by line,
+ * the way "search" used to work, against the whole-buffer search of
+ * literal::Finder, both in memory and streaming; and for many patterns, one
+ * Literal after another against an Aho-Corasick automaton. Run with
+ *
+ * ```text
+ *     cargo bench
+ * ```
+ */
+use std::hint::black_box;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use greplite::aho_corasick::AhoCorasick;
+use greplite::matcher::{AnyOf, Literal, Matcher};
+use greplite::searcher::{Event, Searcher};
+use greplite::{search, search_with};
+
+const LINES: usize = 200_000;
+const RUNS: usize = 7;
+
+/** About 26MB of access log, with "status=503" on one line in 5000 */
+fn log() -> String {
+    let mut log = String::new();
+    for i in 0..LINES {
+        let status = if i % 5000 == 4999 { 503 } else { 200 };
+        log.push_str(&format!(
+            "2024-05-17T12:{:02}:{:02}Z INFO request id={i:08x} method=GET path=/api/v1/items/{} \
+             status={status} bytes={} took={}ms agent=\"curl/8.5.0\"\n",
+            i / 60 % 60,
+            i % 60,
+            i % 1000,
+            i * 37 % 65536,
+            i % 97,
+        ));
+    }
+    log
+}
+
+/** Run "f" RUNS times, and print the median throughput over "bytes" */
+fn bench(name: &str, bytes: usize, mut f: impl FnMut() -> usize) {
+    let mut times: Vec<Duration> = Vec::new();
+    let mut found = 0;
+    for _ in 0..RUNS {
+        let started = Instant::now();
+        found = black_box(f());
+        times.push(started.elapsed());
+    }
+    times.sort();
+    let median = times[RUNS / 2];
+    let throughput = bytes as f64 / median.as_secs_f64() / 1e6;
+    println!("{name:<40} {:>9.2?} {throughput:>9.0} MB/s {found:>8} lines", median);
+}
+
+fn stream(searcher: &Searcher, input: &str) -> usize {
+    let mut found = 0;
+    searcher
+        .search(Cursor::new(input.as_bytes()), |event| {
+            found += usize::from(matches!(event, Event::Match(_)));
+            Ok(true)
+        })
+        .unwrap();
+    found
+}
+
+fn main() {
+    let log = log();
+    println!("{} lines, {:.1} MB\n", LINES, log.len() as f64 / 1e6);
+
+    for pattern in ["status=503", "items/42", "e"] {
+        println!("pattern {pattern:?}");
+        let literal = Literal::new(pattern, true);
+        // Without a prefilter, the streaming search goes line by line
+        let lines = AnyOf(vec![Box::new(Literal::new(pattern, true))]);
+        bench("  in memory, line by line", log.len(), || search_with(&literal, &log).len());
+        bench("  in memory, whole buffer (search)", log.len(), || search(pattern, &log, true).len());
+        bench("  streaming, line by line", log.len(), || stream(&Searcher::new(&lines), &log));
+        bench("  streaming, whole buffer", log.len(), || stream(&Searcher::new(&literal), &log));
+        println!();
+    }
+
+    for count in [4, 16, 64] {
+        // Like error codes: none of them is in the log but "status=503"
+        let patterns: Vec<String> =
+            (0..count).map(|i| format!("E{:04}", i * 37)).chain(["status=503".to_string()]).collect();
+        println!("{} patterns", patterns.len());
+        let literal = |p: &String| Box::new(Literal::new(p, true)) as Box<dyn Matcher>;
+        let literals = AnyOf(patterns.iter().map(literal).collect());
+        let automaton = AhoCorasick::new(patterns.iter().map(String::as_str).enumerate(), true);
+        bench("  in memory, one literal after another", log.len(), || search_with(&literals, &log).len());
+        bench("  in memory, Aho-Corasick", log.len(), || search_with(&automaton, &log).len());
+        println!();
+    }
+}
@@ -0,0 +1,190 @@
+/*! Matching many literal patterns at once, with an Aho-Corasick automaton.
+ * The patterns are put in one trie, and each of its states gets a failure
+ * link to the state of the longest proper suffix of its path that is also
+ * in the trie. A line is then read char by char along one path through the
+ * automaton, whatever the number of patterns, where trying the patterns one
+ * by one would read it once for each.
+ *
+ * Ignoring case, the patterns and the line are case folded char by char,
+ * as for Literal, so a pattern's length in chars is its length in the line
+ */
+use std::collections::VecDeque;
+
+use crate::casefold;
+use crate::matcher::Matcher;
+
+/** The state of the empty string */
+const ROOT: usize = 0;
+
+#[derive(Debug, Default)]
+struct State {
+    /** Sorted by char */
+    next: Vec<(char, usize)>,
+    fail: usize,
+    /** The patterns that end here, also those ending in the states the
+     * failure links lead to, as (number, length in chars), longest first
+     */
+    outputs: Vec<(usize, usize)>,
+}
+
+/** The char a match starts at, counting from where the search started, its
+ * byte range and its pattern
+ */
+type Best = (usize, usize, usize, usize);
+
+/** The state reached from "state" by "c", following failure links until
+ * one of the states has a transition by "c"
+ */
+fn step(states: &[State], mut state: usize, c: char) -> usize {
+    loop {
+        let next = &states[state].next;
+        if let Ok(index) = next.binary_search_by_key(&c, |&(c, _)| c) {
+            return next[index].1;
+        }
+        if state == ROOT {
+            return ROOT;
+        }
+        state = states[state].fail;
+    }
+}
+
+/** A fixed-string matcher for many patterns, which reports the leftmost
+ * match, of those starting there the longest, like AnyOf of Literals does
+ */
+#[derive(Debug)]
+pub struct AhoCorasick {
+    states: Vec<State>,
+    case_sensitive: bool,
+    /** The length in chars of the longest pattern */
+    longest: usize,
+}
+
+impl AhoCorasick {
+    /** The patterns come numbered, so that a match says which one it is */
+    pub fn new<'p>(patterns: impl IntoIterator<Item = (usize, &'p str)>, case_sensitive: bool) -> Self {
+        let mut states = vec![State::default()];
+        let mut longest = 0;
+        for (number, pattern) in patterns {
+            let mut state = ROOT;
+            let mut len = 0;
+            for c in pattern.chars() {
+                let c = if case_sensitive { c } else { casefold::fold(c) };
+                state = match states[state].next.binary_search_by_key(&c, |&(c, _)| c) {
+                    Ok(index) => states[state].next[index].1,
+                    Err(index) => {
+                        let new = states.len();
+                        states.push(State::default());
+                        states[state].next.insert(index, (c, new));
+                        new
+                    }
+                };
+                len += 1;
+            }
+            states[state].outputs.push((number, len));
+            longest = longest.max(len);
+        }
+
+        // Breadth first, so that the states that failure links lead to, which
+        // are nearer the root, are done before the states linking to them
+        let mut queue = VecDeque::from([ROOT]);
+        while let Some(state) = queue.pop_front() {
+            for (c, child) in states[state].next.clone() {
+                let fail = match state {
+                    ROOT => ROOT,
+                    _ => step(&states, states[state].fail, c),
+                };
+                let inherited = states[fail].outputs.clone();
+                states[child].fail = fail;
+                states[child].outputs.extend(inherited);
+                queue.push_back(child);
+            }
+        }
+        for state in &mut states {
+            state.outputs.sort_by_key(|&(number, len)| (usize::MAX - len, number));
+        }
+        Self { states, case_sensitive, longest }
+    }
+
+    /** Make the longest pattern ending in "state" the best match, if it
+     * starts left of the best so far, or at the same char, in which case it
+     * is longer. "read" chars from the start of the search end at "end"
+     */
+    fn consider(&self, best: &mut Option<Best>, state: usize, read: usize, line: &str, end: usize) {
+        let Some(&(number, len)) = self.states[state].outputs.first() else { return };
+        let first = read - len;
+        if best.is_some_and(|(best_first, ..)| best_first < first) {
+            return;
+        }
+        let begin = match len {
+            0 => end,
+            _ => line[..end].char_indices().nth_back(len - 1).map_or(end, |(at, _)| at),
+        };
+        *best = Some((first, begin, end, number));
+    }
+}
+
+impl Matcher for AhoCorasick {
+    fn find_at(&self, line: &str, start: usize) -> Option<(usize, usize)> {
+        self.find_pattern_at(line, start).map(|(begin, end, _)| (begin, end))
+    }
+
+    /** Past the first match, the line is only read on for as long as a
+     * longer pattern could still start at or before it
+     */
+    fn find_pattern_at(&self, line: &str, start: usize) -> Option<(usize, usize, usize)> {
+        let mut best = None;
+        let mut state = ROOT;
+        let mut read = 0;
+        self.consider(&mut best, state, read, line, start);
+        for (offset, c) in line[start..].char_indices() {
+            if best.is_some_and(|(first, ..)| read - first >= self.longest) {
+                break;
+            }
+            state = step(&self.states, state, if self.case_sensitive { c } else { casefold::fold(c) });
+            read += 1;
+            self.consider(&mut best, state, read, line, start + offset + c.len_utf8());
+        }
+        best.map(|(_, begin, end, number)| (begin, end, number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::{AnyOf, Literal};
+
+    fn automaton(patterns: &[&str], case_sensitive: bool) -> AhoCorasick {
+        AhoCorasick::new(patterns.iter().copied().enumerate(), case_sensitive)
+    }
+
+    #[test]
+    fn leftmost_longest() {
+        let matcher = automaton(&["bc", "abcd", "b", "he", "she", "hers"], true);
+        assert_eq!(matcher.find_pattern_at("xabcdx", 0), Some((1, 5, 1)));
+        assert_eq!(matcher.find_pattern_at("xabcx", 0), Some((2, 4, 0)));
+        assert_eq!(matcher.find_pattern_at("ushers", 0), Some((1, 4, 4)));
+        assert_eq!(matcher.find_pattern_at("ushers", 2), Some((2, 6, 5)));
+        assert_eq!(matcher.find_at("ushers", 5), None);
+        assert_eq!(automaton(&[], true).find_at("anything", 0), None);
+        assert_eq!(automaton(&["x", ""], true).find_pattern_at("ax", 0), Some((0, 0, 1)));
+        // Two of the same pattern: the first one is reported
+        assert_eq!(automaton(&["me", "me"], true).find_pattern_at("Trust me", 0), Some((6, 8, 0)));
+    }
+
+    #[test]
+    fn like_any_of_literals() {
+        let patterns = ["rust", "TRUST", "st m", "e.", "ſafe", "KELVIN", "ß"];
+        let lines = ["Trust me.", "Rust: ſafe, fast", "\u{212A}elvin strasse ẞ", "", "nothing here"];
+        for case_sensitive in [true, false] {
+            let matcher = automaton(&patterns, case_sensitive);
+            let literal = |p: &&str| Box::new(Literal::new(p, case_sensitive)) as Box<dyn Matcher>;
+            let any = AnyOf(patterns.iter().map(literal).collect());
+            for line in lines {
+                for start in line.char_indices().map(|(at, _)| at) {
+                    assert_eq!(matcher.find_at(line, start), any.find_at(line, start), "{line:?} at {start}");
+                }
+            }
+        }
+        assert_eq!(automaton(&patterns, false).find_pattern_at("\u{212A}elvin", 0), Some((0, 8, 5)));
+    }
+}
@@ -0,0 +1,352 @@
+/*! Command-line parsing, in the style of grep. Short flags can be bundled
+ * ("-in") and take a value either attached or as the next argument ("-eX",
+ * "-e X"); long flags take it after a '=' or as the next argument. Flags
+ * may come before, after or between the positional arguments, and "--"
+ * makes everything after it positional, e.g. a pattern starting with '-'
+ */
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::glob::Glob;
+use crate::printer::ColorChoice;
+use crate::walk::WalkOptions;
+use crate::{Output, SearchConfig, STDIN};
+
+pub const USAGE: &str = "\
+Usage: greplite [OPTION]... PATTERN [FILE]...
+       greplite [OPTION]... -e PATTERN... [FILE]...
+       greplite [OPTION]... -f PATTERN_FILE... [FILE]...
+Search each FILE for lines containing PATTERN. Directories are searched
+recursively; a FILE of \"-\", or no FILE at all, is standard input.
+
+Options:
+  -e, --regexp=PATTERN      search for PATTERN; may be given more than once
+  -f, --file=PATTERN_FILE   search for the patterns in PATTERN_FILE, one per
+                            line; \"-\" is standard input
+  -E, --extended-regexp     PATTERN is a regular expression
+  -i, --ignore-case         ignore case distinctions
+      --no-ignore-case      do not ignore case distinctions
+  -S, --smart-case          ignore case unless PATTERN has uppercase letters
+  -w, --word-regexp         match only whole words
+  -x, --line-regexp         match only whole lines
+  -v, --invert-match        select non-matching lines
+  -n, --line-number         print the line number of each output line
+  -b, --byte-offset         print the byte offset of each output line
+  -A, --after-context=NUM   print NUM lines of context after selected lines
+  -B, --before-context=NUM  print NUM lines of context before selected lines
+  -C, --context=NUM         print NUM lines of context around selected lines
+  -c, --count               print only a count of selected lines per file
+  -l, --files-with-matches  print only the names of files with selected lines
+      --color[=WHEN]        highlight matches; WHEN is auto (the default),
+                            always or never. NO_COLOR turns off auto
+  -j, --threads=NUM         search NUM files at once; 0 means one per CPU
+      --hidden              search hidden files and directories
+      --include=GLOB        search only files whose name matches GLOB
+      --exclude=GLOB        skip files whose name matches GLOB
+      --help                display this help and exit
+      --version             display version information and exit
+
+Without -i, -S or --no-ignore-case, case is ignored unless the environment
+variable CASE_SENSITIVE is set.
+
+The exit status is 0 if a line is selected, 1 if no line is selected and 2
+if an error occurred.";
+
+/** The short form of long flags */
+const SHORT: [(char, &str); 16] = [
+    ('e', "regexp"),
+    ('f', "file"),
+    ('E', "extended-regexp"),
+    ('i', "ignore-case"),
+    ('S', "smart-case"),
+    ('w', "word-regexp"),
+    ('x', "line-regexp"),
+    ('v', "invert-match"),
+    ('n', "line-number"),
+    ('b', "byte-offset"),
+    ('A', "after-context"),
+    ('B', "before-context"),
+    ('C', "context"),
+    ('j', "threads"),
+    ('c', "count"),
+    ('l', "files-with-matches"),
+];
+
+/** Long flags that take a value */
+const WITH_VALUE: [&str; 8] =
+    ["regexp", "file", "after-context", "before-context", "context", "threads", "include", "exclude"];
+
+/** Why the arguments did not make a SearchConfig. Asking for help or the
+ * version is not a mistake, but the search does not happen either
+ */
+#[derive(Debug, PartialEq)]
+pub enum ArgsError {
+    Help,
+    Version,
+    /** The message says what is wrong; the usage text follows it */
+    Invalid(String),
+    /** A pattern file (-f) could not be read; the message says which and why */
+    Unreadable(String),
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgsError::Help => write!(f, "{USAGE}"),
+            ArgsError::Version => write!(f, "greplite {}", env!("CARGO_PKG_VERSION")),
+            ArgsError::Invalid(message) => write!(f, "{message}\n\n{USAGE}"),
+            ArgsError::Unreadable(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl Error for ArgsError {}
+
+/** The flags seen so far */
+#[derive(Default)]
+struct Parser {
+    patterns: Vec<String>,
+    /** Whether -f was given, in which case there may be no pattern at all */
+    pattern_file: bool,
+    positional: Vec<String>,
+    case_sensitive: Option<bool>,
+    smart_case: bool,
+    regex: bool,
+    word: bool,
+    whole_line: bool,
+    invert: bool,
+    line_numbers: bool,
+    byte_offsets: bool,
+    /** -A and -B win over -C, whatever the order */
+    after_context: Option<usize>,
+    before_context: Option<usize>,
+    context: usize,
+    output: Output,
+    color: ColorChoice,
+    threads: Option<usize>,
+    walk: WalkOptions,
+}
+
+impl Parser {
+    /** Apply the flag with long name "name" */
+    fn flag(&mut self, name: &str, value: Option<String>) -> Result<(), ArgsError> {
+        match (name, value) {
+            ("regexp", Some(pattern)) => self.patterns.push(pattern),
+            ("file", Some(path)) => {
+                self.patterns.extend(read_patterns(&path)?);
+                self.pattern_file = true;
+            }
+            ("after-context", Some(lines)) => self.after_context = Some(context_length(&lines)?),
+            ("before-context", Some(lines)) => self.before_context = Some(context_length(&lines)?),
+            ("context", Some(lines)) => self.context = context_length(&lines)?,
+            ("threads", Some(threads)) => {
+                let invalid = || ArgsError::Invalid(format!("{threads}: invalid number of threads"));
+                self.threads = Some(threads.parse().map_err(|_| invalid())?);
+            }
+            ("include", Some(glob)) => self.walk.include.push(Glob::new(&glob)),
+            ("exclude", Some(glob)) => self.walk.exclude.push(Glob::new(&glob)),
+            ("color" | "colour", None) => self.color = ColorChoice::Auto,
+            ("color" | "colour", Some(when)) => {
+                self.color = ColorChoice::parse(&when)
+                    .ok_or_else(|| ArgsError::Invalid(format!("invalid argument '{when}' for '--color'")))?;
+            }
+            (name, Some(_)) if !WITH_VALUE.contains(&name) => {
+                return Err(ArgsError::Invalid(format!("option '--{name}' does not take a value")));
+            }
+            ("extended-regexp", None) => self.regex = true,
+            // Whichever of these comes last wins
+            ("ignore-case", None) => (self.case_sensitive, self.smart_case) = (Some(false), false),
+            ("no-ignore-case", None) => (self.case_sensitive, self.smart_case) = (Some(true), false),
+            ("smart-case", None) => self.smart_case = true,
+            ("word-regexp", None) => self.word = true,
+            ("line-regexp", None) => self.whole_line = true,
+            ("invert-match", None) => self.invert = true,
+            ("line-number", None) => self.line_numbers = true,
+            ("byte-offset", None) => self.byte_offsets = true,
+            ("count", None) if self.output != Output::FilesWithMatches => self.output = Output::Count,
+            ("count", None) => {}
+            ("files-with-matches", None) => self.output = Output::FilesWithMatches,
+            ("hidden", None) => self.walk.hidden = true,
+            ("help", None) => return Err(ArgsError::Help),
+            ("version", None) => return Err(ArgsError::Version),
+            _ => return Err(ArgsError::Invalid(format!("unrecognized option '--{name}'"))),
+        }
+        Ok(())
+    }
+
+    /** Apply a bundle of short flags, the part of "-abc" after the '-'. The
+     * first one that takes a value gets the rest of the bundle, if any, or
+     * else the next argument
+     */
+    fn short_flags<'a>(&mut self, bundle: &str, args: &mut impl Iterator<Item = &'a String>) -> Result<(), ArgsError> {
+        for (i, c) in bundle.char_indices() {
+            let Some(&(_, name)) = SHORT.iter().find(|(short, _)| *short == c) else {
+                return Err(ArgsError::Invalid(format!("invalid option -- '{c}'")));
+            };
+            if WITH_VALUE.contains(&name) {
+                let rest = &bundle[i + c.len_utf8()..];
+                let value = if rest.is_empty() { args.next().cloned() } else { Some(rest.to_string()) };
+                let value = value.ok_or_else(|| ArgsError::Invalid(format!("option requires an argument -- '{c}'")))?;
+                return self.flag(name, Some(value));
+            }
+            self.flag(name, None)?;
+        }
+        Ok(())
+    }
+}
+
+/** The lines of the file at "path", or of standard input for "-" */
+fn read_patterns(path: &str) -> Result<Vec<String>, ArgsError> {
+    let text = match path {
+        STDIN => io::read_to_string(io::stdin()),
+        _ => fs::read_to_string(path),
+    };
+    let text = text.map_err(|e| ArgsError::Unreadable(format!("{path}: {e}")))?;
+    Ok(text.lines().map(String::from).collect())
+}
+
+fn context_length(lines: &str) -> Result<usize, ArgsError> {
+    lines.parse().map_err(|_| ArgsError::Invalid(format!("{lines}: invalid context length argument")))
+}
+
+impl SearchConfig {
+    /** Parse the command line, "args[0]" being the program name. Unless -i,
+     * -S or --no-ignore-case say otherwise, the search is case sensitive only
+     * if the CASE_SENSITIVE environment variable is set
+     */
+    pub fn from_args(args: &[String]) -> Result<Self, ArgsError> {
+        let mut parser = Parser::default();
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                parser.positional.extend(args.by_ref().cloned());
+            } else if let Some(long) = arg.strip_prefix("--") {
+                let (name, value) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None if WITH_VALUE.contains(&long) => {
+                        let value = args.next().cloned();
+                        (long, Some(value.ok_or_else(|| {
+                            ArgsError::Invalid(format!("option '--{long}' requires an argument"))
+                        })?))
+                    }
+                    None => (long, None),
+                };
+                parser.flag(name, value)?;
+            } else if let Some(bundle) = arg.strip_prefix('-').filter(|bundle| !bundle.is_empty()) {
+                parser.short_flags(bundle, &mut args)?;
+            } else {
+                parser.positional.push(arg.clone());
+            }
+        }
+
+        let mut paths = parser.positional;
+        let mut patterns = parser.patterns;
+        if patterns.is_empty() && !parser.pattern_file {
+            if paths.is_empty() {
+                return Err(ArgsError::Invalid(String::from("no pattern given")));
+            }
+            patterns.push(paths.remove(0));
+        }
+        if paths.is_empty() {
+            paths.push(STDIN.to_string());
+        }
+        let case_sensitive = parser.case_sensitive.unwrap_or_else(|| env::var("CASE_SENSITIVE").is_ok());
+
+        Ok(Self {
+            patterns,
+            paths,
+            case_sensitive,
+            smart_case: parser.smart_case,
+            regex: parser.regex,
+            word: parser.word,
+            whole_line: parser.whole_line,
+            invert: parser.invert,
+            line_numbers: parser.line_numbers,
+            byte_offsets: parser.byte_offsets,
+            before_context: parser.before_context.unwrap_or(parser.context),
+            after_context: parser.after_context.unwrap_or(parser.context),
+            output: parser.output,
+            color: parser.color,
+            threads: parser.threads.unwrap_or(1),
+            walk: parser.walk,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<SearchConfig, ArgsError> {
+        let args: Vec<String> = std::iter::once("greplite").chain(args.iter().copied()).map(String::from).collect();
+        SearchConfig::from_args(&args)
+    }
+
+    #[test]
+    fn flags_and_positionals() {
+        let config = parse(&["-inE", "a|b", "src", "--include", "*.rs", "-e", "c", "--", "-v"]).unwrap();
+        assert_eq!(config.patterns, ["c"]);
+        assert_eq!(config.paths, ["a|b", "src", "-v"]);
+        assert!(!config.case_sensitive && config.line_numbers && config.regex && !config.invert);
+        assert_eq!(config.walk.include, [Glob::new("*.rs")]);
+
+        let config = parse(&["-ccl", "-eone", "--regexp=two", "--no-ignore-case"]).unwrap();
+        assert_eq!((config.patterns, config.paths), (vec!["one".to_string(), "two".into()], vec!["-".to_string()]));
+        assert!(config.case_sensitive && config.output == Output::FilesWithMatches);
+
+        let config = parse(&["-A1", "-C", "3", "-nb", "x"]).unwrap();
+        assert_eq!((config.before_context, config.after_context), (3, 1));
+        assert!(config.line_numbers && config.byte_offsets);
+        assert!(parse(&["-iS", "x"]).unwrap().smart_case && !parse(&["-Si", "x"]).unwrap().smart_case);
+        assert_eq!(parse(&["--colour=never", "x"]).unwrap().color, ColorChoice::Never);
+        assert_eq!((parse(&["x"]).unwrap().threads, parse(&["-j8", "x"]).unwrap().threads), (1, 8));
+    }
+
+    #[test]
+    fn regex_flag() {
+        let config = parse(&["-E", "a|b", "poem.txt"]).unwrap();
+        assert!(config.regex);
+        assert_eq!((config.patterns, config.paths), (vec!["a|b".to_string()], vec!["poem.txt".to_string()]));
+    }
+
+    #[test]
+    fn walk_flags() {
+        let config = parse(&["--include=*.rs", "fn", "src", "--hidden", "--exclude=*_test.rs", "tests"]).unwrap();
+        assert_eq!(config.paths, ["src", "tests"]);
+        assert!(config.walk.hidden && config.walk.include == [Glob::new("*.rs")]);
+        assert_eq!(config.walk.exclude, [Glob::new("*_test.rs")]);
+        assert!(config.with_file_names());
+        assert!(!parse(&["fn", "Cargo.toml"]).unwrap().with_file_names());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse(&["--help", "x"]).err(), Some(ArgsError::Help));
+        assert_eq!(parse(&["-V"]).err(), Some(ArgsError::Invalid(String::from("invalid option -- 'V'"))));
+        assert!(matches!(parse(&["x", "-e"]), Err(ArgsError::Invalid(m)) if m.contains("requires an argument")));
+        assert!(matches!(parse(&["-C", "many", "x"]), Err(ArgsError::Invalid(m)) if m.contains("context length")));
+        assert!(matches!(parse(&["--count=2", "x"]), Err(ArgsError::Invalid(m)) if m.contains("does not take")));
+        assert!(parse(&[]).err().unwrap().to_string().ends_with(USAGE));
+        let unreadable = parse(&["-f", "/nonexistent/patterns"]);
+        assert!(matches!(unreadable, Err(ArgsError::Unreadable(m)) if m.starts_with("/nonexistent/patterns: ")));
+    }
+
+    #[test]
+    fn pattern_files() {
+        let path = env::temp_dir().join(format!("greplite-{}-patterns.txt", std::process::id()));
+        fs::write(&path, "E0308\nE0382\n").unwrap();
+        let file = path.to_str().unwrap();
+        let config = parse(&["-e", "E0499", "--file", file, "src"]).unwrap();
+        assert_eq!(config.patterns, ["E0499", "E0308", "E0382"]);
+        assert_eq!(config.paths, ["src"]);
+
+        // An empty pattern file matches nothing, and takes no positional
+        fs::write(&path, "").unwrap();
+        let config = parse(&["-f", file, "src"]).unwrap();
+        assert!(config.patterns.is_empty() && config.paths == ["src"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+/*! Unicode simple case folding, which maps every char to one char so that
+ * two strings are equal ignoring case if and only if their folded chars
+ * are. Unlike lowercasing a whole string, it never changes the length of a
+ * char, so match offsets in the folded text are offsets in the original.
+ *
+ * Simple folding is lowercasing, bar the chars in EXCEPTIONS, the chars
+ * whose lowercase is more than one char (like 'İ', which folds to itself),
+ * and Cherokee, which folds to uppercase. Full folding, which would also
+ * match "ß" with "ss", is left out since it changes lengths
+ */
+
+/** Chars whose simple fold is not their lowercase, by CaseFolding.txt, mostly
+ * lowercase variants folding to the common form. Sorted by the first char
+ */
+const EXCEPTIONS: [(char, char); 22] = [
+    ('\u{00B5}', '\u{03BC}'), // micro sign, to Greek mu
+    ('\u{017F}', 's'),        // long s
+    ('\u{0345}', '\u{03B9}'), // combining ypogegrammeni, to iota
+    ('\u{03C2}', '\u{03C3}'), // final sigma
+    ('\u{03D0}', '\u{03B2}'),
+    ('\u{03D1}', '\u{03B8}'),
+    ('\u{03D5}', '\u{03C6}'),
+    ('\u{03D6}', '\u{03C0}'),
+    ('\u{03F0}', '\u{03BA}'),
+    ('\u{03F1}', '\u{03C1}'),
+    ('\u{03F5}', '\u{03B5}'),
+    ('\u{1C80}', '\u{0432}'), // old Cyrillic letter variants
+    ('\u{1C81}', '\u{0434}'),
+    ('\u{1C82}', '\u{043E}'),
+    ('\u{1C83}', '\u{0441}'),
+    ('\u{1C84}', '\u{0442}'),
+    ('\u{1C85}', '\u{0442}'),
+    ('\u{1C86}', '\u{044A}'),
+    ('\u{1C87}', '\u{0463}'),
+    ('\u{1C88}', '\u{A64B}'),
+    ('\u{1E9B}', '\u{1E61}'), // long s with dot above
+    ('\u{1FBE}', '\u{03B9}'), // Greek prosgegrammeni
+];
+
+/** The simple case fold of "c" */
+pub fn fold(c: char) -> char {
+    if c.is_ascii() {
+        return c.to_ascii_lowercase();
+    }
+    match c {
+        // Cherokee folds its lowercase letters to the uppercase ones
+        '\u{13A0}'..='\u{13F5}' => c,
+        '\u{13F8}'..='\u{13FD}' => char::from_u32(c as u32 - 8).unwrap_or(c),
+        '\u{AB70}'..='\u{ABBF}' => char::from_u32(c as u32 - 0xAB70 + 0x13A0).unwrap_or(c),
+        _ => match EXCEPTIONS.binary_search_by_key(&c, |&(from, _)| from) {
+            Ok(index) => EXCEPTIONS[index].1,
+            Err(_) => single(c.to_lowercase()).unwrap_or(c),
+        },
+    }
+}
+
+/** Whether "a" and "b" are the same char ignoring case */
+pub fn eq(a: char, b: char) -> bool {
+    a == b || fold(a) == fold(b)
+}
+
+/** The single-char uppercase of "c", if it has one. Together with "c" and
+ * its fold, that covers the chars a case-insensitive char class has to try
+ */
+pub fn upper(c: char) -> Option<char> {
+    single(c.to_uppercase())
+}
+
+fn single(mut chars: impl Iterator<Item = char>) -> Option<char> {
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+/** Whether "text" has an uppercase letter, for smart case. In a regular
+ * expression the char after a backslash does not count, as "\W" or "\S"
+ * are not letters
+ */
+pub fn has_uppercase(text: &str, regex: bool) -> bool {
+    let mut escaped = false;
+    text.chars().any(|c| {
+        let upper = c.is_uppercase() && !escaped;
+        escaped = regex && c == '\\' && !escaped;
+        upper
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple_folding() {
+        assert!(eq('ẞ', 'ß') && eq('ſ', 'S') && eq('ς', 'Σ') && eq('µ', 'Μ') && eq('K', 'k'));
+        assert!(eq('Ꭰ', 'ꭰ') && eq('ᏸ', 'Ᏸ'));
+        // Turkish: 'İ' and 'ı' have no simple fold to plain 'i'
+        assert!(!eq('İ', 'i') && !eq('ı', 'i') && !eq('ı', 'I') && eq('İ', 'İ'));
+        assert!(EXCEPTIONS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn folded_search() {
+        // Simple folding matches "ẞ" with "ß", but not with "ss"
+        assert_eq!(crate::search("STRAẞE", "Strasse\nStraße", false), ["Straße"]);
+    }
+
+    #[test]
+    fn smart_case() {
+        assert!(has_uppercase("Rust", false));
+        assert!(!has_uppercase("\\W+rust", true));
+        assert!(has_uppercase("\\\\W", true));
+    }
+}
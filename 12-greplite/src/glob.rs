@@ -0,0 +1,138 @@
+/*! Shell-style glob patterns, as used by --include/--exclude and by
+ * .gitignore files:
+ *
+ * ```text
+ *     *        any run of chars except '/'
+ *     **       any run of chars, including '/'
+ *     ?        any one char except '/'
+ *     [a-z]    one char from the class; [!a-z] or [^a-z] negates it
+ *     \*       the char after the backslash, literally
+ * ```
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    DoubleStar,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+impl Glob {
+    /** Compile "pattern". Every string is a valid glob; an unclosed '[' is
+     * taken literally
+     */
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let token = match chars[i] {
+                '*' if chars.get(i + 1) == Some(&'*') => {
+                    i += 1;
+                    Token::DoubleStar
+                }
+                '*' => Token::Star,
+                '?' => Token::Any,
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    Token::Char(chars[i])
+                }
+                '[' => match Self::class(&chars[i + 1..]) {
+                    Some((token, len)) => {
+                        i += len;
+                        token
+                    }
+                    None => Token::Char('['),
+                },
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+            i += 1;
+        }
+        Self { tokens }
+    }
+
+    /** Parse a class from the chars after its '['. Returns the token and how
+     * many chars it took, including the closing ']'
+     */
+    fn class(chars: &[char]) -> Option<(Token, usize)> {
+        let negated = matches!(chars.first(), Some('!' | '^'));
+        let mut i = usize::from(negated);
+        let mut ranges = Vec::new();
+        loop {
+            let low = *chars.get(i)?;
+            if low == ']' && !ranges.is_empty() {
+                return Some((Token::Class { ranges, negated }, i + 1));
+            }
+            if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&c| c != ']') {
+                ranges.push((low, chars[i + 2]));
+                i += 3;
+            } else {
+                ranges.push((low, low));
+                i += 1;
+            }
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        Self::match_from(&self.tokens, &text)
+    }
+
+    fn match_from(tokens: &[Token], text: &[char]) -> bool {
+        let Some((token, rest)) = tokens.split_first() else {
+            return text.is_empty();
+        };
+        match token {
+            Token::Star => (0..=text.len())
+                .take_while(|&n| n == 0 || text[n - 1] != '/')
+                .any(|n| Self::match_from(rest, &text[n..])),
+            // "**/" also matches no directory at all
+            Token::DoubleStar => {
+                (rest.first() == Some(&Token::Char('/')) && Self::match_from(&rest[1..], text))
+                    || (0..=text.len()).any(|n| Self::match_from(rest, &text[n..]))
+            }
+            _ => match text.split_first() {
+                Some((&c, text_rest)) if Self::single(token, c) => Self::match_from(rest, text_rest),
+                _ => false,
+            },
+        }
+    }
+
+    fn single(token: &Token, c: char) -> bool {
+        match token {
+            Token::Char(expected) => *expected == c,
+            Token::Any => c != '/',
+            Token::Class { ranges, negated } => {
+                ranges.iter().any(|&(low, high)| low <= c && c <= high) != *negated
+            }
+            Token::Star | Token::DoubleStar => unreachable!("stars match runs of chars"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(Glob::new("*.rs").matches("main.rs"));
+        assert!(!Glob::new("*.rs").matches("src/main.rs"));
+        assert!(Glob::new("**/*.rs").matches("src/bin/main.rs"));
+        assert!(Glob::new("**/*.rs").matches("main.rs"));
+        assert!(Glob::new("src/**").matches("src/a/b"));
+        assert!(Glob::new("file?.txt").matches("file1.txt"));
+        assert!(Glob::new("[!a-c]x").matches("dx"));
+        assert!(!Glob::new("[!a-c]x").matches("bx"));
+        assert!(Glob::new("[]x").matches("[]x"));
+        assert!(Glob::new("\\*").matches("*"));
+    }
+}
@@ -1,49 +1,321 @@
-use std::env;
 use std::error::Error;
-use std::fs;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+pub mod aho_corasick;
+pub mod args;
+pub mod casefold;
+pub mod glob;
+pub mod literal;
+pub mod matcher;
+pub mod printer;
+pub mod regex;
+pub mod searcher;
+pub mod threadpool;
+pub mod walk;
+
+use aho_corasick::AhoCorasick;
+use matcher::{AnyOf, Literal, Matcher, Numbered, WholeLine, Word};
+use printer::{ColorChoice, Printer};
+use regex::{Regex, RegexError};
+use searcher::{Event, Searcher};
+pub use searcher::{Match, Span};
+use threadpool::ThreadPool;
+use walk::WalkOptions;
+
+/** The path that stands for standard input */
+pub const STDIN: &str = "-";
+
+/** What is printed for each file */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Output {
+    /** The selected lines */
+    #[default]
+    Lines,
+    /** How many lines were selected (-c) */
+    Count,
+    /** Only the file's name, if any line was selected (-l) */
+    FilesWithMatches,
+}
 
 pub struct SearchConfig {
-    pub pattern: String,
-    pub file_path: String,
+    /** A line matches if any of these does; each match says which, by its
+     * index here (-e, -f)
+     */
+    pub patterns: Vec<String>,
+    /** Files and directories to search; directories are walked recursively
+     * and "-" is standard input, which is also searched if no path is given
+     */
+    pub paths: Vec<String>,
     pub case_sensitive: bool,
+    /** Overrides "case_sensitive": each pattern is case sensitive if and
+     * only if it has an uppercase letter (-S)
+     */
+    pub smart_case: bool,
+    /** Treat the patterns as regular expressions (-E) instead of literals */
+    pub regex: bool,
+    /** Only match whole words (-w) */
+    pub word: bool,
+    /** Only match whole lines (-x) */
+    pub whole_line: bool,
+    /** Select the lines that do not match (-v) */
+    pub invert: bool,
+    /** Print line numbers before the lines (-n) */
+    pub line_numbers: bool,
+    /** Print the byte offset of each line's start before it (-b) */
+    pub byte_offsets: bool,
+    /** Lines of context to print before each selected line (-B, -C) */
+    pub before_context: usize,
+    /** Lines of context to print after each selected line (-A, -C) */
+    pub after_context: usize,
+    pub output: Output,
+    /** Whether to highlight matches, file names and line numbers (--color) */
+    pub color: ColorChoice,
+    /** How many files to search at once (-j); 0 is one per CPU */
+    pub threads: usize,
+    /** Which files a walk turns up (--hidden, --include=GLOB, --exclude=GLOB) */
+    pub walk: WalkOptions,
 }
 
 impl SearchConfig {
-    /** Return an instance of the SearchConfig struct whose pattern and
-     * file_path were sourced from the sequence of arguments passed into its
-     * constructor method
+    /** Whether matches are prefixed with the file they are in, which they are
+     * once more than one file could be searched
      */
-    pub fn from_args(args: &[String]) -> Result<Self, &'static str> {
-        if args.len() < 3 {
-            return Err("Insufficient number of arguments");
-        }
-        let pattern = args[1].clone();
-        let file_path = args[2].clone();
-        let case_sensitive = env::var("CASE_SENSITIVE").is_ok();
+    fn with_file_names(&self) -> bool {
+        self.paths.len() > 1 || self.paths.iter().any(|path| Path::new(path).is_dir())
+    }
+
+    fn searcher<'m>(&self, matcher: &'m dyn Matcher) -> Searcher<'m> {
+        Searcher::new(matcher).invert(self.invert).context(self.before_context, self.after_context)
+    }
 
-        Ok(Self { pattern, file_path, case_sensitive })
+    /** One matcher for all the patterns, with -w or -x applied to each, that
+     * numbers them in order. Literal patterns go in an Aho-Corasick automaton,
+     * one for those that are case sensitive and one for those that are not,
+     * except with -w: a shorter pattern may be a whole word where the longest
+     * one that matches at the same place is not, and the automaton only
+     * finds the longest
+     */
+    fn matcher(&self) -> Result<Box<dyn Matcher>, RegexError> {
+        let wrap = |matcher: Box<dyn Matcher>| -> Box<dyn Matcher> {
+            if self.whole_line {
+                Box::new(WholeLine(matcher))
+            } else if self.word {
+                Box::new(Word(matcher))
+            } else {
+                matcher
+            }
+        };
+        let automaton = self.patterns.len() > 1 && !self.regex && !self.word;
+        // Ignoring case, then case sensitive
+        let mut literals: [Vec<(usize, &str)>; 2] = Default::default();
+        let mut matchers = Vec::new();
+        for (number, pattern) in self.patterns.iter().enumerate() {
+            let case_sensitive = match self.smart_case {
+                true => casefold::has_uppercase(pattern, self.regex),
+                false => self.case_sensitive,
+            };
+            if automaton {
+                literals[usize::from(case_sensitive)].push((number, pattern));
+                continue;
+            }
+            let matcher: Box<dyn Matcher> = match self.regex {
+                true => Box::new(Regex::new(pattern, case_sensitive)?),
+                false => Box::new(Literal::new(pattern, case_sensitive)),
+            };
+            matchers.push(Box::new(Numbered(number, wrap(matcher))) as Box<dyn Matcher>);
+        }
+        for (case_sensitive, patterns) in [false, true].into_iter().zip(literals) {
+            if !patterns.is_empty() {
+                matchers.push(wrap(Box::new(AhoCorasick::new(patterns, case_sensitive))));
+            }
+        }
+        Ok(match matchers.len() {
+            1 => matchers.remove(0),
+            _ => Box::new(AnyOf(matchers)),
+        })
     }
 }
 
 /**
  * Given the search config, perform the search and print the results
- * The type Result<(), Box<dyn Error>> means that this function returns a
- * "Result" enum. The "Ok" variant will return the "unit" type "()", which
- * has exactly one value "()" that indicates "Nothing" (but that is not a
- * NULL!). The "Err" variant will return some type that implements the "Error"
- * trait, but the concrete type will not be known until runtime
+ * The type Result<bool, Box<dyn Error>> means that this function returns a
+ * "Result" enum. The "Ok" variant will return whether any line was selected,
+ * which decides the exit status like grep's. The "Err" variant will return
+ * some type that implements the "Error" trait, but the concrete type will
+ * not be known until runtime
  */
-pub fn greplite(config: SearchConfig) -> Result<(), Box<dyn Error>> {
-    println!("Search config: pattern={} file_path={}", config.pattern, config.file_path);
-    let content = fs::read_to_string(config.file_path)?;
+pub fn greplite(config: SearchConfig) -> Result<bool, Box<dyn Error>> {
+    let matcher: Arc<dyn Matcher> = Arc::from(config.matcher()?);
+    let printer = Printer {
+        file_names: config.with_file_names(),
+        line_numbers: config.line_numbers,
+        byte_offsets: config.byte_offsets,
+        color: config.color.enabled(),
+    };
+    let files = walk::files(&config.paths, &config.walk);
+    let mut out = io::stdout().lock();
 
-    let results = search(&config.pattern, &content, config.case_sensitive);
+    /* A file that cannot be read is reported and skipped, so that one bad
+     * file does not stop the search of all the others
+     */
+    let mut failures = 0;
+    let mut selected = false;
+    let mut report = |result: io::Result<bool>| match result {
+        Ok(found) => {
+            selected |= found;
+            Ok(())
+        }
+        // Whoever reads our output has stopped, e.g. "greplite x | head"
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Err(e),
+        Err(e) => {
+            eprintln!("greplite: {e}");
+            failures += 1;
+            Ok(())
+        }
+    };
+
+    let threads = match config.threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let stopped = if threads > 1 && files.len() > 1 {
+        search_parallel(Arc::new(config), matcher, printer, files, threads, &mut out, &mut report)
+    } else {
+        let searcher = config.searcher(matcher.as_ref());
+        let mut printed = false;
+        files.into_iter().try_for_each(|path| {
+            let result = path.and_then(|path| search_path(&config, &searcher, &printer, &path, printed, &mut out));
+            printed |= *result.as_ref().unwrap_or(&false);
+            report(result)
+        })
+    };
+    if stopped.is_err() {
+        return Ok(selected);
+    }
+
+    match failures {
+        0 => Ok(selected),
+        1 => Err("1 file could not be searched".into()),
+        n => Err(format!("{n} files could not be searched").into()),
+    }
+}
+
+/** Search "files" on "threads" workers (-j). Each file's output is gathered
+ * in a buffer and written in one piece, in the order of "files", so that the
+ * output is the same as from searching them one after another. "report"
+ * gets each file's outcome, in the same order, and stops the search by
+ * returning an error. A search that panicked is a failure of its file
+ */
+fn search_parallel(
+    config: Arc<SearchConfig>,
+    matcher: Arc<dyn Matcher>,
+    printer: Printer,
+    files: Vec<io::Result<PathBuf>>,
+    threads: usize,
+    out: &mut impl Write,
+    report: &mut impl FnMut(io::Result<bool>) -> io::Result<()>,
+) -> io::Result<()> {
+    let with_context = config.output == Output::Lines && config.before_context + config.after_context > 0;
+    // To say whose search panicked
+    let names: Vec<String> =
+        files.iter().map(|path| path.as_ref().map_or_else(|_| String::new(), |path| display_name(path))).collect();
+    let job_printer = printer.clone();
+    let results = ThreadPool::new(threads).map_ordered(files, move |path| {
+        let searcher = config.searcher(matcher.as_ref());
+        let mut block = Vec::new();
+        // The separator from the files before is up to the one writing the blocks
+        let result = path.and_then(|path| search_path(&config, &searcher, &job_printer, &path, false, &mut block));
+        (block, result)
+    });
 
-    for result in results {
-        println!("{result}");
+    let mut printed = false;
+    for (result, name) in results.zip(names) {
+        let (block, result) = result.unwrap_or_else(|_| {
+            (Vec::new(), Err(io::Error::other(format!("search of {name} panicked"))))
+        });
+        if printed && with_context && matches!(result, Ok(true)) {
+            printer.separator(out)?;
+        }
+        out.write_all(&block)?;
+        printed |= *result.as_ref().unwrap_or(&false);
+        report(result)?;
+    }
+    Ok(())
+}
+
+/** Search the file at "path", or stdin if it is "-", and write what
+ * "config.output" asks for to "out". Binary files are skipped. With context
+ * lines, a file's lines are separated from those printed before, if
+ * "printed" says there were any. Returns whether any line was selected,
+ * which for the selected lines is whether any lines were printed
+ */
+fn search_path(
+    config: &SearchConfig,
+    searcher: &Searcher,
+    printer: &Printer,
+    path: &Path,
+    printed: bool,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    let mut reader: Box<dyn BufRead> = if path == Path::new(STDIN) {
+        Box::new(io::stdin().lock())
+    } else {
+        let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        Box::new(BufReader::new(file))
+    };
+    if searcher::is_binary(&mut reader)? {
+        return Ok(false);
+    }
+
+    let name = display_name(path);
+    match config.output {
+        Output::Lines => {
+            let separate = printed && config.before_context + config.after_context > 0;
+            let mut first = true;
+            searcher.search(reader, |event| {
+                if first && separate {
+                    printer.separator(out)?;
+                }
+                first = false;
+                printer.event(out, &name, &event)?;
+                Ok(true)
+            })?;
+            Ok(!first)
+        }
+        Output::Count => {
+            let mut count = 0;
+            searcher.search(reader, |event| {
+                count += usize::from(matches!(event, Event::Match(_)));
+                Ok(true)
+            })?;
+            printer.count(out, &name, count)?;
+            Ok(count > 0)
+        }
+        Output::FilesWithMatches => {
+            let mut selected = false;
+            // The first selected line settles it
+            searcher.search(reader, |event| {
+                selected = matches!(event, Event::Match(_));
+                Ok(!selected)
+            })?;
+            if selected {
+                printer.file_name(out, &name)?;
+            }
+            Ok(selected)
+        }
     }
+}
 
-    return Ok(());
+/** How matches name the file they are in */
+fn display_name(path: &Path) -> String {
+    match path == Path::new(STDIN) {
+        true => String::from("(standard input)"),
+        false => path.display().to_string(),
+    }
 }
 
 /**
@@ -52,24 +324,45 @@ pub fn greplite(config: SearchConfig) -> Result<(), Box<dyn Error>> {
  * is valid
  */
 pub fn search<'a>(pattern: &str, content: &'a str, case_sensitive: bool) -> Vec<&'a str> {
-    let mut results: Vec<&str> = Vec::new();
-    let mut pattern = pattern.to_string();
-    if !case_sensitive { pattern = pattern.to_lowercase() };
-
-    for line in content.lines() {
-        if line.contains(&pattern)
-            || (!case_sensitive && line.to_lowercase().contains(&pattern))
-        {
-            results.push(line);
-        }
+    let matcher = Literal::new(pattern, case_sensitive);
+    match matcher.prefilter() {
+        // Look for the pattern in the whole content, not line by line
+        Some(finder) => literal::matching_lines(finder, content).map(|(_, line)| line).collect(),
+        None => search_with(&matcher, content),
     }
+}
 
-    return results;
+/**
+ * Like "search", but with any Matcher deciding which lines match, e.g. a
+ * compiled regular expression
+ */
+pub fn search_with<'a, M: Matcher + ?Sized>(matcher: &M, content: &'a str) -> Vec<&'a str> {
+    content.lines().filter(|line| matcher.is_match(line)).collect()
+}
+
+/**
+ * Like "search_with", but each matching line comes with its number, the
+ * byte offset of its start within "content", and where in it the matches are
+ */
+pub fn search_matches<'a, M: Matcher + ?Sized>(matcher: &M, content: &'a str) -> Vec<Match<'a>> {
+    let mut results = Vec::new();
+    let mut byte_offset = 0;
+    for (index, raw) in content.split_inclusive('\n').enumerate() {
+        let line = raw.strip_suffix('\n').unwrap_or(raw);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let spans = searcher::spans(matcher, line);
+        if !spans.is_empty() || matcher.is_match(line) {
+            results.push(Match { line_number: index + 1, byte_offset, line, spans });
+        }
+        byte_offset += raw.len();
+    }
+    results
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
 
     #[test]
     fn one_result() {
@@ -94,5 +387,54 @@ Trust me.";
 
         assert_eq!(vec!["Rust:", "Trust me."], search(pattern, content, false));
     }
-}
 
+    #[test]
+    fn regex_search() {
+        let content = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+        let regex = Regex::new("^[A-Z][a-z]+[.:]$|f[a-z]+t", true).unwrap();
+
+        assert_eq!(vec!["Rust:", "safe, fast, productive."], search_with(&regex, content));
+    }
+
+    #[test]
+    fn match_positions() {
+        let content = "Rust:\r\nsafe, fast, productive.\nPick three.\nTrust me.";
+        let results = search_matches(&Literal::new("st", true), content);
+        let positions: Vec<_> = results
+            .iter()
+            .map(|m| (m.line_number, m.byte_offset, m.spans.iter().map(|s| (s.begin, s.end)).collect()))
+            .collect();
+        assert_eq!(positions, [(1, 0, vec![(2, 4)]), (2, 7, vec![(8, 10)]), (4, 43, vec![(3, 5)])]);
+        assert_eq!(results[2].line, "Trust me.");
+    }
+
+    #[test]
+    fn whether_lines_were_selected() {
+        let path = std::env::temp_dir().join(format!("greplite-{}-selected.txt", std::process::id()));
+        fs::write(&path, "Rust:\nTrust me.\n").unwrap();
+        let search = |args: &[&str]| {
+            let args: Vec<String> = ["greplite", "-l"].iter().chain(args).map(|arg| arg.to_string()).collect();
+            greplite(SearchConfig::from_args(&args).unwrap())
+        };
+        let file = path.to_str().unwrap();
+        assert!(search(&["rust", file]).unwrap());
+        assert!(!search(&["python", file]).unwrap());
+        assert!(!search(&["-v", "-e", "", file]).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(search(&["rust", file]).is_err());
+    }
+
+    #[test]
+    fn which_pattern_matched() {
+        let args = ["greplite", "-S", "-e", "E0382", "-e", "borrow", "-e", "Moved", "-e", "e0"];
+        let config = SearchConfig::from_args(&args.map(String::from)).unwrap();
+        let matcher = config.matcher().unwrap();
+        let results = search_matches(matcher.as_ref(), "error[E0382]: borrow of moved value\nMoved here");
+        let patterns: Vec<Vec<_>> = results.iter().map(|m| m.spans.iter().map(|s| s.pattern).collect()).collect();
+        assert_eq!(patterns, [vec![0, 1], vec![2]]);
+    }
+}
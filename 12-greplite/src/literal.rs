@@ -0,0 +1,164 @@
+/*! Fast search for a fixed byte string in a large buffer, so that a literal
+ * pattern can be looked for in a whole file at once rather than line by
+ * line, with only the lines it turns up in being split out.
+ *
+ * Finder uses Boyer-Moore-Horspool: the window is compared from its last
+ * byte, and on a mismatch it is moved on by how far that last byte is from
+ * the end of the needle, up to the whole needle length. The longer the
+ * needle, the more of the haystack is never looked at
+ */
+
+#[derive(Clone, Debug)]
+pub struct Finder {
+    needle: Vec<u8>,
+    /** How far to move the window on, by its last byte */
+    skip: [usize; 256],
+}
+
+impl Finder {
+    pub fn new(needle: &[u8]) -> Self {
+        let mut skip = [needle.len().max(1); 256];
+        if let Some((_, init)) = needle.split_last() {
+            for (i, &b) in init.iter().enumerate() {
+                skip[usize::from(b)] = init.len() - i;
+            }
+        }
+        Self { needle: needle.to_vec(), skip }
+    }
+
+    pub fn needle(&self) -> &[u8] {
+        &self.needle
+    }
+
+    /** The offset of the first occurrence of the needle in "haystack" */
+    pub fn find(&self, haystack: &[u8]) -> Option<usize> {
+        let Some((&last, init)) = self.needle.split_last() else {
+            return Some(0);
+        };
+        if init.is_empty() {
+            return memchr(last, haystack);
+        }
+        let mut at = 0;
+        while let Some(window) = haystack.get(at..at + self.needle.len()) {
+            let end = window[init.len()];
+            if end == last && &window[..init.len()] == init {
+                return Some(at);
+            }
+            at += self.skip[usize::from(end)];
+        }
+        None
+    }
+}
+
+/** A u64 with every byte set to 0x01 */
+const ONES: u64 = u64::MAX / 255;
+
+/** Whether any of the eight bytes of "word" is the byte repeated in "splat".
+ * XOR zeroes the bytes that are, and only a zero byte borrows into its own
+ * top bit when one is subtracted from every byte
+ */
+fn has_byte(word: u64, splat: u64) -> bool {
+    let x = word ^ splat;
+    x.wrapping_sub(ONES) & !x & (ONES << 7) != 0
+}
+
+/** The offset of the first "byte" in "haystack". Eight bytes are checked at
+ * a time, and only the eight with a hit are looked at one by one
+ */
+pub fn memchr(byte: u8, haystack: &[u8]) -> Option<usize> {
+    let splat = ONES * u64::from(byte);
+    let mut chunks = haystack.chunks_exact(8);
+    for (i, chunk) in chunks.by_ref().enumerate() {
+        let word = u64::from_ne_bytes(chunk.try_into().expect("chunks of eight"));
+        if has_byte(word, splat) {
+            return chunk.iter().position(|&b| b == byte).map(|at| 8 * i + at);
+        }
+    }
+    let rest = chunks.remainder();
+    rest.iter().position(|&b| b == byte).map(|at| haystack.len() - rest.len() + at)
+}
+
+/** The offset of the last "byte" in "haystack", found like by memchr */
+pub fn memrchr(byte: u8, haystack: &[u8]) -> Option<usize> {
+    let splat = ONES * u64::from(byte);
+    let mut chunks = haystack.rchunks_exact(8);
+    for (i, chunk) in chunks.by_ref().enumerate() {
+        let word = u64::from_ne_bytes(chunk.try_into().expect("chunks of eight"));
+        if has_byte(word, splat) {
+            let start = haystack.len() - 8 * (i + 1);
+            return chunk.iter().rposition(|&b| b == byte).map(|at| start + at);
+        }
+    }
+    chunks.remainder().iter().rposition(|&b| b == byte)
+}
+
+/** How many times "byte" occurs in "haystack". Counting into a u8 lets the
+ * compiler compare many bytes per instruction, and a u8 cannot overflow
+ * counting 255 bytes
+ */
+pub fn count(byte: u8, haystack: &[u8]) -> usize {
+    haystack
+        .chunks(255)
+        .map(|chunk| usize::from(chunk.iter().fold(0u8, |n, &b| n + u8::from(b == byte))))
+        .sum()
+}
+
+/** The lines of "content" that contain the needle of "finder", each with
+ * the byte offset of its start, in the same form as str::lines gives them.
+ * The needle must not be empty or contain a "\n"
+ */
+pub fn matching_lines<'f, 'a: 'f>(finder: &'f Finder, content: &'a str) -> impl Iterator<Item = (usize, &'a str)> + 'f {
+    let bytes = content.as_bytes();
+    let mut from = 0;
+    std::iter::from_fn(move || {
+        if from > bytes.len() {
+            return None;
+        }
+        let hit = from + finder.find(&bytes[from..])?;
+        let start = memrchr(b'\n', &bytes[from..hit]).map_or(from, |newline| from + newline + 1);
+        // A line ends at a "\n", and a "\r" before it is left out; both are
+        // ASCII, so the slices lie on char boundaries
+        let Some(end) = memchr(b'\n', &bytes[hit..]).map(|newline| hit + newline) else {
+            from = bytes.len() + 1;
+            return Some((start, &content[start..]));
+        };
+        from = end + 1;
+        let line = &content[start..end];
+        Some((start, line.strip_suffix('\r').unwrap_or(line)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_like_str_find() {
+        let haystack = "the quick brown fox jumps over the lazy dog; the end";
+        for needle in ["the", "e", "dog;", "the end", "lazy cat", "", "quick brown fox jumps over the lazy dog; the end!"] {
+            assert_eq!(Finder::new(needle.as_bytes()).find(haystack.as_bytes()), haystack.find(needle), "{needle:?}");
+        }
+    }
+
+    #[test]
+    fn byte_scans() {
+        let haystack = b"0123456789\nabcdefgh\n\x80xyz";
+        for (start, end) in [(0, haystack.len()), (3, 24), (11, 20), (0, 7)] {
+            let part = &haystack[start..end];
+            for byte in [b'\n', b'0', b'z', 0x80, b'-'] {
+                assert_eq!(memchr(byte, part), part.iter().position(|&b| b == byte));
+                assert_eq!(memrchr(byte, part), part.iter().rposition(|&b| b == byte));
+                assert_eq!(count(byte, part), part.iter().filter(|&&b| b == byte).count());
+            }
+        }
+    }
+
+    #[test]
+    fn lines_with_hits() {
+        let content = "Rust:\r\nsafe, fast, productive.\nPick three.\nTrust me.";
+        let finder = Finder::new(b"st");
+        let lines: Vec<_> = matching_lines(&finder, content).collect();
+        assert_eq!(lines, [(0, "Rust:"), (7, "safe, fast, productive."), (43, "Trust me.")]);
+        assert_eq!(matching_lines(&Finder::new(b"me."), content).collect::<Vec<_>>(), [(43, "Trust me.")]);
+    }
+}
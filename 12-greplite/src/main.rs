@@ -2,21 +2,32 @@ use std::env;
 use std::process;
 
 use greplite::{ self, SearchConfig };
+use greplite::args::ArgsError;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let config = SearchConfig::from_args(&args).unwrap_or_else(|err| {
+        /* Asking for help or the version is answered on stdout, since the
+         * user wanted it; a mistake goes to stderr with the usage
+         */
+        if let ArgsError::Help | ArgsError::Version = err {
+            println!("{err}");
+            process::exit(0);
+        }
         eprintln!("Problem parsing arguments: {err}");
-        process::exit(1);
+        process::exit(2);
     });
 
-    /* "if let" is used instead of "unwrap" or "expect" because we expect
-     * the function greplite() to return no meaningful result; instead, we
-     * only care for when the function fails
+    /* Like grep, the exit status says how the search went: 0 if any line
+     * was selected, 1 if none was, and 2 if something went wrong, so that
+     * scripts can test it with "if greplite -e ...; then"
      */
-    if let Err(e) = greplite::greplite(config) {
-        eprintln!("The search failed: {e}");
-        process::exit(1);
+    match greplite::greplite(config) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("The search failed: {e}");
+            process::exit(2);
+        }
     }
 }
-
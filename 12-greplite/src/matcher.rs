@@ -0,0 +1,223 @@
+/*! The Matcher trait abstracts over what counts as a match within a line, so
+ * that the same search loop can run a plain substring search or a regular
+ * expression
+ */
+
+use crate::casefold;
+use crate::literal::Finder;
+
+/** Matchers are shared by the threads of a parallel search */
+pub trait Matcher: Send + Sync {
+    /** Return the byte range of the first match in "line" that starts at or
+     * after the byte offset "start", which must lie on a char boundary
+     */
+    fn find_at(&self, line: &str, start: usize) -> Option<(usize, usize)>;
+
+    /** Like find_at, but also say which pattern matched, by its number. A
+     * matcher of one pattern is pattern 0, unless it is Numbered
+     */
+    fn find_pattern_at(&self, line: &str, start: usize) -> Option<(usize, usize, usize)> {
+        self.find_at(line, start).map(|(begin, end)| (begin, end, 0))
+    }
+
+    /** Whether "line" contains a match anywhere
+     */
+    fn is_match(&self, line: &str) -> bool {
+        self.find_at(line, 0).is_some()
+    }
+
+    /** Bytes that every matching line contains, if there are any such. They
+     * let a search skip through a whole buffer to the lines worth matching
+     */
+    fn prefilter(&self) -> Option<&Finder> {
+        None
+    }
+}
+
+/** Chars that make up words, for -w and \b */
+pub(crate) fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/** A fixed string, matched exactly or ignoring case
+ */
+pub struct Literal {
+    pattern: String,
+    /** The pattern's chars case folded, if case is ignored */
+    folded: Option<Vec<char>>,
+    /** The pattern, if it can be looked for in a buffer of many lines */
+    finder: Option<Finder>,
+}
+
+impl Literal {
+    pub fn new(pattern: &str, case_sensitive: bool) -> Self {
+        let folded = (!case_sensitive).then(|| pattern.chars().map(casefold::fold).collect());
+        let finder = (case_sensitive && !pattern.is_empty() && !pattern.contains('\n'))
+            .then(|| Finder::new(pattern.as_bytes()));
+        Self { pattern: pattern.to_string(), folded, finder }
+    }
+
+    /** If "folded" matches "line" at "start" regardless of case, return
+     * where the match ends. Chars are folded one by one, so nothing is
+     * allocated, and the offsets are those of the line itself
+     */
+    fn match_ignoring_case(folded: &[char], line: &str, start: usize) -> Option<usize> {
+        let mut chars = line[start..].char_indices();
+        for &expected in folded {
+            let (_, actual) = chars.next()?;
+            if casefold::fold(actual) != expected {
+                return None;
+            }
+        }
+        Some(chars.next().map_or(line.len(), |(offset, _)| start + offset))
+    }
+}
+
+impl Matcher for Literal {
+    fn find_at(&self, line: &str, start: usize) -> Option<(usize, usize)> {
+        let Some(folded) = &self.folded else {
+            return line[start..]
+                .find(&self.pattern)
+                .map(|offset| (start + offset, start + offset + self.pattern.len()));
+        };
+        line[start..]
+            .char_indices()
+            .map(|(offset, _)| start + offset)
+            .chain(std::iter::once(line.len()))
+            .find_map(|from| Self::match_ignoring_case(folded, line, from).map(|end| (from, end)))
+    }
+
+    fn prefilter(&self) -> Option<&Finder> {
+        self.finder.as_ref()
+    }
+}
+
+/** Only matches that are whole words, i.e. not preceded or followed by a
+ * word char (-w). A match that is not is skipped, and the search goes on
+ * from the char after where it started
+ */
+pub struct Word(pub Box<dyn Matcher>);
+
+impl Matcher for Word {
+    fn find_at(&self, line: &str, start: usize) -> Option<(usize, usize)> {
+        self.find_pattern_at(line, start).map(|(begin, end, _)| (begin, end))
+    }
+
+    fn find_pattern_at(&self, line: &str, start: usize) -> Option<(usize, usize, usize)> {
+        let mut from = start;
+        loop {
+            let (begin, end, pattern) = self.0.find_pattern_at(line, from)?;
+            let before = line[..begin].chars().next_back();
+            let after = line[end..].chars().next();
+            if !before.is_some_and(is_word) && !after.is_some_and(is_word) {
+                return Some((begin, end, pattern));
+            }
+            from = begin + line[begin..].chars().next()?.len_utf8();
+        }
+    }
+
+    fn prefilter(&self) -> Option<&Finder> {
+        self.0.prefilter()
+    }
+}
+
+/** Only a match that spans the whole line (-x). Since a match is the
+ * longest of those starting leftmost, the line matches as a whole if and
+ * only if the first match does
+ */
+pub struct WholeLine(pub Box<dyn Matcher>);
+
+impl Matcher for WholeLine {
+    fn find_at(&self, line: &str, start: usize) -> Option<(usize, usize)> {
+        self.find_pattern_at(line, start).map(|(begin, end, _)| (begin, end))
+    }
+
+    fn find_pattern_at(&self, line: &str, start: usize) -> Option<(usize, usize, usize)> {
+        match self.0.find_pattern_at(line, 0) {
+            Some((0, end, pattern)) if start == 0 && end == line.len() => Some((0, end, pattern)),
+            _ => None,
+        }
+    }
+
+    fn prefilter(&self) -> Option<&Finder> {
+        self.0.prefilter()
+    }
+}
+
+/** Matches wherever any of several matchers does, e.g. for repeated -e.
+ * The leftmost match wins, of those starting there the longest, and of
+ * those the one of the lowest-numbered pattern
+ */
+pub struct AnyOf(pub Vec<Box<dyn Matcher>>);
+
+impl Matcher for AnyOf {
+    fn find_at(&self, line: &str, start: usize) -> Option<(usize, usize)> {
+        self.find_pattern_at(line, start).map(|(begin, end, _)| (begin, end))
+    }
+
+    fn find_pattern_at(&self, line: &str, start: usize) -> Option<(usize, usize, usize)> {
+        self.0
+            .iter()
+            .filter_map(|matcher| matcher.find_pattern_at(line, start))
+            .min_by_key(|&(begin, end, pattern)| (begin, usize::MAX - end, pattern))
+    }
+}
+
+/** A matcher of one pattern, with the number it is to report (its index
+ * among the patterns of a search)
+ */
+pub struct Numbered(pub usize, pub Box<dyn Matcher>);
+
+impl Matcher for Numbered {
+    fn find_at(&self, line: &str, start: usize) -> Option<(usize, usize)> {
+        self.1.find_at(line, start)
+    }
+
+    fn find_pattern_at(&self, line: &str, start: usize) -> Option<(usize, usize, usize)> {
+        self.1.find_at(line, start).map(|(begin, end)| (begin, end, self.0))
+    }
+
+    fn prefilter(&self) -> Option<&Finder> {
+        self.1.prefilter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_spans() {
+        let matcher = Literal::new("st", true);
+        assert_eq!(matcher.find_at("Trust me", 0), Some((3, 5)));
+        assert_eq!(matcher.find_at("Trust me", 4), None);
+
+        let matcher = Literal::new("RUST", false);
+        assert_eq!(matcher.find_at("Trust me", 0), Some((1, 5)));
+        assert!(Literal::new("", false).is_match(""));
+        assert!(!matcher.is_match("Tru"));
+
+        let matcher = Literal::new("STRASSE ẞ", false);
+        assert_eq!(matcher.find_at("Die strasse ß", 0), Some((4, 14)));
+        assert!(!Literal::new("DIŞ", false).is_match("dış"));
+    }
+
+    #[test]
+    fn wrappers() {
+        let word = Word(Box::new(Literal::new("me", true)));
+        assert_eq!(word.find_at("meme, me", 0), Some((6, 8)));
+        assert!(!word.is_match("mean_me2"));
+
+        let whole = WholeLine(Box::new(Literal::new("trust me", false)));
+        assert!(whole.is_match("Trust me"));
+        assert!(!whole.is_match("Trust me."));
+
+        let any = AnyOf(vec![Box::new(Literal::new("st", true)), Box::new(Literal::new("rust", true))]);
+        assert_eq!(any.find_at("Trust me", 0), Some((1, 5)));
+
+        let numbered = |n, pattern| Box::new(Numbered(n, Box::new(Literal::new(pattern, true)))) as Box<dyn Matcher>;
+        let any = Word(Box::new(AnyOf(vec![numbered(4, "me"), numbered(7, "rust")])));
+        assert_eq!(any.find_pattern_at("Trust me", 0), Some((6, 8, 4)));
+        assert_eq!(any.find_pattern_at("rust me", 0), Some((0, 4, 7)));
+    }
+}
@@ -0,0 +1,163 @@
+/*! Writing search results the way GNU grep does. Each line is preceded by
+ * whichever of its file name, line number and byte offset were asked for,
+ * each followed by ':' on a selected line and by '-' on a context line.
+ * Groups of lines that do not follow each other are separated by "--".
+ *
+ * In color, matches are bold red, file names magenta, line numbers and
+ * byte offsets green and separators cyan, as with grep --color
+ */
+use std::env;
+use std::fmt::Display;
+use std::io::{self, IsTerminal, Write};
+
+use crate::searcher::{Event, Match};
+
+/** SGR parameters of the parts of the output */
+const MATCH: &str = "01;31";
+const FILE_NAME: &str = "35";
+const NUMBER: &str = "32";
+const SEPARATOR: &str = "36";
+
+/** When to color the output (--color) */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorChoice {
+    /** If stdout is a terminal and NO_COLOR is not set */
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(choice: &str) -> Option<Self> {
+        match choice {
+            "auto" | "tty" | "if-tty" => Some(ColorChoice::Auto),
+            "always" | "yes" | "force" => Some(ColorChoice::Always),
+            "never" | "no" | "none" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+
+    /** Whether to color output to stdout. See https://no-color.org for
+     * NO_COLOR, which an explicit --color=always overrides
+     */
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Printer {
+    pub file_names: bool,
+    pub line_numbers: bool,
+    pub byte_offsets: bool,
+    pub color: bool,
+}
+
+impl Printer {
+    /** Write "event", from the file called "name" */
+    pub fn event(&self, out: &mut impl Write, name: &str, event: &Event) -> io::Result<()> {
+        match event {
+            Event::Match(line) => self.line(out, name, line, ':'),
+            Event::Context(line) => self.line(out, name, line, '-'),
+            Event::Break => self.separator(out),
+        }
+    }
+
+    pub fn separator(&self, out: &mut impl Write) -> io::Result<()> {
+        self.paint(out, SEPARATOR, "--")?;
+        writeln!(out)
+    }
+
+    /** Write "text", in the color "sgr" if coloring */
+    fn paint(&self, out: &mut impl Write, sgr: &str, text: impl Display) -> io::Result<()> {
+        match self.color {
+            true => write!(out, "\x1b[{sgr}m{text}\x1b[0m"),
+            false => write!(out, "{text}"),
+        }
+    }
+
+    /** Write "text" and the separator that follows a prefix */
+    fn prefix(&self, out: &mut impl Write, sgr: &str, text: impl Display, separator: char) -> io::Result<()> {
+        self.paint(out, sgr, text)?;
+        self.paint(out, SEPARATOR, separator)
+    }
+
+    fn line(&self, out: &mut impl Write, name: &str, line: &Match, separator: char) -> io::Result<()> {
+        if self.file_names {
+            self.prefix(out, FILE_NAME, name, separator)?;
+        }
+        if self.line_numbers {
+            self.prefix(out, NUMBER, line.line_number, separator)?;
+        }
+        if self.byte_offsets {
+            self.prefix(out, NUMBER, line.byte_offset, separator)?;
+        }
+        // Only the matches on selected lines stand out
+        if !self.color || separator != ':' {
+            return writeln!(out, "{}", line.line);
+        }
+        let mut written = 0;
+        for span in &line.spans {
+            write!(out, "{}", &line.line[written..span.begin])?;
+            self.paint(out, MATCH, &line.line[span.begin..span.end])?;
+            written = span.end;
+        }
+        writeln!(out, "{}", &line.line[written..])
+    }
+
+    /** Write the number of selected lines in the file called "name" (-c) */
+    pub fn count(&self, out: &mut impl Write, name: &str, count: usize) -> io::Result<()> {
+        if self.file_names {
+            self.prefix(out, FILE_NAME, name, ':')?;
+        }
+        writeln!(out, "{count}")
+    }
+
+    /** Write the name of a file with selected lines (-l) */
+    pub fn file_name(&self, out: &mut impl Write, name: &str) -> io::Result<()> {
+        self.paint(out, FILE_NAME, name)?;
+        writeln!(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::searcher::Span;
+
+    fn span(begin: usize, end: usize) -> Span {
+        Span { begin, end, pattern: 0 }
+    }
+
+    #[test]
+    fn gnu_prefixes() {
+        let printer = Printer { file_names: true, line_numbers: true, byte_offsets: true, color: false };
+        let line = Match { line_number: 3, byte_offset: 17, line: "Trust me.", spans: vec![span(3, 5)] };
+        let mut out = Vec::new();
+        printer.event(&mut out, "poem.txt", &Event::Match(line.clone())).unwrap();
+        printer.event(&mut out, "poem.txt", &Event::Break).unwrap();
+        printer.event(&mut out, "poem.txt", &Event::Context(line)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "poem.txt:3:17:Trust me.\n--\npoem.txt-3-17-Trust me.\n");
+    }
+
+    #[test]
+    fn colored_matches() {
+        let printer = Printer { line_numbers: true, color: true, ..Printer::default() };
+        let line = Match { line_number: 3, byte_offset: 0, line: "Trust me.", spans: vec![span(1, 3), span(6, 8)] };
+        let mut out = Vec::new();
+        printer.event(&mut out, "-", &Event::Match(line)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b[32m3\x1b[0m\x1b[36m:\x1b[0mT\x1b[01;31mru\x1b[0mst \x1b[01;31mme\x1b[0m.\n"
+        );
+        assert_eq!(ColorChoice::parse("always"), Some(ColorChoice::Always));
+        assert!(!ColorChoice::Never.enabled());
+    }
+}
@@ -0,0 +1,611 @@
+/*! A small regular expression engine. A pattern is parsed into a syntax tree,
+ * compiled into the instructions of a Thompson NFA and run as a Pike VM,
+ * which follows every possible path through the pattern at once. Matching
+ * therefore takes time linear in the length of the line, whatever the
+ * pattern, and there is no backtracking to blow up.
+ *
+ * The syntax is close to POSIX extended regular expressions (grep -E):
+ *
+ * ```text
+ *     .            any character
+ *     [abc] [^a-z] character classes, with [:alpha:], [:digit:] and friends
+ *     \d \w \s     digits, word characters, whitespace; \D \W \S negate them
+ *     ^ $          start and end of the line
+ *     \b \B        word boundary and its negation
+ *     (a|b)        groups and alternation; (?:a|b) is the same
+ *     * + ?        repetition, as well as {n}, {n,} and {n,m}
+ * ```
+ *
+ * Like grep, the leftmost match wins, and of the matches starting there the
+ * longest one
+ */
+use std::error::Error;
+use std::fmt;
+
+use crate::casefold;
+use crate::matcher::{is_word, Matcher};
+
+/** Counted repetitions are compiled into copies of the repeated expression,
+ * so they are capped to keep the program small
+ */
+const MAX_REPEAT: u32 = 1000;
+
+#[derive(Debug, PartialEq)]
+pub struct RegexError {
+    /** Index of the char in the pattern where the problem was found */
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid regular expression at char {}: {}", self.position, self.message)
+    }
+}
+
+impl Error for RegexError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Assertion {
+    LineStart,
+    LineEnd,
+    WordBoundary,
+    NotWordBoundary,
+}
+
+/** A set of chars given as inclusive ranges
+ */
+#[derive(Clone, Debug, PartialEq)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat { node: Box<Node>, min: u32, max: Option<u32> },
+}
+
+#[derive(Clone, Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    /** Continue at both targets; the first one has priority */
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+impl Class {
+    fn contains(&self, c: char, ignore_case: bool) -> bool {
+        let within = |c: char| self.ranges.iter().any(|&(low, high)| low <= c && c <= high);
+        let found = within(c) || (ignore_case && {
+            let folded = casefold::fold(c);
+            within(folded) || casefold::upper(folded).is_some_and(within) || casefold::upper(c).is_some_and(within)
+        });
+        found != self.negated
+    }
+
+    /** The ranges of a shorthand like \d, or of a [:name:] inside brackets
+     */
+    fn named(name: &str) -> Option<Vec<(char, char)>> {
+        let ranges = match name {
+            "digit" => vec![('0', '9')],
+            "alpha" => vec![('a', 'z'), ('A', 'Z')],
+            "alnum" => vec![('0', '9'), ('a', 'z'), ('A', 'Z')],
+            "word" => vec![('0', '9'), ('a', 'z'), ('A', 'Z'), ('_', '_')],
+            "upper" => vec![('A', 'Z')],
+            "lower" => vec![('a', 'z')],
+            "space" => vec![(' ', ' '), ('\t', '\r')],
+            "blank" => vec![(' ', ' '), ('\t', '\t')],
+            "xdigit" => vec![('0', '9'), ('a', 'f'), ('A', 'F')],
+            "punct" => vec![('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+            _ => return None,
+        };
+        Some(ranges)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: &str) -> Result<T, RegexError> {
+        Err(RegexError { position: self.pos, message: message.to_string() })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn parse(mut self) -> Result<Node, RegexError> {
+        let node = self.alternation()?;
+        if self.pos < self.chars.len() {
+            return self.error("unmatched )");
+        }
+        Ok(node)
+    }
+
+    fn alternation(&mut self) -> Result<Node, RegexError> {
+        let mut branches = vec![self.concatenation()?];
+        while self.eat('|') {
+            branches.push(self.concatenation()?);
+        }
+        if branches.len() == 1 {
+            return Ok(branches.pop().unwrap());
+        }
+        Ok(Node::Alternate(branches))
+    }
+
+    fn concatenation(&mut self) -> Result<Node, RegexError> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            items.push(self.repetition()?);
+        }
+        match items.len() {
+            0 => Ok(Node::Empty),
+            1 => Ok(items.pop().unwrap()),
+            _ => Ok(Node::Concat(items)),
+        }
+    }
+
+    fn repetition(&mut self) -> Result<Node, RegexError> {
+        let mut node = self.atom()?;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('{') => match self.counted()? {
+                    Some(bounds) => bounds,
+                    None => break,
+                },
+                Some(c @ ('*' | '+' | '?')) => {
+                    self.pos += 1;
+                    match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        _ => (0, Some(1)),
+                    }
+                }
+                _ => break,
+            };
+            node = Node::Repeat { node: Box::new(node), min, max };
+        }
+        Ok(node)
+    }
+
+    /** Parse "{n}", "{n,}" or "{n,m}" at the current position. Anything else
+     * is not a repetition, and the brace is taken literally
+     */
+    fn counted(&mut self) -> Result<Option<(u32, Option<u32>)>, RegexError> {
+        let start = self.pos;
+        self.pos += 1;
+        let number = |parser: &mut Parser| {
+            let from = parser.pos;
+            while parser.peek().is_some_and(|c| c.is_ascii_digit()) {
+                parser.pos += 1;
+            }
+            let digits: String = parser.chars[from..parser.pos].iter().collect();
+            digits.parse::<u32>().ok()
+        };
+        let bounds = match number(self) {
+            Some(min) if self.eat('}') => Some((min, Some(min))),
+            Some(min) if self.eat(',') => {
+                let max = number(self);
+                self.eat('}').then_some((min, max))
+            }
+            _ => None,
+        };
+        let Some((min, max)) = bounds else {
+            self.pos = start;
+            return Ok(None);
+        };
+        if max.is_some_and(|max| max < min) {
+            return self.error("repetition bounds out of order");
+        }
+        if max.unwrap_or(min) > MAX_REPEAT {
+            return self.error(&format!("repetition count above {MAX_REPEAT}"));
+        }
+        Ok(Some((min, max)))
+    }
+
+    fn atom(&mut self) -> Result<Node, RegexError> {
+        let c = self.chars[self.pos];
+        self.pos += 1;
+        match c {
+            '(' => {
+                if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                }
+                let node = self.alternation()?;
+                if !self.eat(')') {
+                    return self.error("missing )");
+                }
+                Ok(node)
+            }
+            '[' => self.class(),
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Assert(Assertion::LineStart)),
+            '$' => Ok(Node::Assert(Assertion::LineEnd)),
+            '*' | '+' | '?' => {
+                self.pos -= 1;
+                self.error("nothing to repeat")
+            }
+            '\\' => self.escape(),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, RegexError> {
+        let Some(c) = self.peek() else {
+            return self.error("trailing backslash");
+        };
+        self.pos += 1;
+        let class = |name: &str, negated: bool| {
+            Node::Class(Class { ranges: Class::named(name).unwrap(), negated })
+        };
+        Ok(match c {
+            'd' | 'D' => class("digit", c == 'D'),
+            'w' | 'W' => class("word", c == 'W'),
+            's' | 'S' => class("space", c == 'S'),
+            'b' => Node::Assert(Assertion::WordBoundary),
+            'B' => Node::Assert(Assertion::NotWordBoundary),
+            'n' => Node::Char('\n'),
+            't' => Node::Char('\t'),
+            c => Node::Char(c),
+        })
+    }
+
+    /** Parse a bracket expression; the opening [ has been consumed
+     */
+    fn class(&mut self) -> Result<Node, RegexError> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.peek() else {
+                return self.error("missing ]");
+            };
+            self.pos += 1;
+            let low = match c {
+                ']' if !first => break,
+                '[' if self.peek() == Some(':') => {
+                    let rest: String = self.chars[self.pos + 1..].iter().collect();
+                    let Some(end) = rest.find(":]") else {
+                        return self.error("unterminated [: class");
+                    };
+                    let name = &rest[..end];
+                    match Class::named(name) {
+                        Some(named) => ranges.extend(named),
+                        None => return self.error(&format!("unknown class [:{name}:]")),
+                    }
+                    self.pos += name.chars().count() + 3;
+                    first = false;
+                    continue;
+                }
+                '\\' => match self.escape()? {
+                    Node::Char(c) => c,
+                    Node::Class(class) if !class.negated => {
+                        ranges.extend(class.ranges);
+                        first = false;
+                        continue;
+                    }
+                    _ => return self.error("escape not allowed in brackets"),
+                },
+                c => c,
+            };
+            first = false;
+            // a '-' right before the closing bracket is literal
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let high = match self.chars[self.pos] {
+                    '\\' => {
+                        self.pos += 1;
+                        match self.escape()? {
+                            Node::Char(c) => c,
+                            _ => return self.error("invalid range end"),
+                        }
+                    }
+                    c => {
+                        self.pos += 1;
+                        c
+                    }
+                };
+                if high < low {
+                    return self.error("range out of order");
+                }
+                ranges.push((low, high));
+            } else {
+                ranges.push((low, low));
+            }
+        }
+        Ok(Node::Class(Class { ranges, negated }))
+    }
+}
+
+/** Turns a syntax tree into a program for the VM
+ */
+struct Compiler {
+    program: Vec<Inst>,
+}
+
+impl Compiler {
+    fn emit(&mut self, inst: Inst) -> usize {
+        self.program.push(inst);
+        self.program.len() - 1
+    }
+
+    /** Point the Split or Jmp at "at" to "target" once it is known
+     */
+    fn patch(&mut self, at: usize, target: usize) {
+        match &mut self.program[at] {
+            Inst::Split(_, second) => *second = target,
+            Inst::Jmp(to) => *to = target,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+
+    fn compile(&mut self, node: &Node) {
+        match node {
+            Node::Empty => {}
+            Node::Char(c) => {
+                self.emit(Inst::Char(*c));
+            }
+            Node::Any => {
+                self.emit(Inst::Any);
+            }
+            Node::Class(class) => {
+                self.emit(Inst::Class(class.clone()));
+            }
+            Node::Assert(assertion) => {
+                self.emit(Inst::Assert(*assertion));
+            }
+            Node::Concat(items) => items.iter().for_each(|item| self.compile(item)),
+            Node::Alternate(branches) => {
+                let mut exits = Vec::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 == branches.len() {
+                        self.compile(branch);
+                        break;
+                    }
+                    let split = self.emit(Inst::Split(0, 0));
+                    self.program[split] = Inst::Split(split + 1, 0);
+                    self.compile(branch);
+                    exits.push(self.emit(Inst::Jmp(0)));
+                    let next = self.program.len();
+                    self.patch(split, next);
+                }
+                let end = self.program.len();
+                exits.into_iter().for_each(|exit| self.patch(exit, end));
+            }
+            Node::Repeat { node, min, max } => {
+                for _ in 0..*min {
+                    self.compile(node);
+                }
+                match max {
+                    None => {
+                        let split = self.emit(Inst::Split(0, 0));
+                        self.program[split] = Inst::Split(split + 1, 0);
+                        self.compile(node);
+                        self.emit(Inst::Jmp(split));
+                        let end = self.program.len();
+                        self.patch(split, end);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            let split = self.emit(Inst::Split(0, 0));
+                            self.program[split] = Inst::Split(split + 1, 0);
+                            splits.push(split);
+                            self.compile(node);
+                        }
+                        let end = self.program.len();
+                        splits.into_iter().for_each(|split| self.patch(split, end));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/** The threads of the VM at one position in the line: which instruction
+ * each one is at and where its match started. A thread per instruction is
+ * enough, since two threads at the same instruction behave the same from
+ * there on; the one that started first is kept
+ */
+struct Threads {
+    list: Vec<(usize, usize)>,
+    seen: Vec<bool>,
+}
+
+impl Threads {
+    fn new(size: usize) -> Self {
+        Self { list: Vec::with_capacity(size), seen: vec![false; size] }
+    }
+
+    fn clear(&mut self) {
+        self.seen.fill(false);
+        self.list.clear();
+    }
+}
+
+/** A compiled regular expression
+ */
+#[derive(Clone, Debug)]
+pub struct Regex {
+    program: Vec<Inst>,
+    ignore_case: bool,
+}
+
+impl Regex {
+    pub fn new(pattern: &str, case_sensitive: bool) -> Result<Self, RegexError> {
+        let node = Parser { chars: pattern.chars().collect(), pos: 0 }.parse()?;
+        let mut compiler = Compiler { program: Vec::new() };
+        compiler.compile(&node);
+        compiler.emit(Inst::Match);
+        Ok(Self { program: compiler.program, ignore_case: !case_sensitive })
+    }
+
+    /** Whether "assertion" holds at byte offset "at" of "line"
+     */
+    fn holds(assertion: Assertion, line: &str, at: usize) -> bool {
+        let before = line[..at].chars().next_back().is_some_and(is_word);
+        let after = line[at..].chars().next().is_some_and(is_word);
+        match assertion {
+            Assertion::LineStart => at == 0,
+            Assertion::LineEnd => at == line.len(),
+            Assertion::WordBoundary => before != after,
+            Assertion::NotWordBoundary => before == after,
+        }
+    }
+
+    /** Add a thread at "pc" to "threads", following jumps and assertions
+     * right away so that only threads waiting for a char are kept
+     */
+    fn add(&self, threads: &mut Threads, pc: usize, start: usize, line: &str, at: usize) {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if threads.seen[pc] {
+                continue;
+            }
+            threads.seen[pc] = true;
+            match &self.program[pc] {
+                Inst::Jmp(to) => stack.push(*to),
+                // pushed in reverse, so that the first target is followed first
+                Inst::Split(first, second) => stack.extend([*second, *first]),
+                Inst::Assert(assertion) => {
+                    if Self::holds(*assertion, line, at) {
+                        stack.push(pc + 1);
+                    }
+                }
+                _ => threads.list.push((pc, start)),
+            }
+        }
+    }
+
+    fn step(&self, inst: &Inst, c: char) -> bool {
+        match inst {
+            Inst::Char(expected) => *expected == c || (self.ignore_case && casefold::eq(*expected, c)),
+            Inst::Any => c != '\n',
+            Inst::Class(class) => class.contains(c, self.ignore_case),
+            _ => false,
+        }
+    }
+}
+
+impl Matcher for Regex {
+    fn find_at(&self, line: &str, start: usize) -> Option<(usize, usize)> {
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        let mut found: Option<(usize, usize)> = None;
+        let mut at = start;
+        loop {
+            // a new attempt starts at every position until something matched
+            if found.is_none() {
+                self.add(&mut current, 0, at, line, at);
+            }
+            if current.list.is_empty() && found.is_some() {
+                break;
+            }
+            let c = line[at..].chars().next();
+            for i in 0..current.list.len() {
+                let (pc, from) = current.list[i];
+                if found.is_some_and(|(leftmost, _)| from > leftmost) {
+                    break;
+                }
+                match &self.program[pc] {
+                    Inst::Match => {
+                        // threads are ordered by where they started, so this
+                        // is the leftmost match, and it only grows longer
+                        found = Some((from, at));
+                    }
+                    inst => {
+                        if let Some(c) = c.filter(|&c| self.step(inst, c)) {
+                            let after = at + c.len_utf8();
+                            self.add(&mut next, pc + 1, from, line, after);
+                        }
+                    }
+                }
+            }
+            let Some(c) = line[at..].chars().next() else { break };
+            at += c.len_utf8();
+            current.clear();
+            std::mem::swap(&mut current, &mut next);
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, line: &str) -> Option<(usize, usize)> {
+        Regex::new(pattern, true).unwrap().find_at(line, 0)
+    }
+
+    #[test]
+    fn literals_classes_and_anchors() {
+        assert_eq!(find("duct", "safe, fast, productive."), Some((15, 19)));
+        assert_eq!(find("f[a-z]st", "safe, fast"), Some((6, 10)));
+        assert_eq!(find("[^a-z ,]", "safe, fast!"), Some((10, 11)));
+        assert_eq!(find("[[:digit:]]+", "abc 2024-01"), Some((4, 8)));
+        assert_eq!(find(r"\d{2}-\d\d", "abc 2024-01"), Some((6, 11)));
+        assert_eq!(find("^Pick", "Pick three."), Some((0, 4)));
+        assert_eq!(find("^three", "Pick three."), None);
+        assert_eq!(find(r"three\.$", "Pick three."), Some((5, 11)));
+        assert_eq!(find(r"\bst", "Trust stay"), Some((6, 8)));
+        assert_eq!(find("a.c", "a\u{e9}c"), Some((0, 4)));
+    }
+
+    #[test]
+    fn alternation_groups_and_repetition() {
+        assert_eq!(find("cat|dog", "hotdog"), Some((3, 6)));
+        assert_eq!(find("(ab)+", "xababab"), Some((1, 7)));
+        assert_eq!(find("(?:ab|a)(c|bcd)", "abcd"), Some((0, 4)));
+        assert_eq!(find("colou?r", "color"), Some((0, 5)));
+        assert_eq!(find("a{2,3}", "aaaa"), Some((0, 3)));
+        assert_eq!(find("x*", "abc"), Some((0, 0)));
+        assert_eq!(find("(a*)*b", "aaab"), Some((0, 4)));
+        assert_eq!(find("a{,2}", "a{,2}"), Some((0, 5)));
+        assert_eq!(find("(ab){2}*c", "ababababc"), Some((0, 9)));
+        assert_eq!(find(&"a?".repeat(30), &"a".repeat(30)), Some((0, 30)));
+    }
+
+    #[test]
+    fn ignore_case() {
+        let regex = Regex::new("rust|[x-z]+", false).unwrap();
+        assert_eq!(regex.find_at("Trust me", 0), Some((1, 5)));
+        assert_eq!(regex.find_at("XYZ", 0), Some((0, 3)));
+        let regex = Regex::new("[r-t]+ẞ", false).unwrap();
+        assert_eq!(regex.find_at("MAſSß", 0), Some((2, 7)));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        for pattern in ["(ab", "ab)", "*a", "[abc", "a{3,1}", "[z-a]", "\\", "[[:nope:]]", "a{1001}"] {
+            assert!(Regex::new(pattern, true).is_err(), "accepted {pattern:?}");
+        }
+        assert_eq!(Regex::new("ab)", true).unwrap_err().position, 2);
+    }
+}
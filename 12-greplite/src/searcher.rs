@@ -0,0 +1,314 @@
+/*! Searching a stream line by line, for input that is too large to hold in
+ * memory or that never ends, like a pipe. Only one line is held at a time,
+ * in a buffer that is reused for the next, so memory use does not grow with
+ * the input (unless its lines do, or the number of lines of context before
+ * a match that is asked for)
+ */
+use std::collections::VecDeque;
+use std::io::{self, BufRead};
+
+use crate::literal::{self, Finder};
+use crate::matcher::Matcher;
+
+/** How much more input a whole-buffer search reads at a time */
+const CHUNK: usize = 64 * 1024;
+
+/** A line reported by a search, and where in the input it was found */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match<'l> {
+    /** Counting from 1 */
+    pub line_number: usize,
+    /** Of the start of the line, counting from 0 at the start of the input */
+    pub byte_offset: usize,
+    /** Without its "\n" or "\r\n" */
+    pub line: &'l str,
+    /** Where in the line the patterns matched, left to right */
+    pub spans: Vec<Span>,
+}
+
+/** A byte range of a line that a pattern matched */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub begin: usize,
+    pub end: usize,
+    /** Which pattern matched, as numbered by the matcher */
+    pub pattern: usize,
+}
+
+/** What a search reports, in input order */
+#[derive(Debug, PartialEq)]
+pub enum Event<'l> {
+    /** A selected line */
+    Match(Match<'l>),
+    /** A line that is not selected but is near one that is (-A, -B, -C) */
+    Context(Match<'l>),
+    /** Lines were left out between this group of lines and the one before */
+    Break,
+}
+
+pub struct Searcher<'m> {
+    matcher: &'m dyn Matcher,
+    invert: bool,
+    before: usize,
+    after: usize,
+}
+
+impl<'m> Searcher<'m> {
+    pub fn new(matcher: &'m dyn Matcher) -> Self {
+        Self { matcher, invert: false, before: 0, after: 0 }
+    }
+
+    /** Select the lines that do not match instead (-v) */
+    pub fn invert(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+
+    /** Also report this many lines before and after each selected line */
+    pub fn context(mut self, before: usize, after: usize) -> Self {
+        self.before = before;
+        self.after = after;
+        self
+    }
+
+    fn report<'l>(&self, line_number: usize, byte_offset: usize, line: &'l str) -> Match<'l> {
+        Match { line_number, byte_offset, line, spans: spans(self.matcher, line) }
+    }
+
+    /** Call "found" with every selected line from "reader", and the context
+     * around it, until it returns false. Invalid UTF-8 is replaced with
+     * U+FFFD, so that text with the odd stray byte can still be searched
+     */
+    pub fn search<R, F>(&self, mut reader: R, mut found: F) -> io::Result<()>
+    where
+        R: BufRead,
+        F: FnMut(Event) -> io::Result<bool>,
+    {
+        if let (Some(finder), false, 0, 0) = (self.matcher.prefilter(), self.invert, self.before, self.after) {
+            return self.search_buffers(finder, reader, found);
+        }
+        let mut buf = Vec::new();
+        let mut offset = 0;
+        // The lines before the current one that may yet be context, oldest first
+        let mut before: VecDeque<(usize, usize, String)> = VecDeque::with_capacity(self.before);
+        let mut after = 0;
+        let mut last_reported: Option<usize> = None;
+
+        // Reports a line, after a Break if it does not follow the last one
+        let with_context = self.before + self.after > 0;
+        let mut emit = |event: Event, number: usize| -> io::Result<bool> {
+            let gap = with_context && last_reported.is_some_and(|last| number > last + 1);
+            last_reported = Some(number);
+            if gap && !found(Event::Break)? {
+                return Ok(false);
+            }
+            found(event)
+        };
+
+        for number in 1.. {
+            buf.clear();
+            let read = reader.read_until(b'\n', &mut buf)?;
+            if read == 0 {
+                break;
+            }
+            // Only allocates if the line is not valid UTF-8
+            let line = String::from_utf8_lossy(trim_newline(&buf));
+            if self.matcher.is_match(&line) != self.invert {
+                for (number, offset, line) in before.drain(..) {
+                    if !emit(Event::Context(self.report(number, offset, &line)), number)? {
+                        return Ok(());
+                    }
+                }
+                if !emit(Event::Match(self.report(number, offset, &line)), number)? {
+                    return Ok(());
+                }
+                after = self.after;
+            } else if after > 0 {
+                after -= 1;
+                if !emit(Event::Context(self.report(number, offset, &line)), number)? {
+                    return Ok(());
+                }
+            } else if self.before > 0 {
+                if before.len() == self.before {
+                    before.pop_front();
+                }
+                before.push_back((number, offset, line.into_owned()));
+            }
+            offset += read;
+        }
+        Ok(())
+    }
+
+    /** Like "search", for when "finder" picks out every line that may match
+     * and only matching lines are wanted. Input is read in large blocks of
+     * whole lines, the finder skips through each block, and only the lines
+     * it stops in are split out and matched. The lines in between are only
+     * counted, for the line numbers
+     */
+    fn search_buffers<R, F>(&self, finder: &Finder, mut reader: R, mut found: F) -> io::Result<()>
+    where
+        R: BufRead,
+        F: FnMut(Event) -> io::Result<bool>,
+    {
+        let mut buf = Vec::with_capacity(CHUNK);
+        // Of the first line in "buf", and where it starts in the input
+        let mut number = 1;
+        let mut offset = 0;
+        loop {
+            let filled = buf.len();
+            buf.resize(filled + CHUNK, 0);
+            let read = loop {
+                match reader.read(&mut buf[filled..]) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result,
+                }
+            };
+            buf.truncate(filled + read.as_ref().map_or(0, |&read| read));
+            let read = read?;
+            // A line that is not complete yet waits for the rest of it
+            let end = match literal::memrchr(b'\n', &buf[filled..]) {
+                Some(newline) => filled + newline + 1,
+                None if read == 0 => buf.len(),
+                None => continue,
+            };
+
+            let block = &buf[..end];
+            let mut from = 0;
+            while let Some(hit) = finder.find(&block[from..]).map(|hit| from + hit) {
+                let start = literal::memrchr(b'\n', &block[from..hit]).map_or(from, |newline| from + newline + 1);
+                let newline = literal::memchr(b'\n', &block[hit..]).map(|newline| hit + newline);
+                number += literal::count(b'\n', &block[from..start]);
+                let line = String::from_utf8_lossy(trim_newline(&block[start..newline.unwrap_or(end)]));
+                if self.matcher.is_match(&line) && !found(Event::Match(self.report(number, offset + start, &line)))? {
+                    return Ok(());
+                }
+                from = newline.map_or(end, |newline| newline + 1);
+                number += usize::from(newline.is_some());
+            }
+            number += literal::count(b'\n', &block[from..]);
+            offset += end;
+            buf.drain(..end);
+            if read == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/** Every match of "matcher" in "line", left to right and not overlapping.
+ * After an empty match the next one is looked for a char further on
+ */
+pub fn spans<M: Matcher + ?Sized>(matcher: &M, line: &str) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    while start <= line.len() {
+        let Some((begin, end, pattern)) = matcher.find_pattern_at(line, start) else { break };
+        if end > begin {
+            spans.push(Span { begin, end, pattern });
+            start = end;
+        } else {
+            start = end + line[end..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    spans
+}
+
+/** Whether the input looks binary, i.e. has a NUL byte in its first block.
+ * Nothing is consumed, so the reader can still be searched afterwards
+ */
+pub fn is_binary<R: BufRead>(reader: &mut R) -> io::Result<bool> {
+    Ok(reader.fill_buf()?.contains(&0))
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::{AnyOf, Literal};
+    use std::io::Cursor;
+
+    /** Each event as "<number>:<line>" for matches, "<number>-<line>" for
+     * context and "--" for breaks, like grep -n prints them
+     */
+    fn collect(searcher: &Searcher, input: &[u8]) -> Vec<String> {
+        let mut found = Vec::new();
+        searcher
+            .search(Cursor::new(input), |event| {
+                found.push(match event {
+                    Event::Match(m) => format!("{}:{}", m.line_number, m.line),
+                    Event::Context(m) => format!("{}-{}", m.line_number, m.line),
+                    Event::Break => String::from("--"),
+                });
+                Ok(true)
+            })
+            .unwrap();
+        found
+    }
+
+    #[test]
+    fn lines_from_a_stream() {
+        let input: &[u8] = b"Rust:\r\nsafe, fast\xff productive.\nPick three.\nTrust me.";
+        let matcher = Literal::new("st", true);
+        let found = collect(&Searcher::new(&matcher), input);
+        assert_eq!(found, ["1:Rust:", "2:safe, fast\u{FFFD} productive.", "4:Trust me."]);
+        assert_eq!(collect(&Searcher::new(&matcher).invert(true), input), ["3:Pick three."]);
+
+        let mut binary = Cursor::new(b"ELF\0\x01".as_slice());
+        assert!(is_binary(&mut binary).unwrap());
+        assert!(!is_binary(&mut Cursor::new(input)).unwrap());
+    }
+
+    #[test]
+    fn context_groups() {
+        let input = b"a\nb\nmatch\nc\nd\ne\nf\nmatch\nmatch\ng\n";
+        let matcher = Literal::new("match", true);
+        let found = collect(&Searcher::new(&matcher).context(1, 2), input);
+        assert_eq!(found, ["2-b", "3:match", "4-c", "5-d", "--", "7-f", "8:match", "9:match", "10-g"]);
+        let found = collect(&Searcher::new(&matcher).context(0, 3), input);
+        assert_eq!(found, ["3:match", "4-c", "5-d", "6-e", "--", "8:match", "9:match", "10-g"]);
+    }
+
+    #[test]
+    fn offsets_and_spans() {
+        let matcher = Literal::new("o", true);
+        let mut found = Vec::new();
+        Searcher::new(&matcher)
+            .search(Cursor::new("xy\r\nfoo\n"), |event| {
+                if let Event::Match(m) = event {
+                    found.push((m.line_number, m.byte_offset, m.spans));
+                }
+                Ok(true)
+            })
+            .unwrap();
+        let spans = vec![Span { begin: 1, end: 2, pattern: 0 }, Span { begin: 2, end: 3, pattern: 0 }];
+        assert_eq!(found, [(2, 4, spans)]);
+    }
+
+    #[test]
+    fn whole_buffers_like_lines() {
+        // Lines longer than a chunk, CRLF, invalid UTF-8 and no final newline
+        let mut input = Vec::new();
+        for i in 0..3000 {
+            let line = match i % 7 {
+                0 => format!("{i} needle in a haystack\r\n").into_bytes(),
+                3 => format!("{i} {}needle\n", "x".repeat(CHUNK / 3)).into_bytes(),
+                5 => [format!("{i} nee").as_bytes(), b"\xffdle needle\n"].concat(),
+                _ => format!("{i} nothing\n").into_bytes(),
+            };
+            input.extend_from_slice(&line);
+        }
+        input.extend_from_slice(b"last needle");
+
+        let literal = Literal::new("needle", true);
+        assert!(literal.prefilter().is_some());
+        // Hides the prefilter, so that the search goes line by line
+        let lines = AnyOf(vec![Box::new(Literal::new("needle", true))]);
+        let expected = collect(&Searcher::new(&lines).context(0, 0), &input);
+        assert_eq!(expected.len(), 429 + 429 + 428 + 1);
+        assert_eq!(collect(&Searcher::new(&literal), &input), expected);
+    }
+}
@@ -0,0 +1,173 @@
+/*! The thread pool of the webserver chapter: a fixed number of workers take
+ * jobs off one shared channel. On top of it, map_ordered runs a function on
+ * many inputs at once, but hands the results back in the order of the
+ * inputs, so that parallel output reads the same as sequential output
+ */
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/** A closure that can be passed across threads */
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+impl ThreadPool {
+    /** Return a new thread pool with "n" workers
+     *
+     * # Panics
+     *
+     * If "n" is 0
+     */
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0, "a thread pool needs a worker");
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..n).map(|_| Worker::new(Arc::clone(&receiver))).collect();
+        Self { workers, sender: Some(sender) }
+    }
+
+    /** Send "f" to one of the workers to run */
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            sender.send(Box::new(f)).expect("workers outlive the pool's sender");
+        }
+    }
+
+    /** Run "f" on every input, on the workers, and return the results in
+     * the order of "inputs". Results that come in early are held until the
+     * ones before them are taken. Where "f" panicked, the result is an error
+     * with the panic's payload, and the results after it still come
+     */
+    pub fn map_ordered<I, T, F>(self, inputs: Vec<I>, f: F) -> Ordered<T>
+    where
+        I: Send + 'static,
+        T: Send + 'static,
+        F: Fn(I) -> T + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let (sender, receiver) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let len = inputs.len();
+        for (index, input) in inputs.into_iter().enumerate() {
+            let (f, sender, cancelled) = (Arc::clone(&f), sender.clone(), Arc::clone(&cancelled));
+            self.execute(move || {
+                if !cancelled.load(Ordering::Relaxed) {
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| f(input)));
+                    // Fails only if the results are no longer wanted
+                    let _ = sender.send((index, result));
+                }
+            });
+        }
+        Ordered { receiver, pending: BTreeMap::new(), next: 0, len, cancelled, _pool: self }
+    }
+}
+
+/** Let each worker finish its current job, then join it. Dropping the only
+ * sender closes the channel, so that workers waiting for a job get an error
+ */
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in &mut self.workers {
+            if let Some(handle) = worker.handle.take() {
+                // A job that panicked has said so on stderr already
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+struct Worker {
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
+        let handle = thread::spawn(move || loop {
+            let job = receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recv();
+            match job {
+                Ok(job) => job(),
+                Err(_) => return,
+            }
+        });
+        Self { handle: Some(handle) }
+    }
+}
+
+/** The results of map_ordered. Dropping it skips the jobs that have not
+ * started yet, and waits for the ones that have
+ */
+pub struct Ordered<T> {
+    receiver: mpsc::Receiver<(usize, thread::Result<T>)>,
+    pending: BTreeMap<usize, thread::Result<T>>,
+    next: usize,
+    len: usize,
+    cancelled: Arc<AtomicBool>,
+    // Dropped last, after the receiver
+    _pool: ThreadPool,
+}
+
+impl<T> Iterator for Ordered<T> {
+    type Item = thread::Result<T>;
+
+    fn next(&mut self) -> Option<thread::Result<T>> {
+        if self.next == self.len {
+            return None;
+        }
+        loop {
+            if let Some(result) = self.pending.remove(&self.next) {
+                self.next += 1;
+                return Some(result);
+            }
+            let (index, result) = self.receiver.recv().ok()?;
+            self.pending.insert(index, result);
+        }
+    }
+}
+
+impl<T> Drop for Ordered<T> {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn results_in_input_order() {
+        // The first inputs take the longest, so they finish last
+        let results: Vec<u64> = ThreadPool::new(4)
+            .map_ordered((0..8).collect(), |n: u64| {
+                thread::sleep(Duration::from_millis(40 - 5 * n));
+                n * n
+            })
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(results, [0, 1, 4, 9, 16, 25, 36, 49]);
+    }
+
+    #[test]
+    fn a_panic_is_an_error() {
+        let results: Vec<_> = ThreadPool::new(2)
+            .map_ordered((0..4).collect(), |n: u32| {
+                assert_ne!(n, 1, "one panics");
+                n
+            })
+            .collect();
+        assert_eq!(results.len(), 4);
+        assert!(results[1].is_err());
+        assert_eq!(results.iter().filter_map(|result| result.as_ref().ok()).collect::<Vec<_>>(), [&0, &2, &3]);
+    }
+}
@@ -0,0 +1,254 @@
+/*! Turn the paths given on the command line into the files to search.
+ * Directories are walked recursively, in name order, skipping hidden
+ * entries and whatever the .gitignore and .ignore files along the way
+ * exclude. Files named on the command line are searched even if hidden or
+ * ignored, but --include/--exclude apply to every file. "-" is passed
+ * through as is, for standard input.
+ *
+ * Symbolic links are followed if named on the command line, and skipped
+ * when met in a walk, as by grep -r, so that a link to a directory above it
+ * cannot make the walk go round forever
+ */
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::glob::Glob;
+use crate::STDIN;
+
+/** Files that exclude paths from a walk, in the directory they apply to */
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+#[derive(Clone, Debug, Default)]
+pub struct WalkOptions {
+    /** Also walk into entries whose name starts with a '.' */
+    pub hidden: bool,
+    /** If any, only search files whose name matches one of these */
+    pub include: Vec<Glob>,
+    /** Never search files whose name matches one of these */
+    pub exclude: Vec<Glob>,
+}
+
+impl WalkOptions {
+    /** Whether --include/--exclude let a file with this name through */
+    fn wants(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob.matches(name)))
+            && !self.exclude.iter().any(|glob| glob.matches(name))
+    }
+}
+
+/** One line of an ignore file */
+#[derive(Debug)]
+struct Rule {
+    glob: Glob,
+    /** "!pattern" lets back in what an earlier rule excluded */
+    negated: bool,
+    /** "pattern/" only matches directories */
+    dir_only: bool,
+    /** A pattern with a '/' matches the whole path below the ignore file's
+     * directory, otherwise only the last component
+     */
+    anchored: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        Some(Rule { glob: Glob::new(line), negated, dir_only, anchored })
+    }
+
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        self.glob.matches(if self.anchored { relative } else { name })
+    }
+}
+
+/** The rules of one directory's ignore files */
+#[derive(Debug)]
+struct Ignore {
+    dir: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl Ignore {
+    fn load(dir: &Path) -> Option<Self> {
+        let rules: Vec<Rule> = IGNORE_FILES
+            .iter()
+            .filter_map(|name| fs::read_to_string(dir.join(name)).ok())
+            .flat_map(|text| text.lines().filter_map(Rule::parse).collect::<Vec<_>>())
+            .collect();
+        (!rules.is_empty()).then(|| Ignore { dir: dir.to_path_buf(), rules })
+    }
+}
+
+/** Whether "path" is excluded. Deeper ignore files override shallower ones
+ * and later rules override earlier ones, as in git
+ */
+fn is_ignored(stack: &[Ignore], path: &Path, is_dir: bool) -> bool {
+    let mut ignored = false;
+    for ignore in stack {
+        let Ok(relative) = path.strip_prefix(&ignore.dir) else { continue };
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        for rule in &ignore.rules {
+            if rule.matches(&relative, is_dir) {
+                ignored = !rule.negated;
+            }
+        }
+    }
+    ignored
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy()).into_owned()
+}
+
+/** Every file to search under "paths", in order. A path that cannot be read
+ * yields an error naming it, and the walk goes on
+ */
+pub fn files(paths: &[String], options: &WalkOptions) -> Vec<io::Result<PathBuf>> {
+    let mut found = Vec::new();
+    for path in paths.iter().map(PathBuf::from) {
+        if path == Path::new(STDIN) {
+            found.push(Ok(path));
+            continue;
+        }
+        match fs::metadata(&path) {
+            Ok(meta) if meta.is_dir() => walk(&path, options, &mut Vec::new(), &mut found),
+            Ok(_) if !options.wants(&file_name(&path)) => {}
+            Ok(_) => found.push(Ok(path)),
+            Err(e) => found.push(Err(annotate(&path, e))),
+        }
+    }
+    found
+}
+
+fn walk(dir: &Path, options: &WalkOptions, stack: &mut Vec<Ignore>, found: &mut Vec<io::Result<PathBuf>>) {
+    let entries = match fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>()) {
+        Ok(entries) => entries,
+        Err(e) => return found.push(Err(annotate(dir, e))),
+    };
+    let mut entries: Vec<PathBuf> = entries.into_iter().map(|entry| entry.path()).collect();
+    entries.sort();
+
+    let pushed = Ignore::load(dir).map(|ignore| stack.push(ignore)).is_some();
+    for path in entries {
+        let name = file_name(&path);
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(e) => {
+                found.push(Err(annotate(&path, e)));
+                continue;
+            }
+        };
+        if meta.is_symlink() || name == ".git" || (!options.hidden && name.starts_with('.')) {
+            continue;
+        }
+        if is_ignored(stack, &path, meta.is_dir()) {
+            continue;
+        }
+        if meta.is_dir() {
+            walk(&path, options, stack, found);
+        } else if options.wants(&name) {
+            found.push(Ok(path));
+        }
+    }
+    if pushed {
+        stack.pop();
+    }
+}
+
+fn annotate(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn tree(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = env::temp_dir().join(format!("greplite-{}-{test}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (name, content) in files {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    fn relative(root: &Path, options: &WalkOptions) -> Vec<String> {
+        files(&[root.to_string_lossy().into_owned()], options)
+            .into_iter()
+            .map(|path| path.unwrap().strip_prefix(root).unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn ignore_files_and_hidden() {
+        let root = tree("ignore", &[
+            (".gitignore", "*.log\n/build/\n!keep.log\n"),
+            (".hidden", ""),
+            ("a.txt", ""),
+            ("build/out.txt", ""),
+            ("src/build/gen.txt", ""),
+            ("src/debug.log", ""),
+            ("src/keep.log", ""),
+            ("src/.ignore", "gen.txt\n"),
+        ]);
+        assert_eq!(relative(&root, &WalkOptions::default()), ["a.txt", "src/keep.log"]);
+
+        let options = WalkOptions { hidden: true, ..WalkOptions::default() };
+        assert_eq!(relative(&root, &options), [".gitignore", ".hidden", "a.txt", "src/.ignore", "src/keep.log"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let root = tree("globs", &[("a.rs", ""), ("b.rs", ""), ("c.txt", ""), ("d/e.rs", "")]);
+        let options = WalkOptions {
+            include: vec![Glob::new("*.rs")],
+            exclude: vec![Glob::new("b*")],
+            ..WalkOptions::default()
+        };
+        assert_eq!(relative(&root, &options), ["a.rs", "d/e.rs"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_not_followed() {
+        use std::os::unix::fs::symlink;
+
+        let root = tree("symlinks", &[("sl/a/f.txt", "")]);
+        symlink("..", root.join("sl/a/up")).unwrap();
+        symlink("f.txt", root.join("sl/a/link.txt")).unwrap();
+        assert_eq!(relative(&root, &WalkOptions::default()), ["sl/a/f.txt"]);
+
+        // Unless named on the command line
+        let named = root.join("sl/a/up").to_string_lossy().into_owned();
+        let found: Vec<PathBuf> = files(&[named], &WalkOptions::default()).into_iter().map(Result::unwrap).collect();
+        assert_eq!(found, [root.join("sl/a/up/a/f.txt")]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
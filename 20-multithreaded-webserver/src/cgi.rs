@@ -0,0 +1,300 @@
+//! Running a local program for each request, following CGI/1.1 (RFC 3875):
+//! the request line and headers go into environment variables, the body
+//! into the program's stdin, and the program writes a header section and
+//! the body of the response to its stdout.
+//!
+//! The program runs on the worker that handles the request, which only
+//! waits for it; two short-lived helper threads shovel the body in and the
+//! output out. A program that runs out its time, or whose client goes
+//! away, is killed, along with whatever it started that still holds on to
+//! its output
+use std::io::{self, prelude::*};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::HttpError;
+use crate::http::{Request, Response};
+use crate::vhost;
+
+/// How often the worker looks at the program while it runs
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A program to run for requests to a route
+#[derive(Clone, Debug)]
+pub struct Cgi {
+    program: PathBuf,
+    timeout: Duration,
+}
+
+/// How a run of the program ended, if not with its output
+enum Failure {
+    Spawn(io::Error),
+    TimedOut,
+    ClientGone,
+}
+
+impl Cgi {
+    /// Run "program" with a timeout of 30 seconds
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Cgi { program: program.into(), timeout: Duration::from_secs(30) }
+    }
+
+    /// Kill the program and fail with a 504 if it takes longer than this
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run the program for "request", which arrived for "script_name" (the
+    /// path of the route) plus "path_info". A program that cannot be run is
+    /// an internal error, one that answers with nonsense a 502 and one that
+    /// runs out its time a 504
+    pub fn run(&self, request: &Request, script_name: &str, path_info: &str) -> Result<Response, HttpError> {
+        let mut command = Command::new(&self.program);
+        command
+            .env_clear()
+            .envs(environment(request, script_name, path_info))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        if let Some(dir) = self.program.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            command.current_dir(dir);
+        }
+        // a group of its own, so that killing it takes its children along
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+        match self.execute(command, request) {
+            Ok(output) => parse_output(&output).ok_or_else(|| {
+                eprintln!("{}: malformed CGI response", self.program.display());
+                HttpError::Status(502, String::from("BAD GATEWAY"))
+            }),
+            Err(Failure::Spawn(e)) => Err(HttpError::internal(format!("{}: {e}", self.program.display()))),
+            Err(Failure::TimedOut) => {
+                eprintln!("{}: killed after {:?}", self.program.display(), self.timeout);
+                Err(HttpError::Status(504, String::from("GATEWAY TIMEOUT")))
+            }
+            // nobody is left to read this
+            Err(Failure::ClientGone) => {
+                Err(HttpError::internal(format!("{}: client went away", self.program.display())))
+            }
+        }
+    }
+
+    /// Feed the body to the program and collect everything it writes. The
+    /// output is complete once the program has exited and every process
+    /// that inherited its stdout has closed it, e.g. one it left running in
+    /// the background; the timeout covers all of that
+    fn execute(&self, mut command: Command, request: &Request) -> Result<Vec<u8>, Failure> {
+        let mut child = command.spawn().map_err(Failure::Spawn)?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let deadline = Instant::now() + self.timeout;
+
+        thread::scope(|scope| {
+            // a program that does not read its input just gets a broken pipe
+            scope.spawn(move || {
+                let _ = stdin.write_all(&request.body);
+            });
+            let (sender, receiver) = mpsc::channel();
+            scope.spawn(move || {
+                let mut output = Vec::new();
+                let _ = sender.send(stdout.read_to_end(&mut output).map(|_| output));
+            });
+
+            let mut output = None;
+            loop {
+                match child.try_wait() {
+                    Ok(Some(_)) if output.is_some() => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        kill(&mut child);
+                        return Err(Failure::Spawn(e));
+                    }
+                }
+                if Instant::now() >= deadline {
+                    kill(&mut child);
+                    return Err(Failure::TimedOut);
+                }
+                if request.socket.as_ref().is_some_and(|socket| socket.is_disconnected()) {
+                    kill(&mut child);
+                    return Err(Failure::ClientGone);
+                }
+                match output {
+                    None => match receiver.recv_timeout(POLL_INTERVAL) {
+                        Ok(result) => output = Some(result),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => panic!("reader thread panicked"),
+                    },
+                    Some(_) => thread::sleep(POLL_INTERVAL),
+                }
+            }
+            output.expect("the loop waits for the output").map_err(Failure::Spawn)
+        })
+    }
+}
+
+/// Kill the program along with anything it started, and reap it. Its pipes
+/// close, which ends the helpers. The group outlives a program that has
+/// exited already for as long as something it started runs, so this also
+/// reaches what it left behind
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    // SAFETY: kill has no memory-safety preconditions; the negative pid
+    // addresses the process group the program leads
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// The CGI meta-variables for a request
+fn environment(request: &Request, script_name: &str, path_info: &str) -> Vec<(String, String)> {
+    let mut env = vec![
+        (String::from("GATEWAY_INTERFACE"), String::from("CGI/1.1")),
+        (String::from("SERVER_SOFTWARE"), format!("webserver/{}", env!("CARGO_PKG_VERSION"))),
+        (String::from("SERVER_PROTOCOL"), request.version.clone()),
+        (String::from("REQUEST_METHOD"), request.method.clone()),
+        (String::from("REQUEST_URI"), request.target.clone()),
+        (String::from("SCRIPT_NAME"), script_name.to_string()),
+        (String::from("PATH_INFO"), path_info.to_string()),
+        (String::from("QUERY_STRING"), request.target.split_once('?').map_or("", |(_, q)| q).to_string()),
+        (String::from("PATH"), String::from("/usr/local/bin:/usr/bin:/bin")),
+    ];
+    if let Some(addr) = request.peer_addr {
+        env.push((String::from("REMOTE_ADDR"), addr.ip().to_string()));
+        env.push((String::from("REMOTE_PORT"), addr.port().to_string()));
+    }
+    let local = request.socket.as_ref().and_then(|socket| socket.local_addr().ok());
+    let server_name = match (request.header("Host"), local) {
+        (Some(host), _) => vhost::host_name(host),
+        (None, Some(addr)) => addr.ip().to_string(),
+        (None, None) => String::from("localhost"),
+    };
+    env.push((String::from("SERVER_NAME"), server_name));
+    if let Some(addr) = local {
+        env.push((String::from("SERVER_PORT"), addr.port().to_string()));
+    }
+    if !request.body.is_empty() || request.header("Content-Length").is_some() {
+        env.push((String::from("CONTENT_LENGTH"), request.body.len().to_string()));
+    }
+    if let Some(content_type) = request.header("Content-Type") {
+        env.push((String::from("CONTENT_TYPE"), content_type.to_string()));
+    }
+
+    for (name, value) in &request.headers {
+        // "X_Real_IP" would pass for "X-Real-IP" once named HTTP_X_REAL_IP,
+        // so names with underscores are dropped, as by nginx and Apache
+        if name.contains('_') {
+            continue;
+        }
+        let var = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        // HTTP_PROXY would be taken for a proxy setting by many programs
+        let skip = ["HTTP_CONTENT_TYPE", "HTTP_CONTENT_LENGTH", "HTTP_PROXY", "HTTP_AUTHORIZATION"];
+        if skip.contains(&var.as_str()) || !var.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            continue;
+        }
+        match env.iter_mut().find(|(existing, _)| *existing == var) {
+            // repeated headers are joined, as for a single header
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => env.push((var, value.clone())),
+        }
+    }
+    env
+}
+
+/// Turn the program's output into a response. The header section needs at
+/// least a Content-Type, a Location or a Status
+fn parse_output(output: &[u8]) -> Option<Response> {
+    let mut reader = output;
+    let mut response = Response::new(200, "OK");
+    let mut status = None;
+    loop {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line).ok()? == 0 {
+            // no empty line to end the headers
+            return None;
+        }
+        let line = String::from_utf8(line).ok()?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("Status") {
+            let (code, reason) = value.split_once(' ').unwrap_or((value, ""));
+            status = Some((code.parse::<u16>().ok()?, reason.to_string()));
+        } else {
+            response = response.with_header(name, value);
+        }
+    }
+
+    match status {
+        Some((code, reason)) => {
+            response.status = code;
+            response.reason = reason;
+        }
+        None if response.header("Location").is_some() => {
+            response.status = 302;
+            response.reason = String::from("FOUND");
+        }
+        None if response.header("Content-Type").is_none() => return None,
+        None => {}
+    }
+    Some(response.with_body(reader.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meta_variables() {
+        let mut request = Request::new("POST", "/cgi/tool/extra?a=1&b=2")
+            .with_header("Host", "example.com:8080")
+            .with_header("Content-Type", "text/plain")
+            .with_header("X-Trace", "one")
+            .with_header("x-trace", "two")
+            .with_header("X_Trace", "spoofed")
+            .with_header("Proxy", "evil:3128")
+            .with_body("hi");
+        request.peer_addr = Some("10.1.2.3:4567".parse().unwrap());
+        let env = environment(&request, "/cgi/tool", "/extra");
+        let var = |name: &str| env.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+
+        assert_eq!(var("REQUEST_METHOD"), Some("POST"));
+        assert_eq!(var("SCRIPT_NAME"), Some("/cgi/tool"));
+        assert_eq!(var("PATH_INFO"), Some("/extra"));
+        assert_eq!(var("QUERY_STRING"), Some("a=1&b=2"));
+        assert_eq!(var("SERVER_NAME"), Some("example.com"));
+        assert_eq!(var("REMOTE_ADDR"), Some("10.1.2.3"));
+        assert_eq!(var("CONTENT_LENGTH"), Some("2"));
+        assert_eq!(var("CONTENT_TYPE"), Some("text/plain"));
+        assert_eq!(var("HTTP_X_TRACE"), Some("one, two"));
+        assert_eq!(var("HTTP_CONTENT_TYPE"), None);
+        assert_eq!(var("HTTP_PROXY"), None);
+    }
+
+    #[test]
+    fn output_parsing() {
+        let response = parse_output(b"Content-Type: text/plain\r\nX-Extra: 1\r\n\r\nbody\n").unwrap();
+        assert_eq!((response.status, response.header("X-Extra")), (200, Some("1")));
+        assert_eq!(response.body, b"body\n");
+
+        let response = parse_output(b"Status: 404 Not Found\nContent-Type: text/html\n\n").unwrap();
+        assert_eq!((response.status, response.reason.as_str()), (404, "Not Found"));
+        assert_eq!(parse_output(b"Location: /elsewhere\n\n").unwrap().status, 302);
+
+        assert!(parse_output(b"X-Only: 1\n\nbody").is_none());
+        assert!(parse_output(b"Content-Type: text/plain").is_none());
+        assert!(parse_output(b"not a header\n\n").is_none());
+    }
+}
@@ -0,0 +1,95 @@
+//! A small blocking HTTP/1.1 client, mostly for exercising the server from
+//! tests. One Client owns one connection and reuses it between requests for
+//! as long as the server keeps it open
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::http::{Request, Response};
+
+pub struct Client {
+    addr: SocketAddr,
+    stream: BufReader<TcpStream>,
+    closed: bool,
+    max_body_size: usize,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Client::new(addr, stream))
+    }
+
+    /// Like "connect", but give up if the connection cannot be established
+    /// within "timeout"
+    pub fn connect_timeout(addr: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        Ok(Client::new(addr, stream))
+    }
+
+    fn new(addr: SocketAddr, stream: TcpStream) -> Self {
+        Client { addr, stream: BufReader::new(stream), closed: false, max_body_size: usize::MAX }
+    }
+
+    /// Fail with "BodyTooLarge" instead of reading a response body of more
+    /// than "max" bytes
+    pub fn set_max_body_size(&mut self, max: usize) {
+        self.max_body_size = max;
+    }
+
+    /// Give up on a response that takes longer than "timeout" to arrive
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.get_ref().set_read_timeout(timeout)?;
+        self.stream.get_ref().set_write_timeout(timeout)
+    }
+
+    /// Send the request on this client's connection and wait for the
+    /// response. A Host header is added if the request does not have one
+    pub fn send(&mut self, request: Request) -> io::Result<Response> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "server closed the connection"));
+        }
+        let request = match request.header("Host") {
+            Some(_) => request,
+            None => {
+                let host = self.addr.to_string();
+                request.with_header("Host", &host)
+            }
+        };
+        request.write_to(self.stream.get_mut())?;
+
+        // the response to a HEAD request announces a body it does not have
+        let response = if request.method == "HEAD" {
+            Response::read_head(&mut self.stream)?
+        } else {
+            Response::read_limited(&mut self.stream, self.max_body_size)?
+        };
+        let server_closes = response
+            .header("Connection")
+            .is_some_and(|conn| conn.eq_ignore_ascii_case("close"));
+        if server_closes || !request.keep_alive() {
+            self.closed = true;
+        }
+        Ok(response)
+    }
+
+    pub fn get(&mut self, path: &str) -> io::Result<Response> {
+        self.send(Request::new("GET", path))
+    }
+
+    /// Whether the server has said it will close the connection, after which
+    /// every further request on this client fails
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// Open a connection, send a single request and close the connection again
+pub fn send(addr: SocketAddr, request: Request) -> io::Result<Response> {
+    Client::connect(addr)?.send(request.with_header("Connection", "close"))
+}
+
+/// Shorthand for a one-off GET request
+pub fn get(addr: SocketAddr, path: &str) -> io::Result<Response> {
+    send(addr, Request::new("GET", path))
+}
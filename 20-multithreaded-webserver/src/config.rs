@@ -0,0 +1,199 @@
+//! Reading the server configuration from a TOML file. Every setting is
+//! optional and falls back to "ServerConfig::default()":
+//!
+//! ```toml
+//! addr = "0.0.0.0:8080"
+//! workers = 8
+//! mode = "event-loop"          # or "blocking"
+//! max_requests = 0             # 0 runs indefinitely
+//! keep_alive_timeout = 5       # seconds
+//! max_body_size = 1048576      # bytes
+//! error_pages = "errors"       # 404.html, 500.html, 503.html, ...
+//!
+//! [tls]
+//! addr = "0.0.0.0:8443"
+//! cert = "cert.pem"
+//! key = "key.pem"
+//! redirect_http = true
+//!
+//! # Sites, picked by the Host header
+//! [[hosts]]
+//! names = ["docs.internal", "*.docs.internal"]
+//! root = "sites/docs"
+//! list = ["", "archive"]       # directories that get a listing
+//! show_hidden = false
+//! default = true               # also serves requests for unknown hosts
+//! ```
+//!
+//! Relative paths are relative to the directory of the config file
+//!
+//! A server started from a file picks up changes to it while it runs, see
+//! "reload"; only addr, workers, mode, max_requests and tls need a restart
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error::ErrorPages;
+use crate::files::FileServer;
+use crate::router::Router;
+use crate::server::{ServerConfig, ServerMode};
+use crate::tls::TlsConfig;
+use crate::vhost::VirtualHost;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    addr: Option<String>,
+    workers: Option<usize>,
+    mode: Option<ServerMode>,
+    max_requests: Option<usize>,
+    keep_alive_timeout: Option<u64>,
+    max_body_size: Option<usize>,
+    error_pages: Option<PathBuf>,
+    tls: Option<TlsSection>,
+    hosts: Vec<HostSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsSection {
+    addr: String,
+    cert: PathBuf,
+    key: PathBuf,
+    #[serde(default)]
+    redirect_http: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostSection {
+    names: Vec<String>,
+    root: PathBuf,
+    #[serde(default)]
+    list: Vec<String>,
+    #[serde(default)]
+    show_hidden: bool,
+    #[serde(default)]
+    default: bool,
+}
+
+/// Read and check the config file at "path"
+pub fn load<P: AsRef<Path>>(path: P) -> Result<ServerConfig, Box<dyn Error>> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new("."));
+    parse(&text, base).map_err(|e| format!("{}: {e}", path.display()).into())
+}
+
+/// Build a server config from the text of a config file. Relative paths
+/// are resolved against "base"
+pub fn parse(text: &str, base: &Path) -> Result<ServerConfig, Box<dyn Error>> {
+    let file: FileConfig = toml::from_str(text)?;
+    let mut config = ServerConfig::default();
+    if let Some(addr) = file.addr {
+        config.addr = addr;
+    }
+    if let Some(workers) = file.workers {
+        if workers == 0 {
+            return Err("workers must be at least 1".into());
+        }
+        config.workers = workers;
+    }
+    if let Some(mode) = file.mode {
+        config.mode = mode;
+    }
+    if let Some(max_requests) = file.max_requests {
+        config.max_requests = max_requests;
+    }
+    if let Some(secs) = file.keep_alive_timeout {
+        config.keep_alive_timeout = Duration::from_secs(secs);
+    }
+    if let Some(max_body_size) = file.max_body_size {
+        config.max_body_size = max_body_size;
+    }
+    if let Some(dir) = file.error_pages {
+        let dir = base.join(dir);
+        if !dir.is_dir() {
+            return Err(format!("error pages: {} is not a directory", dir.display()).into());
+        }
+        config.error_pages = ErrorPages::new(dir);
+    }
+    if let Some(tls) = file.tls {
+        config.tls = Some(TlsConfig {
+            addr: tls.addr,
+            cert_path: base.join(tls.cert),
+            key_path: base.join(tls.key),
+            redirect_http: tls.redirect_http,
+        });
+    }
+
+    let mut default = None;
+    for host in file.hosts {
+        let names: Vec<&str> = host.names.iter().map(String::as_str).collect();
+        if names.is_empty() {
+            return Err("every host needs at least one name".into());
+        }
+        let root = base.join(&host.root);
+        if !root.is_dir() {
+            return Err(format!("{}: document root {} is not a directory", names[0], root.display()).into());
+        }
+        let mut files = FileServer::new(&root).show_hidden(host.show_hidden);
+        for dir in &host.list {
+            if !root.join(dir.trim_matches('/')).is_dir() {
+                return Err(format!("{}: cannot list {dir}, it is not a directory", names[0]).into());
+            }
+            files = files.list(dir);
+        }
+
+        let vhost = VirtualHost::new(&names, Router::new().files("/", files));
+        if host.default {
+            if default.is_some() {
+                return Err("only one host can be the default".into());
+            }
+            default = Some(vhost.router.clone());
+        }
+        config.hosts.push(vhost);
+    }
+    if let Some(router) = default {
+        config.router = router;
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_and_defaults() {
+        let text = "addr = \"0.0.0.0:9000\"\nmode = \"blocking\"\nkeep_alive_timeout = 2";
+        let config = parse(text, Path::new(".")).unwrap();
+        assert_eq!(config.addr, "0.0.0.0:9000");
+        assert_eq!(config.mode, ServerMode::Blocking);
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(2));
+        assert_eq!(config.workers, ServerConfig::default().workers);
+        assert!(config.hosts.is_empty());
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let base = Path::new(env!("CARGO_MANIFEST_DIR"));
+        for text in [
+            "workers = 0",
+            "workers = \"four\"",
+            "adr = \"typo\"",
+            "mode = \"threads\"",
+            "error_pages = \"no-such-dir\"",
+            "[[hosts]]\nnames = []\nroot = \"src\"",
+            "[[hosts]]\nnames = [\"a\"]\nroot = \"no-such-dir\"",
+            "[[hosts]]\nnames = [\"a\"]\nroot = \"src\"\nlist = [\"nope\"]",
+            "[[hosts]]\nnames = [\"a\"]\nroot = \"src\"\ndefault = true\n\
+             [[hosts]]\nnames = [\"b\"]\nroot = \"src\"\ndefault = true",
+        ] {
+            assert!(parse(text, base).is_err(), "accepted {text:?}");
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Errors that handlers return instead of a response, and the pages the
+//! server answers them with. Every error gets a request id, which is shown
+//! to the client and logged next to the underlying error, so that a report
+//! from a user can be matched with the server's log
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::form::FormError;
+use crate::http::{Request, Response};
+use crate::template::{escape_html, TemplateError, Templates};
+
+/// Why a handler could not produce its response
+#[derive(Debug)]
+pub enum HttpError {
+    /// The message is shown to the client
+    BadRequest(String),
+    Forbidden,
+    NotFound,
+    /// E.g. a backend is down; the message is only logged
+    Unavailable(String),
+    /// Something went wrong on the server; the error is only logged
+    Internal(Box<dyn Error + Send + Sync>),
+    /// Any other status, with its reason phrase
+    Status(u16, String),
+}
+
+impl HttpError {
+    /// Wrap any error as an internal one
+    pub fn internal(e: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        HttpError::Internal(e.into())
+    }
+
+    /// The status code and reason phrase of the response
+    pub fn status(&self) -> (u16, &str) {
+        match self {
+            HttpError::BadRequest(_) => (400, "BAD REQUEST"),
+            HttpError::Forbidden => (403, "FORBIDDEN"),
+            HttpError::NotFound => (404, "NOT FOUND"),
+            HttpError::Unavailable(_) => (503, "SERVICE UNAVAILABLE"),
+            HttpError::Internal(_) => (500, "INTERNAL SERVER ERROR"),
+            HttpError::Status(status, reason) => (*status, reason),
+        }
+    }
+
+    /// What the client may be told about the error
+    fn public_message(&self) -> Option<&str> {
+        match self {
+            HttpError::BadRequest(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (status, reason) = self.status();
+        match self {
+            HttpError::BadRequest(message) | HttpError::Unavailable(message) => {
+                write!(f, "{status} {reason}: {message}")
+            }
+            // the error itself is the source
+            _ => write!(f, "{status} {reason}"),
+        }
+    }
+}
+
+impl Error for HttpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HttpError::Internal(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// A file that is missing or off limits is the client's problem; anything
+/// else is the server's
+impl From<io::Error> for HttpError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => HttpError::NotFound,
+            io::ErrorKind::PermissionDenied => HttpError::Forbidden,
+            _ => HttpError::Internal(Box::new(e)),
+        }
+    }
+}
+
+impl From<TemplateError> for HttpError {
+    fn from(e: TemplateError) -> Self {
+        HttpError::Internal(Box::new(e))
+    }
+}
+
+impl From<FormError> for HttpError {
+    fn from(e: FormError) -> Self {
+        match e {
+            FormError::UnsupportedType => HttpError::Status(415, String::from("UNSUPPORTED MEDIA TYPE")),
+            FormError::Malformed(message) => HttpError::BadRequest(message.to_string()),
+            FormError::PartTooLarge | FormError::TooLarge => {
+                HttpError::Status(413, String::from("PAYLOAD TOO LARGE"))
+            }
+            FormError::Io(e) => HttpError::Internal(Box::new(e)),
+        }
+    }
+}
+
+/// An id for the request, unique within this run of the server and unlikely
+/// to repeat across runs. An X-Request-Id set by a proxy in front is kept
+pub fn request_id(request: &Request) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let valid = |id: &&str| !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_graphic());
+    if let Some(id) = request.header("X-Request-Id").filter(valid) {
+        return id.to_string();
+    }
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    format!("{:08x}-{:06x}", started as u32, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// What an error page template gets to show
+#[derive(Serialize)]
+struct Context<'a> {
+    status: u16,
+    reason: &'a str,
+    request_id: &'a str,
+    message: Option<&'a str>,
+}
+
+/// Error pages rendered from "<status>.html" templates in a directory,
+/// e.g. "404.html" or "503.html". A template can show "status", "reason",
+/// "request_id" and, for bad requests, "message". Statuses without a
+/// template get a plain built-in page
+#[derive(Clone, Debug)]
+pub struct ErrorPages {
+    templates: Arc<Templates>,
+}
+
+impl ErrorPages {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ErrorPages { templates: Arc::new(Templates::new(dir).hot_reload(cfg!(debug_assertions))) }
+    }
+
+    /// Log "error" with the request it happened to, and render its page
+    pub fn respond(&self, request: &Request, error: &HttpError) -> Response {
+        let id = request_id(request);
+        let (status, reason) = error.status();
+        eprintln!("[{id}] {} {}: {}", request.method, request.target, describe(error));
+
+        let context = Context { status, reason, request_id: &id, message: error.public_message() };
+        let body = match self.templates.render(&format!("{status}.html"), &context) {
+            Ok(html) => html,
+            Err(TemplateError::Io(_, e)) if e.kind() == io::ErrorKind::NotFound => builtin_page(&context),
+            Err(e) => {
+                eprintln!("[{id}] Could not render the error page: {e}");
+                builtin_page(&context)
+            }
+        };
+        Response::new(status, reason)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_header("X-Request-Id", &id)
+            .with_body(body)
+    }
+}
+
+/// Pages in the working directory, where the server has always looked
+/// for 404.html
+impl Default for ErrorPages {
+    fn default() -> Self {
+        ErrorPages::new(".")
+    }
+}
+
+/// An error followed by the chain of its sources
+fn describe(error: &dyn Error) -> String {
+    let mut text = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        text.push_str(&format!(": {e}"));
+        source = e.source();
+    }
+    text
+}
+
+fn builtin_page(context: &Context) -> String {
+    let message = context.message.map(|m| format!("\n    <p>{}</p>", escape_html(m))).unwrap_or_default();
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n  <head>\n    <meta charset=\"utf-8\">\n    \
+         <title>{status} {reason}</title>\n  </head>\n  <body>\n    <h1>{status} {reason}</h1>{message}\n    \
+         <p>Request id: {id}</p>\n  </body>\n</html>\n",
+        status = context.status,
+        reason = escape_html(context.reason),
+        id = escape_html(context.request_id),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_and_logged_cause() {
+        let missing = io::Error::new(io::ErrorKind::NotFound, "gone");
+        assert_eq!(HttpError::from(missing).status(), (404, "NOT FOUND"));
+        let broken = HttpError::from(io::Error::other("disk on fire"));
+        assert_eq!(broken.status().0, 500);
+        assert_eq!(describe(&broken), "500 INTERNAL SERVER ERROR: disk on fire");
+        assert_eq!(HttpError::from(FormError::TooLarge).status().0, 413);
+    }
+
+    #[test]
+    fn builtin_page_hides_internals() {
+        let pages = ErrorPages::new("no-such-dir");
+        let request = Request::new("GET", "/x").with_header("X-Request-Id", "abc-123");
+        let response = pages.respond(&request, &HttpError::internal("secret detail"));
+        assert_eq!((response.status, response.header("X-Request-Id")), (500, Some("abc-123")));
+        assert!(response.text().contains("Request id: abc-123"));
+        assert!(!response.text().contains("secret"));
+
+        let response = pages.respond(&request, &HttpError::BadRequest(String::from("bad <id>")));
+        assert!(response.text().contains("bad &lt;id&gt;"));
+    }
+}
@@ -0,0 +1,345 @@
+//! Serving files from a directory tree. A directory is answered with its
+//! index.html if it has one, or else with a listing of its entries, but
+//! only if listings have been turned on for it. Hidden files (names
+//! starting with a dot) are neither listed nor served unless allowed
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::error::HttpError;
+use crate::form::{self, FormLimits};
+use crate::http::{Request, Response};
+use crate::template::Template;
+
+const LISTING: &str = r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8">
+    <title>Index of {{ path }}</title>
+  </head>
+  <body>
+    <h1>Index of {{ path }}</h1>
+    <table>
+      <tr>
+        <th><a href="?sort=name&order={{ flip.name }}">Name</a></th>
+        <th><a href="?sort=size&order={{ flip.size }}">Size</a></th>
+        <th><a href="?sort=modified&order={{ flip.modified }}">Modified</a></th>
+      </tr>
+      {% if parent %}<tr><td><a href="../">../</a></td><td></td><td></td></tr>{% endif %}
+      {% for entry in entries %}
+      <tr>
+        <td><a href="{{ entry.href }}">{{ entry.name }}{% if entry.dir %}/{% endif %}</a></td>
+        <td>{{ entry.human_size }}</td>
+        <td>{{ entry.modified }}</td>
+      </tr>
+      {% endfor %}
+    </table>
+  </body>
+</html>
+"#;
+
+/// Serves the files below "root"
+#[derive(Clone, Debug)]
+pub struct FileServer {
+    root: PathBuf,
+    /// Directories, relative to root, whose subtrees may be listed
+    listed: Vec<PathBuf>,
+    show_hidden: bool,
+}
+
+/// What a listing is sorted by, from the "sort" query parameter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortBy {
+    Name,
+    Size,
+    Modified,
+}
+
+/// One entry of a directory listing
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub dir: bool,
+    /// In bytes; 0 for directories
+    pub size: u64,
+    /// RFC 3339, in UTC
+    pub modified: String,
+    #[serde(skip)]
+    modified_at: SystemTime,
+}
+
+/// Percent-decode a request path. Unlike form fields, "+" stays a "+"
+fn decode_path(path: &str) -> Option<String> {
+    let input = path.as_bytes();
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] == b'%' {
+            let hi = form::hex_digit(*input.get(i + 1)?)?;
+            let lo = form::hex_digit(*input.get(i + 2)?)?;
+            out.push(hi << 4 | lo);
+            i += 3;
+        } else {
+            out.push(input[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Percent-encode a file name for use in a link
+fn encode_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Format a point in time as RFC 3339 in UTC, e.g. "2024-02-29T13:05:00Z"
+pub fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64;
+    let (days, rest) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // days since 1970-01-01 to a civil date, after Howard Hinnant
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+/// A size for people, e.g. "512 B" or "1.5 KiB"
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "log" | "md" => "text/plain; charset=utf-8",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Sort entries with directories first, then by "sort_by"; names break ties
+pub fn sort_entries(entries: &mut [Entry], sort_by: SortBy, descending: bool) {
+    entries.sort_by(|a, b| {
+        let order = match sort_by {
+            SortBy::Name => Ordering::Equal,
+            SortBy::Size => a.size.cmp(&b.size),
+            SortBy::Modified => a.modified_at.cmp(&b.modified_at),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let order = if descending { order.reverse() } else { order };
+        b.dir.cmp(&a.dir).then(order)
+    });
+}
+
+impl FileServer {
+    /// Serve the files below "root"; no directory is listed yet
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FileServer { root: root.into(), listed: Vec::new(), show_hidden: false }
+    }
+
+    /// Allow listings for "dir", relative to the root ("" for the root
+    /// itself), and every directory below it
+    pub fn list(mut self, dir: &str) -> Self {
+        self.listed.push(PathBuf::from(dir.trim_matches('/')));
+        self
+    }
+
+    /// List and serve hidden files too
+    pub fn show_hidden(mut self, show: bool) -> Self {
+        self.show_hidden = show;
+        self
+    }
+
+    /// The entries of a directory, unsorted
+    pub fn entries(&self, dir: &Path) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if is_hidden(&name) && !self.show_hidden {
+                continue;
+            }
+            // follows symlinks, like serving the file would
+            let Ok(meta) = fs::metadata(entry.path()) else { continue };
+            let modified_at = meta.modified().unwrap_or(UNIX_EPOCH);
+            entries.push(Entry {
+                name,
+                dir: meta.is_dir(),
+                size: if meta.is_dir() { 0 } else { meta.len() },
+                modified: format_time(modified_at),
+                modified_at,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Answer a GET for "path", which is relative to the root and still
+    /// percent-encoded. Anything that is not there to be served, hidden or
+    /// unlisted included, is NotFound
+    pub fn serve(&self, request: &Request, path: &str) -> Result<Response, HttpError> {
+        let decoded = decode_path(path).ok_or(HttpError::NotFound)?;
+        let mut relative = PathBuf::new();
+        for part in decoded.split('/').filter(|part| !part.is_empty()) {
+            if part == "." || part == ".." || part.contains('\\') || (is_hidden(part) && !self.show_hidden) {
+                return Err(HttpError::NotFound);
+            }
+            relative.push(part);
+        }
+        let full = self.root.join(&relative);
+        let meta = fs::metadata(&full).map_err(|_| HttpError::NotFound)?;
+
+        if meta.is_file() {
+            return self.file(&full);
+        }
+        if !request.path().ends_with('/') {
+            let location = match request.target.split_once('?') {
+                Some((path, query)) => format!("{path}/?{query}"),
+                None => format!("{}/", request.target),
+            };
+            return Ok(Response::new(301, "MOVED PERMANENTLY").with_header("Location", &location));
+        }
+        let index = full.join("index.html");
+        if index.is_file() {
+            return self.file(&index);
+        }
+        if !self.listed.iter().any(|dir| relative.starts_with(dir)) {
+            return Err(HttpError::NotFound);
+        }
+        self.listing(request, &full, relative.as_os_str().is_empty())
+    }
+
+    /// A file that is there but cannot be read is an internal error
+    fn file(&self, path: &Path) -> Result<Response, HttpError> {
+        let body = fs::read(path).map_err(|e| HttpError::internal(format!("{}: {e}", path.display())))?;
+        Ok(Response::new(200, "OK").with_header("Content-Type", content_type(path)).with_body(body))
+    }
+
+    /// The listing of "dir" as HTML, or as JSON if the client asks for it
+    /// with "?format=json" or an Accept header
+    fn listing(&self, request: &Request, dir: &Path, is_root: bool) -> Result<Response, HttpError> {
+        let query = request.target.split_once('?').map_or("", |(_, query)| query);
+        let params = form::parse_urlencoded(query.as_bytes(), &FormLimits::default()).unwrap_or_default();
+        let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+        let sort_by = match param("sort") {
+            Some("size") => SortBy::Size,
+            Some("modified") => SortBy::Modified,
+            _ => SortBy::Name,
+        };
+        let descending = param("order") == Some("desc");
+        let json = param("format") == Some("json")
+            || request.header("Accept").is_some_and(|accept| accept.contains("application/json"));
+
+        let mut entries =
+            self.entries(dir).map_err(|e| HttpError::internal(format!("{}: {e}", dir.display())))?;
+        sort_entries(&mut entries, sort_by, descending);
+
+        if json {
+            let listing = serde_json::json!({ "path": request.path(), "entries": entries });
+            return Ok(Response::json(200, "OK", &listing));
+        }
+        let flip = |by: SortBy| if by == sort_by && !descending { "desc" } else { "asc" };
+        let rows: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                let mut href = encode_name(&entry.name);
+                if entry.dir {
+                    href.push('/');
+                }
+                serde_json::json!({
+                    "name": entry.name,
+                    "dir": entry.dir,
+                    "href": href,
+                    "human_size": if entry.dir { String::from("-") } else { human_size(entry.size) },
+                    "modified": entry.modified,
+                })
+            })
+            .collect();
+        let context = serde_json::json!({
+            "path": request.path(),
+            "parent": !is_root,
+            "entries": rows,
+            "flip": {
+                "name": flip(SortBy::Name),
+                "size": flip(SortBy::Size),
+                "modified": flip(SortBy::Modified),
+            },
+        });
+
+        static TEMPLATE: OnceLock<Template> = OnceLock::new();
+        let template = TEMPLATE.get_or_init(|| Template::parse("listing", LISTING).expect("valid template"));
+        let html = template.render(&context)?;
+        Ok(Response::new(200, "OK").with_header("Content-Type", "text/html; charset=utf-8").with_body(html))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn times_and_sizes() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_211_900);
+        assert_eq!(format_time(leap_day), "2024-02-29T13:05:00Z");
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024), "3.0 MiB");
+    }
+
+    #[test]
+    fn paths_are_decoded_and_encoded() {
+        assert_eq!(decode_path("/a%20b/c+d").as_deref(), Some("/a b/c+d"));
+        assert_eq!(decode_path("/bad%2"), None);
+        assert_eq!(encode_name("a b&c.txt"), "a%20b%26c.txt");
+    }
+}
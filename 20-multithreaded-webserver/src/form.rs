@@ -0,0 +1,515 @@
+//! Parsing "application/x-www-form-urlencoded" and "multipart/form-data"
+//! request bodies. The server has read the whole body by the time a handler
+//! runs, within its "max_body_size", so the limits here bound what a form is
+//! parsed into rather than what is read. File parts that grow past a
+//! threshold are written to a temporary file instead of being kept in memory
+//! a second time
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::HttpError;
+use crate::http::{self, Request, Response};
+
+/// Bounds on what a form may make the server store. The defaults are those
+/// for the default "max_body_size" of the server
+#[derive(Clone, Debug)]
+pub struct FormLimits {
+    /// Largest single field or file
+    pub max_part_size: usize,
+    /// Largest sum of all fields and files
+    pub max_total_size: usize,
+    /// Most parts a multipart body may have
+    pub max_parts: usize,
+    /// File parts larger than this are moved from memory to "upload_dir"
+    pub memory_threshold: usize,
+    pub upload_dir: PathBuf,
+}
+
+impl FormLimits {
+    /// Limits that let through any form in a body of up to "max_body_size"
+    /// bytes, the largest the server hands to a handler if configured with it
+    pub fn for_body_size(max_body_size: usize) -> Self {
+        FormLimits {
+            max_part_size: max_body_size,
+            max_total_size: max_body_size,
+            max_parts: 128,
+            memory_threshold: 64 * 1024,
+            upload_dir: env::temp_dir(),
+        }
+    }
+}
+
+impl Default for FormLimits {
+    fn default() -> Self {
+        FormLimits::for_body_size(http::DEFAULT_MAX_BODY_SIZE)
+    }
+}
+
+#[derive(Debug)]
+pub enum FormError {
+    /// The request is not a form at all
+    UnsupportedType,
+    Malformed(&'static str),
+    PartTooLarge,
+    TooLarge,
+    Io(io::Error),
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::UnsupportedType => write!(f, "expected a form body"),
+            FormError::Malformed(msg) => write!(f, "malformed form: {msg}"),
+            FormError::PartTooLarge => write!(f, "form field too large"),
+            FormError::TooLarge => write!(f, "form too large"),
+            FormError::Io(e) => write!(f, "could not store upload: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl From<io::Error> for FormError {
+    fn from(e: io::Error) -> Self {
+        FormError::Io(e)
+    }
+}
+
+/// Upload spilled to disk; the file is deleted when this is dropped unless
+/// it has been moved somewhere permanent with "persist"
+#[derive(Debug)]
+pub struct TempPath {
+    path: Option<PathBuf>,
+}
+
+impl TempPath {
+    fn create(dir: &Path) -> io::Result<(Self, File)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("upload-{}-{n}", process::id()));
+            match File::options().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((TempPath { path: Some(path) }, file)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().expect("temporary file already persisted")
+    }
+
+    /// Move the file to "to" and stop it from being deleted
+    pub fn persist<P: AsRef<Path>>(mut self, to: P) -> io::Result<()> {
+        let path = self.path.take().expect("temporary file already persisted");
+        fs::rename(&path, to).inspect_err(|_| self.path = Some(path))
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FileData {
+    Memory(Vec<u8>),
+    Disk(TempPath),
+}
+
+/// An uploaded file
+#[derive(Debug)]
+pub struct FilePart {
+    pub name: String,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: usize,
+    pub data: FileData,
+}
+
+impl FilePart {
+    /// The whole content of the file, reading it back from disk if needed
+    pub fn bytes(&self) -> io::Result<Vec<u8>> {
+        match &self.data {
+            FileData::Memory(bytes) => Ok(bytes.clone()),
+            FileData::Disk(temp) => fs::read(temp.path()),
+        }
+    }
+}
+
+/// A parsed form: plain fields in the order they were sent, and files
+#[derive(Debug, Default)]
+pub struct Form {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<FilePart>,
+}
+
+impl Form {
+    /// Value of the first field with this name
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// First file uploaded under this field name
+    pub fn file(&self, name: &str) -> Option<&FilePart> {
+        self.files.iter().find(|file| file.name == name)
+    }
+}
+
+pub(crate) fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Undo the percent-encoding of a form field, where "+" also means a space
+pub fn percent_decode(input: &[u8]) -> Result<String, FormError> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hi = input.get(i + 1).copied().and_then(hex_digit);
+                let lo = input.get(i + 2).copied().and_then(hex_digit);
+                match (hi, lo) {
+                    (Some(hi), Some(lo)) => out.push(hi << 4 | lo),
+                    _ => return Err(FormError::Malformed("bad percent escape")),
+                }
+                i += 2;
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8(out).map_err(|_| FormError::Malformed("field is not UTF-8"))
+}
+
+/// Parse "a=1&b=two+words" into name/value pairs
+pub fn parse_urlencoded(body: &[u8], limits: &FormLimits) -> Result<Vec<(String, String)>, FormError> {
+    if body.len() > limits.max_total_size {
+        return Err(FormError::TooLarge);
+    }
+    let mut fields = Vec::new();
+    for pair in body.split(|&byte| byte == b'&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = match pair.iter().position(|&byte| byte == b'=') {
+            Some(eq) => (&pair[..eq], &pair[eq + 1..]),
+            None => (pair, &b""[..]),
+        };
+        if value.len() > limits.max_part_size {
+            return Err(FormError::PartTooLarge);
+        }
+        fields.push((percent_decode(name)?, percent_decode(value)?));
+    }
+    Ok(fields)
+}
+
+/// Split a header value like `form-data; name="a"; filename="b;c.txt"` into
+/// its parameters, honouring quoted strings
+fn header_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+    // skip the leading disposition type
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+    loop {
+        let name: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        let name = name.trim().to_ascii_lowercase();
+        if name.is_empty() {
+            return params;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|&c| c != ';').collect();
+            value = value.trim().to_string();
+        }
+        params.push((name, value));
+    }
+}
+
+/// The boundary parameter of a multipart Content-Type
+fn boundary(content_type: &str) -> Option<String> {
+    header_params(content_type)
+        .into_iter()
+        .find(|(name, _)| name == "boundary")
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty() && value.len() <= 70)
+}
+
+/// Where the bytes of the part being parsed go
+enum Sink {
+    Discard,
+    Memory(Vec<u8>),
+    Disk(TempPath, File),
+}
+
+/// A "BufRead" with some bytes that have been read but not yet consumed
+struct Input<R> {
+    reader: R,
+    pending: Vec<u8>,
+}
+
+impl<R: BufRead> Input<R> {
+    /// Pull more bytes from the reader; false at the end of the stream
+    fn fill(&mut self) -> io::Result<bool> {
+        let buf = self.reader.fill_buf()?;
+        let n = buf.len();
+        self.pending.extend_from_slice(buf);
+        self.reader.consume(n);
+        Ok(n > 0)
+    }
+
+    fn read_line(&mut self, max: usize) -> Result<String, FormError> {
+        loop {
+            if let Some(end) = self.pending.iter().position(|&byte| byte == b'\n') {
+                let mut line: Vec<u8> = self.pending.drain(..=end).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return String::from_utf8(line).map_err(|_| FormError::Malformed("header is not UTF-8"));
+            }
+            if self.pending.len() > max {
+                return Err(FormError::Malformed("header line too long"));
+            }
+            if !self.fill()? {
+                return Err(FormError::Malformed("unexpected end of body"));
+            }
+        }
+    }
+
+    /// Move bytes into "sink" until "delim" is found, consuming the delimiter.
+    /// "write" is told about every chunk so it can enforce the limits
+    fn copy_until<F>(&mut self, delim: &[u8], mut write: F) -> Result<(), FormError>
+    where
+        F: FnMut(&[u8]) -> Result<(), FormError>,
+    {
+        loop {
+            if let Some(pos) = self.pending.windows(delim.len()).position(|window| window == delim) {
+                write(&self.pending[..pos])?;
+                self.pending.drain(..pos + delim.len());
+                return Ok(());
+            }
+            // everything but a possible prefix of the delimiter is content
+            let safe = self.pending.len().saturating_sub(delim.len() - 1);
+            if safe > 0 {
+                write(&self.pending[..safe])?;
+                self.pending.drain(..safe);
+            }
+            if !self.fill()? {
+                return Err(FormError::Malformed("missing closing boundary"));
+            }
+        }
+    }
+}
+
+/// Parse a multipart body delimited by "boundary" from a stream
+pub fn parse_multipart<R: BufRead>(reader: R, boundary: &str, limits: &FormLimits) -> Result<Form, FormError> {
+    let delim = format!("\r\n--{boundary}").into_bytes();
+    // the first delimiter is not preceded by a line break, so pretend it is
+    let mut input = Input { reader, pending: b"\r\n".to_vec() };
+    input.copy_until(&delim, |_| Ok(()))?; // preamble
+
+    let mut form = Form::default();
+    let mut total = 0usize;
+    loop {
+        // what follows a delimiter decides whether another part follows
+        while input.pending.len() < 2 {
+            if !input.fill()? {
+                return Err(FormError::Malformed("unexpected end of body"));
+            }
+        }
+        if input.pending.starts_with(b"--") {
+            return Ok(form);
+        }
+        input.read_line(1024)?; // rest of the delimiter line
+
+        if form.fields.len() + form.files.len() >= limits.max_parts {
+            return Err(FormError::TooLarge);
+        }
+        let mut disposition = None;
+        let mut content_type = None;
+        loop {
+            let line = input.read_line(8 * 1024)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').ok_or(FormError::Malformed("bad part header"))?;
+            if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+                disposition = Some(header_params(value.trim()));
+            } else if name.trim().eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.trim().to_string());
+            }
+        }
+        let params = disposition.ok_or(FormError::Malformed("part without Content-Disposition"))?;
+        let param = |key: &str| params.iter().find(|(name, _)| name == key).map(|(_, value)| value.clone());
+        let name = param("name").ok_or(FormError::Malformed("part without a name"))?;
+        let filename = param("filename");
+
+        let mut size = 0usize;
+        let mut sink = Sink::Memory(Vec::new());
+        input.copy_until(&delim, |bytes| {
+            size += bytes.len();
+            total += bytes.len();
+            if size > limits.max_part_size {
+                return Err(FormError::PartTooLarge);
+            }
+            if total > limits.max_total_size {
+                return Err(FormError::TooLarge);
+            }
+            if let Sink::Memory(buf) = &mut sink {
+                if filename.is_some() && size > limits.memory_threshold {
+                    let (temp, mut file) = TempPath::create(&limits.upload_dir)?;
+                    file.write_all(buf)?;
+                    sink = Sink::Disk(temp, file);
+                }
+            }
+            match &mut sink {
+                Sink::Memory(buf) => buf.extend_from_slice(bytes),
+                Sink::Disk(_, file) => file.write_all(bytes)?,
+                Sink::Discard => {}
+            }
+            Ok(())
+        })?;
+
+        let data = match std::mem::replace(&mut sink, Sink::Discard) {
+            Sink::Memory(buf) => FileData::Memory(buf),
+            Sink::Disk(temp, mut file) => {
+                file.flush()?;
+                FileData::Disk(temp)
+            }
+            Sink::Discard => unreachable!(),
+        };
+        match (filename, data) {
+            (Some(filename), data) => form.files.push(FilePart { name, filename, content_type, size, data }),
+            (None, FileData::Memory(buf)) => {
+                let value = String::from_utf8(buf).map_err(|_| FormError::Malformed("field is not UTF-8"))?;
+                form.fields.push((name, value));
+            }
+            (None, FileData::Disk(_)) => unreachable!("fields are never written to disk"),
+        }
+    }
+}
+
+impl Request {
+    /// Parse the body as a urlencoded or a multipart form, depending on the
+    /// Content-Type. The body is already in memory; see the module
+    /// documentation
+    pub fn form(&self, limits: &FormLimits) -> Result<Form, FormError> {
+        let content_type = self.header("Content-Type").ok_or(FormError::UnsupportedType)?;
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        if mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            let fields = parse_urlencoded(&self.body, limits)?;
+            Ok(Form { fields, files: Vec::new() })
+        } else if mime.eq_ignore_ascii_case("multipart/form-data") {
+            let boundary = boundary(content_type).ok_or(FormError::Malformed("missing boundary"))?;
+            parse_multipart(&self.body[..], &boundary, limits)
+        } else {
+            Err(FormError::UnsupportedType)
+        }
+    }
+}
+
+/// Wrap a handler that takes an already parsed form. Bodies that are not a
+/// form, are malformed or exceed the limits are answered with the error page
+/// for their status before the handler runs
+pub fn handler<F>(limits: FormLimits, f: F) -> impl Fn(&Request) -> Result<Response, HttpError> + Send + Sync
+where
+    F: Fn(&Request, Form) -> Result<Response, HttpError> + Send + Sync + 'static,
+{
+    move |request| f(request, request.form(&limits)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = "preamble\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"title\"\r\n\
+\r\n\
+Hello, world\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"upload\"; filename=\"a;b.txt\"\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+line one\r\nline two\r\n\
+--XyZ--\r\n";
+
+    #[test]
+    fn urlencoded() {
+        let fields = parse_urlencoded(b"a=1&b=two+words&c=%C3%9F%26&flag", &FormLimits::default()).unwrap();
+        let expected = [("a", "1"), ("b", "two words"), ("c", "ß&"), ("flag", "")];
+        let expected: Vec<_> = expected.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(fields, expected);
+        assert!(parse_urlencoded(b"a=%zz", &FormLimits::default()).is_err());
+    }
+
+    #[test]
+    fn multipart_in_memory() {
+        let form = parse_multipart(BODY.as_bytes(), "XyZ", &FormLimits::default()).unwrap();
+        assert_eq!(form.field("title"), Some("Hello, world"));
+        let file = form.file("upload").unwrap();
+        assert_eq!(file.filename, "a;b.txt");
+        assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(file.bytes().unwrap(), b"line one\r\nline two");
+        assert!(matches!(file.data, FileData::Memory(_)));
+    }
+
+    #[test]
+    fn multipart_spills_to_disk() {
+        let limits = FormLimits { memory_threshold: 4, ..FormLimits::default() };
+        // a tiny BufReader forces the delimiter to straddle reads
+        let reader = io::BufReader::with_capacity(3, BODY.as_bytes());
+        let form = parse_multipart(reader, "XyZ", &limits).unwrap();
+        let file = form.file("upload").unwrap();
+        let FileData::Disk(temp) = &file.data else { panic!("expected a file on disk") };
+        let path = temp.path().to_path_buf();
+        assert_eq!(fs::read(&path).unwrap(), b"line one\r\nline two");
+        drop(form);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn multipart_limits() {
+        let limits = FormLimits { max_part_size: 8, ..FormLimits::default() };
+        assert!(matches!(parse_multipart(BODY.as_bytes(), "XyZ", &limits), Err(FormError::PartTooLarge)));
+        let limits = FormLimits { max_total_size: 20, ..FormLimits::default() };
+        assert!(matches!(parse_multipart(BODY.as_bytes(), "XyZ", &limits), Err(FormError::TooLarge)));
+        assert!(parse_multipart(BODY.as_bytes(), "XyZ", &FormLimits::for_body_size(BODY.len())).is_ok());
+        let truncated = &BODY[..BODY.len() - 10];
+        assert!(matches!(
+            parse_multipart(truncated.as_bytes(), "XyZ", &FormLimits::default()),
+            Err(FormError::Malformed(_))
+        ));
+    }
+}
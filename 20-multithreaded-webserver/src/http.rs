@@ -0,0 +1,541 @@
+//! Just enough HTTP/1.1 to read and write requests and responses on a
+//! stream. The same types are used by the server and by the client.
+//! HTTP/1.0 requests are understood too; responses always claim HTTP/1.1,
+//! which 1.0 clients accept, but never rely on chunked encoding
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+/// Upper bound on the request/status line plus all header lines, so that a
+/// client cannot make us buffer an endless header section
+pub const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// The largest request body a server takes unless configured otherwise
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// A parsed request. Header names keep the case the client sent them in;
+/// use "header()" to look them up case-insensitively
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Address of the client that sent the request, filled in by the server
+    pub peer_addr: Option<SocketAddr>,
+    /// The connection the request came in on, filled in by the server
+    pub socket: Option<ClientSocket>,
+}
+
+/// A handle on the connection of a request, for handlers that run long
+/// enough to care whether the client is still there
+#[derive(Clone, Debug)]
+pub struct ClientSocket(Arc<TcpStream>);
+
+impl PartialEq for ClientSocket {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl ClientSocket {
+    /// Address the client connected to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    /// Whether the client has closed the connection or it has failed. Data
+    /// the client already sent, such as a pipelined request, is left alone
+    #[cfg(target_os = "linux")]
+    pub fn is_disconnected(&self) -> bool {
+        use std::os::fd::AsRawFd;
+
+        let mut byte = 0u8;
+        // SAFETY: peeks at most one byte into "byte"; MSG_DONTWAIT keeps it
+        // from blocking without touching the flags the socket is shared with
+        let n = unsafe {
+            libc::recv(
+                self.0.as_raw_fd(),
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        match n {
+            0 => true,
+            n if n > 0 => false,
+            _ => !matches!(
+                io::Error::last_os_error().kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ),
+        }
+    }
+
+    /// Only detected on Linux; elsewhere the client is assumed to stay
+    #[cfg(not(target_os = "linux"))]
+    pub fn is_disconnected(&self) -> bool {
+        false
+    }
+}
+
+/// A response. The status is split into the numeric code and the reason
+/// phrase, e.g. 404 and "NOT FOUND"
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// A connection the server can serve: a bare TCP stream or a TLS session on
+/// top of one. The underlying socket stays reachable for timeouts and the
+/// like
+pub trait Stream: Read + Write + Send {
+    fn socket(&self) -> &TcpStream;
+}
+
+impl Stream for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The stream ended in the middle of a message. Callers reading from a
+/// buffer that is still filling up can retry once more bytes have arrived
+fn truncated(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, msg)
+}
+
+/// The error wrapped in the io::Error returned when a body is larger than
+/// the caller allows. The rest of the body is left unread on the stream
+#[derive(Debug)]
+pub struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "body too large")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// Whether the read failed because the body exceeded its limit
+pub fn is_body_too_large(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<BodyTooLarge>())
+}
+
+/// The error wrapped in the io::Error returned for a request in a version
+/// of HTTP other than 1.0 and 1.1. The headers are left unread
+#[derive(Debug)]
+pub struct UnsupportedVersion(pub String);
+
+impl std::fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unsupported version {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+/// Whether the read failed because the request is in an unknown version
+pub fn is_unsupported_version(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<UnsupportedVersion>())
+}
+
+/// Read one CRLF (or bare LF) terminated line without the line ending.
+/// Returns None on a clean end of stream, and fails if the stream ends in
+/// the middle of a line. "budget" is decremented by the number of bytes
+/// consumed and the read fails once it runs out
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let n = reader.by_ref().take(*budget as u64 + 1).read_until(b'\n', &mut line)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n > *budget {
+        return Err(invalid("header section too large"));
+    }
+    *budget -= n;
+    if line.pop() != Some(b'\n') {
+        return Err(truncated("truncated line"));
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| invalid("header is not UTF-8"))
+}
+
+/// Read "Name: value" lines up to and including the empty line that ends
+/// the header section
+fn read_headers<R: BufRead>(reader: &mut R, budget: &mut usize) -> io::Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, budget)?.ok_or_else(|| truncated("truncated header section"))?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn content_length(headers: &[(String, String)]) -> io::Result<Option<usize>> {
+    match find_header(headers, "Content-Length") {
+        Some(len) => len.parse().map(Some).map_err(|_| invalid("bad Content-Length")),
+        None => Ok(None),
+    }
+}
+
+/// Decode a "Transfer-Encoding: chunked" body, including the trailer section
+fn read_chunked<R: BufRead>(reader: &mut R, max_body_size: usize) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut budget = MAX_HEAD_SIZE;
+        let line = read_line(reader, &mut budget)?.ok_or_else(|| truncated("truncated chunk"))?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))?;
+        if size == 0 {
+            read_headers(reader, &mut budget)?;
+            return Ok(body);
+        }
+        let start = body.len();
+        if size > max_body_size - start {
+            return Err(io::Error::other(BodyTooLarge));
+        }
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        let mut crlf = [0u8; 2];
+        reader.read_exact(&mut crlf)?;
+    }
+}
+
+impl Request {
+    pub fn new(method: &str, target: &str) -> Self {
+        Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: String::from("HTTP/1.1"),
+            headers: Vec::new(),
+            body: Vec::new(),
+            peer_addr: None,
+            socket: None,
+        }
+    }
+
+    /// Read the next request from the stream. Returns None if the client
+    /// closed the connection before sending anything
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Option<Self>> {
+        Request::read_limited(reader, usize::MAX)
+    }
+
+    /// Like "read_from", but fail with "BodyTooLarge" before reading a body
+    /// of more than "max_body_size" bytes
+    pub fn read_limited<R: BufRead>(reader: &mut R, max_body_size: usize) -> io::Result<Option<Self>> {
+        let mut budget = MAX_HEAD_SIZE;
+        let request_line = match read_line(reader, &mut budget)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let mut parts = request_line.split_whitespace();
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) => (method, target, version),
+            _ => return Err(invalid("malformed request line")),
+        };
+        match version.strip_prefix("HTTP/") {
+            Some("1.0" | "1.1") => {}
+            Some(_) => return Err(io::Error::other(UnsupportedVersion(version.to_string()))),
+            None => return Err(invalid("malformed request line")),
+        }
+        let headers = read_headers(reader, &mut budget)?;
+
+        let mut request = Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+            body: Vec::new(),
+            peer_addr: None,
+            socket: None,
+        };
+        if request.header("Transfer-Encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+            // HTTP/1.0 has no chunked encoding, so the framing cannot be trusted
+            if request.version == "HTTP/1.0" {
+                return Err(invalid("chunked body in an HTTP/1.0 request"));
+            }
+            request.body = read_chunked(reader, max_body_size)?;
+        } else if let Some(len) = content_length(&request.headers)? {
+            if len > max_body_size {
+                return Err(io::Error::other(BodyTooLarge));
+            }
+            request.body = vec![0; len];
+            reader.read_exact(&mut request.body)?;
+        }
+        Ok(Some(request))
+    }
+
+    /// Record the connection the request came in on
+    pub fn set_client(&mut self, socket: &TcpStream) {
+        self.peer_addr = socket.peer_addr().ok();
+        self.socket = socket.try_clone().ok().map(|socket| ClientSocket(Arc::new(socket)));
+    }
+
+    /// Value of the first header with the given name, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// The target without its query string
+    pub fn path(&self) -> &str {
+        self.target.split_once('?').map_or(&self.target, |(path, _)| path)
+    }
+
+    /// Whether the client wants the connection kept open after this request:
+    /// HTTP/1.1 keeps it open unless asked not to, HTTP/1.0 only if asked to
+    pub fn keep_alive(&self) -> bool {
+        let has = |option: &str| {
+            self.header("Connection")
+                .is_some_and(|conn| conn.split(',').any(|o| o.trim().eq_ignore_ascii_case(option)))
+        };
+        if self.version == "HTTP/1.0" {
+            has("keep-alive")
+        } else {
+            !has("close")
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Serialize the request; a Content-Length header is added whenever
+    /// there is a body and the caller did not set one
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        let mut head = format!("{} {} {}\r\n", self.method, self.target, self.version);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.body.is_empty() && self.header("Content-Length").is_none() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+impl Response {
+    pub fn new(status: u16, reason: &str) -> Self {
+        Response {
+            status,
+            reason: reason.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Whether responses with this status never have a body
+    fn is_bodiless(&self) -> bool {
+        (100..200).contains(&self.status) || self.status == 204 || self.status == 304
+    }
+
+    /// Read the status line and the headers, leaving the body on the stream
+    pub fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Self> {
+        let mut budget = MAX_HEAD_SIZE;
+        let status_line = read_line(reader, &mut budget)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))?;
+        let mut parts = status_line.splitn(3, ' ');
+        let _version = parts.next();
+        let status = parts
+            .next()
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| invalid("malformed status line"))?;
+        let reason = parts.next().unwrap_or("").to_string();
+        let headers = read_headers(reader, &mut budget)?;
+        Ok(Response { status, reason, headers, body: Vec::new() })
+    }
+
+    /// Read a response from the stream. The body is delimited by
+    /// Content-Length, by chunked encoding, or by the server closing the
+    /// connection, in that order of preference
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Self> {
+        Response::read_limited(reader, usize::MAX)
+    }
+
+    /// Like "read_from", but fail with "BodyTooLarge" instead of reading a
+    /// body of more than "max_body_size" bytes, e.g. from an upstream that
+    /// is not to be trusted with the server's memory
+    pub fn read_limited<R: BufRead>(reader: &mut R, max_body_size: usize) -> io::Result<Self> {
+        let mut response = Response::read_head(reader)?;
+        if response.is_bodiless() {
+            return Ok(response);
+        }
+        if response.header("Transfer-Encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+            response.body = read_chunked(reader, max_body_size)?;
+        } else if let Some(len) = content_length(&response.headers)? {
+            if len > max_body_size {
+                return Err(io::Error::other(BodyTooLarge));
+            }
+            response.body = vec![0; len];
+            reader.read_exact(&mut response.body)?;
+        } else {
+            let limit = u64::try_from(max_body_size).unwrap_or(u64::MAX).saturating_add(1);
+            reader.take(limit).read_to_end(&mut response.body)?;
+            if response.body.len() > max_body_size {
+                return Err(io::Error::other(BodyTooLarge));
+            }
+        }
+        Ok(response)
+    }
+
+    /// Value of the first header with the given name, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// The response to a HEAD request: the same headers, including the
+    /// Content-Length of the body that is left out
+    pub fn into_head(mut self) -> Self {
+        if !self.is_bodiless() && self.header("Content-Length").is_none() {
+            let len = self.body.len().to_string();
+            self = self.with_header("Content-Length", &len);
+        }
+        self.body.clear();
+        self
+    }
+
+    /// The body as text, replacing any invalid UTF-8
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// Serialize the response. Content-Length is always sent, except on
+    /// statuses that cannot have a body, so the client can find the end of
+    /// the body on a kept-alive connection
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.is_bodiless() && self.header("Content-Length").is_none() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_with_body() {
+        let raw = b"POST /submit?x=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 5\r\n\r\nhelloGET";
+        let request = Request::read_from(&mut &raw[..]).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path(), "/submit");
+        assert_eq!(request.header("Content-Length"), Some("5"));
+        assert_eq!(request.body, b"hello");
+        assert!(request.keep_alive());
+    }
+
+    #[test]
+    fn parse_chunked_response() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let response = Response::read_from(&mut &raw[..]).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.reason, "OK");
+        assert_eq!(response.text(), "hello world");
+    }
+
+    #[test]
+    fn oversized_body_is_rejected() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world";
+        let err = Request::read_limited(&mut &raw[..], 10).unwrap_err();
+        assert!(is_body_too_large(&err));
+
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n";
+        let err = Request::read_limited(&mut &raw[..], 10).unwrap_err();
+        assert!(is_body_too_large(&err));
+        assert!(Request::read_limited(&mut &raw[..], 11).is_ok());
+
+        let raws: [&[u8]; 3] = [
+            b"HTTP/1.1 200 OK\r\nContent-Length: 999999999999\r\n\r\nhello world",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nb\r\nhello world\r\n0\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\n\r\nhello world",
+        ];
+        for raw in raws {
+            assert!(is_body_too_large(&Response::read_limited(&mut &raw[..], 10).unwrap_err()));
+        }
+        assert_eq!(Response::read_limited(&mut &raws[2][..], 11).unwrap().text(), "hello world");
+    }
+
+    #[test]
+    fn http_versions() {
+        let request = Request::read_from(&mut &b"HEAD / HTTP/1.0\r\n\r\n"[..]).unwrap().unwrap();
+        assert!(!request.keep_alive());
+        let raw = b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n";
+        assert!(Request::read_from(&mut &raw[..]).unwrap().unwrap().keep_alive());
+        let raw = b"GET / HTTP/1.1\r\nConnection: TE, close\r\n\r\n";
+        assert!(!Request::read_from(&mut &raw[..]).unwrap().unwrap().keep_alive());
+
+        let err = Request::read_from(&mut &b"GET / HTTP/2.0\r\n\r\n"[..]).unwrap_err();
+        assert!(is_unsupported_version(&err));
+        let err = Request::read_from(&mut &b"GET / SPDY/3\r\n\r\n"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let raw = b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(Request::read_from(&mut &raw[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_head_is_rejected() {
+        let raw = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "a".repeat(MAX_HEAD_SIZE));
+        assert!(Request::read_from(&mut raw.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let response = Response::new(404, "NOT FOUND").with_body("nope");
+        let mut raw = Vec::new();
+        response.write_to(&mut raw).unwrap();
+        assert_eq!(raw, b"HTTP/1.1 404 NOT FOUND\r\nContent-Length: 4\r\n\r\nnope");
+        let parsed = Response::read_from(&mut &raw[..]).unwrap();
+        assert_eq!(parsed.header("content-length"), Some("4"));
+        assert_eq!(parsed.body, b"nope");
+
+        let head = response.into_head();
+        assert_eq!((head.header("Content-Length"), head.body.len()), (Some("4"), 0));
+    }
+}
@@ -0,0 +1,82 @@
+//! JSON request bodies and JSON responses, built on serde_json. The body
+//! has already been capped by the server's "max_body_size" before it gets
+//! here, and serde_json refuses to nest deeper than 128 levels, so a hostile
+//! body cannot make the parser use unbounded memory or stack
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::HttpError;
+use crate::http::{Request, Response};
+
+pub const CONTENT_TYPE: &str = "application/json";
+
+impl Request {
+    /// Whether the request says its body is JSON. A missing Content-Type is
+    /// given the benefit of the doubt
+    pub fn is_json(&self) -> bool {
+        match self.header("Content-Type") {
+            Some(content_type) => {
+                let mime = content_type.split(';').next().unwrap_or("").trim();
+                mime.eq_ignore_ascii_case(CONTENT_TYPE)
+            }
+            None => true,
+        }
+    }
+
+    /// Parse the body as JSON into any deserializable type
+    pub fn json<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+impl Response {
+    /// A response whose body is "value" serialized as JSON
+    pub fn json<T: Serialize>(status: u16, reason: &str, value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::new(status, reason)
+                .with_header("Content-Type", CONTENT_TYPE)
+                .with_body(body),
+            Err(e) => {
+                eprintln!("Could not serialize response: {e}");
+                error(500, "INTERNAL SERVER ERROR", "could not serialize response")
+            }
+        }
+    }
+}
+
+/// A JSON error body of the form {"error": "..."}
+pub fn error(status: u16, reason: &str, message: &str) -> Response {
+    let body = serde_json::json!({ "error": message });
+    Response::new(status, reason)
+        .with_header("Content-Type", CONTENT_TYPE)
+        .with_body(body.to_string())
+}
+
+/// Wrap a handler that takes an already parsed JSON body. Requests whose
+/// body is not JSON, or not the JSON the handler expects, are answered with
+/// an error before the handler runs
+///
+/// ```
+/// use webserver::http::Response;
+/// use webserver::json;
+/// use webserver::router::Router;
+///
+/// let router = Router::new().post("/sum", json::handler(|_, numbers: Vec<i64>| {
+///     Ok(Response::json(200, "OK", &numbers.iter().sum::<i64>()))
+/// }));
+/// ```
+pub fn handler<T, F>(f: F) -> impl Fn(&Request) -> Result<Response, HttpError> + Send + Sync + 'static
+where
+    T: DeserializeOwned,
+    F: Fn(&Request, T) -> Result<Response, HttpError> + Send + Sync + 'static,
+{
+    move |request| {
+        if !request.is_json() {
+            return Ok(error(415, "UNSUPPORTED MEDIA TYPE", "expected an application/json body"));
+        }
+        match request.json() {
+            Ok(value) => f(request, value),
+            Err(e) => Ok(error(400, "BAD REQUEST", &format!("malformed JSON: {e}"))),
+        }
+    }
+}
@@ -1 +1,21 @@
+pub mod cgi;
+pub mod client;
+pub mod config;
+pub mod error;
+pub mod files;
+pub mod form;
+pub mod http;
+pub mod json;
+#[cfg(target_os = "linux")]
+pub mod poll;
+pub mod proxy;
+pub mod ratelimit;
+pub mod reload;
+pub mod router;
+pub mod server;
+pub mod template;
+pub mod testing;
 pub mod threadpool;
+pub mod tls;
+pub mod vhost;
+pub mod websocket;
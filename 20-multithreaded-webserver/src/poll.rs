@@ -0,0 +1,219 @@
+//! A thin, mio-style wrapper around Linux epoll: register file descriptors
+//! with a token and an interest, then wait for readiness events. Events are
+//! level-triggered, so a descriptor keeps being reported for as long as it
+//! is ready
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ops::BitOr;
+use std::time::Duration;
+
+/// Identifies a registered descriptor in the events it produces
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Token(pub usize);
+
+/// Which readiness to wait for. "NONE" keeps a descriptor registered while
+/// only reporting hang-ups and errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interest(u32);
+
+impl Interest {
+    pub const NONE: Interest = Interest(0);
+    pub const READABLE: Interest = Interest(libc::EPOLLIN as u32);
+    pub const WRITABLE: Interest = Interest(libc::EPOLLOUT as u32);
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+}
+
+/// Return value of a syscall that signals failure with -1
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+pub struct Poll {
+    epfd: OwnedFd,
+}
+
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        // SAFETY: epoll_create1 has no memory-safety preconditions, and the
+        // descriptor it returns is owned by nobody else
+        let fd = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Poll { epfd: unsafe { OwnedFd::from_raw_fd(fd) } })
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        let mut event = libc::epoll_event { events: interest.0, u64: token.0 as u64 };
+        // SAFETY: "event" is a valid epoll_event for the duration of the call
+        check(unsafe { libc::epoll_ctl(self.epfd.as_raw_fd(), op, fd, &mut event) })?;
+        Ok(())
+    }
+
+    pub fn register<S: AsRawFd>(&self, source: &S, token: Token, interest: Interest) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, source.as_raw_fd(), token, interest)
+    }
+
+    pub fn reregister<S: AsRawFd>(&self, source: &S, token: Token, interest: Interest) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_MOD, source.as_raw_fd(), token, interest)
+    }
+
+    pub fn deregister<S: AsRawFd>(&self, source: &S) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_DEL, source.as_raw_fd(), Token(0), Interest::NONE)
+    }
+
+    /// Wait until at least one registered descriptor is ready or the timeout
+    /// expires, replacing the content of "events". A signal interrupting the
+    /// wait is reported as no events
+    pub fn poll(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as libc::c_int);
+        events.len = 0;
+        // SAFETY: the buffer has room for "capacity" events
+        let n = unsafe {
+            libc::epoll_wait(
+                self.epfd.as_raw_fd(),
+                events.buf.as_mut_ptr(),
+                events.buf.len() as libc::c_int,
+                timeout,
+            )
+        };
+        match check(n) {
+            Ok(n) => events.len = n as usize,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+}
+
+/// Buffer filled by "Poll::poll"
+pub struct Events {
+    buf: Vec<libc::epoll_event>,
+    len: usize,
+}
+
+impl Events {
+    pub fn with_capacity(capacity: usize) -> Events {
+        Events {
+            buf: vec![libc::epoll_event { events: 0, u64: 0 }; capacity.max(1)],
+            len: 0,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        self.buf[..self.len].iter().map(|event| Event { events: event.events, token: Token(event.u64 as usize) })
+    }
+}
+
+/// Readiness of one descriptor
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    events: u32,
+    token: Token,
+}
+
+impl Event {
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.events & libc::EPOLLIN as u32 != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.events & libc::EPOLLOUT as u32 != 0
+    }
+
+    /// The connection is shut down both ways or the descriptor is in an
+    /// error state. A peer that only shut down its side makes the
+    /// descriptor readable, and reading it then returns 0
+    pub fn is_closed(&self) -> bool {
+        self.events & (libc::EPOLLHUP | libc::EPOLLERR) as u32 != 0
+    }
+}
+
+/// Lets other threads interrupt a "Poll::poll" through an eventfd
+pub struct Waker {
+    fd: OwnedFd,
+}
+
+impl Waker {
+    pub fn new(poll: &Poll, token: Token) -> io::Result<Waker> {
+        // SAFETY: as for epoll_create1
+        let fd = check(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+        let waker = Waker { fd: unsafe { OwnedFd::from_raw_fd(fd) } };
+        poll.register(&waker.fd, token, Interest::READABLE)?;
+        Ok(waker)
+    }
+
+    pub fn wake(&self) -> io::Result<()> {
+        let one: u64 = 1;
+        // SAFETY: writes exactly the 8 bytes of "one"
+        let n = unsafe { libc::write(self.fd.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+        match check(n as libc::c_int) {
+            Ok(_) => Ok(()),
+            // the counter is saturated, so the poll will wake up anyway
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reset the wake-up so that the waker stops being reported as readable
+    pub fn reset(&self) {
+        let mut count: u64 = 0;
+        // SAFETY: reads at most the 8 bytes of "count"
+        unsafe { libc::read(self.fd.as_raw_fd(), &mut count as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn readiness_and_wake_ups() {
+        let poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        poll.register(&server, Token(1), Interest::READABLE).unwrap();
+
+        poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(events.iter().count(), 0);
+
+        client.write_all(b"hi").unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        let event = events.iter().next().unwrap();
+        assert_eq!(event.token(), Token(1));
+        assert!(event.is_readable() && !event.is_writable());
+
+        poll.reregister(&server, Token(1), Interest::WRITABLE).unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        assert!(events.iter().next().unwrap().is_writable());
+        poll.deregister(&server).unwrap();
+
+        let waker = Arc::new(Waker::new(&poll, Token(0)).unwrap());
+        let remote = Arc::clone(&waker);
+        thread::spawn(move || remote.wake().unwrap());
+        poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(events.iter().next().unwrap().token(), Token(0));
+        waker.reset();
+        poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(events.iter().count(), 0);
+    }
+}
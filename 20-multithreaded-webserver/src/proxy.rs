@@ -0,0 +1,290 @@
+//! Forwarding requests to a set of upstream servers. Each request opens a
+//! fresh connection to one healthy upstream; an upstream that fails is taken
+//! out of rotation until a periodic health check finds it answering again
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::client::Client;
+use crate::error::HttpError;
+use crate::http::{self, Request, Response};
+
+/// The shortest health check interval; a shorter one is taken for this,
+/// so that the checks never keep the upstreams busy
+pub const MIN_HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Headers that only describe one hop and are never passed on
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// How the next upstream is chosen among the healthy ones
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Balance {
+    /// Take turns
+    #[default]
+    RoundRobin,
+    /// The one with the fewest requests in flight, taking turns on a tie
+    LeastConnections,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    pub upstreams: Vec<SocketAddr>,
+    pub balance: Balance,
+    /// How long to wait for an upstream to accept the connection
+    pub connect_timeout: Duration,
+    /// How long to wait for each read or write once connected; a request
+    /// that runs into it fails with a 504
+    pub timeout: Duration,
+    /// Responses with a larger body are not read, and fail with a 502
+    pub max_response_size: usize,
+    /// Every upstream gets a GET for this path once per interval; it is
+    /// healthy if it answers with anything below 400
+    pub health_check_path: String,
+    /// At least "MIN_HEALTH_CHECK_INTERVAL"
+    pub health_check_interval: Duration,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            upstreams: Vec::new(),
+            balance: Balance::RoundRobin,
+            connect_timeout: Duration::from_secs(1),
+            timeout: Duration::from_secs(30),
+            max_response_size: 16 * 1024 * 1024,
+            health_check_path: String::from("/"),
+            health_check_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+struct Upstream {
+    addr: SocketAddr,
+    healthy: AtomicBool,
+    /// Requests currently being forwarded to it
+    active: AtomicUsize,
+}
+
+struct Inner {
+    config: ProxyConfig,
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+}
+
+/// A load-balancing reverse proxy. Clones share the upstreams and their
+/// health; the health checks stop once the last clone is dropped
+#[derive(Clone)]
+pub struct Proxy {
+    inner: Arc<Inner>,
+}
+
+/// Keeps an upstream's count of requests in flight up to date
+struct InFlight<'a>(&'a Upstream);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Proxy {
+    /// Start proxying to the upstreams in "config", all of which start out
+    /// healthy, and spawn the health-check thread
+    pub fn new(config: ProxyConfig) -> Self {
+        let upstreams = config
+            .upstreams
+            .iter()
+            .map(|&addr| Upstream { addr, healthy: AtomicBool::new(true), active: AtomicUsize::new(0) })
+            .collect();
+        let inner = Arc::new(Inner { config, upstreams, next: AtomicUsize::new(0) });
+
+        let weak = Arc::downgrade(&inner);
+        let interval = inner.config.health_check_interval.max(MIN_HEALTH_CHECK_INTERVAL);
+        thread::spawn(move || health_checks(weak, interval));
+        Proxy { inner }
+    }
+
+    /// The upstreams that currently receive requests
+    pub fn healthy_upstreams(&self) -> Vec<SocketAddr> {
+        self.inner
+            .upstreams
+            .iter()
+            .filter(|upstream| upstream.healthy.load(Ordering::SeqCst))
+            .map(|upstream| upstream.addr)
+            .collect()
+    }
+
+    /// Pick a healthy upstream that has not been tried yet for this request
+    fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let upstreams = &self.inner.upstreams;
+        if upstreams.is_empty() {
+            return None;
+        }
+        let start = self.inner.next.fetch_add(1, Ordering::SeqCst) % upstreams.len();
+        let mut candidates = (0..upstreams.len())
+            .map(|i| (start + i) % upstreams.len())
+            .filter(|i| !tried.contains(i) && upstreams[*i].healthy.load(Ordering::SeqCst));
+        match self.inner.config.balance {
+            Balance::RoundRobin => candidates.next(),
+            Balance::LeastConnections => candidates.min_by_key(|i| upstreams[*i].active.load(Ordering::SeqCst)),
+        }
+    }
+
+    /// Send the request to an upstream and relay its response. Upstreams
+    /// that cannot be reached are marked unhealthy and the next one is
+    /// tried; once the request has been sent it is not retried. Without a
+    /// healthy upstream the proxy is Unavailable; an upstream that fails or
+    /// times out makes a 502 or 504, and so does one whose response is over
+    /// "max_response_size", though it stays in rotation
+    pub fn forward(&self, request: &Request) -> Result<Response, HttpError> {
+        let config = &self.inner.config;
+        let mut tried = Vec::new();
+        while let Some(i) = self.pick(&tried) {
+            tried.push(i);
+            let upstream = &self.inner.upstreams[i];
+            let mut client = match Client::connect_timeout(upstream.addr, config.connect_timeout) {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("Upstream {} unreachable: {e}", upstream.addr);
+                    upstream.healthy.store(false, Ordering::SeqCst);
+                    continue;
+                }
+            };
+
+            upstream.active.fetch_add(1, Ordering::SeqCst);
+            let _in_flight = InFlight(upstream);
+            client.set_max_body_size(config.max_response_size);
+            let result = client
+                .set_timeout(Some(config.timeout))
+                .and_then(|_| client.send(upstream_request(request, upstream.addr)));
+            return match result {
+                Ok(response) => Ok(downstream_response(response)),
+                Err(e) if http::is_body_too_large(&e) => {
+                    let max = config.max_response_size;
+                    eprintln!("Upstream {} sent a response over {max} bytes", upstream.addr);
+                    Err(HttpError::Status(502, String::from("BAD GATEWAY")))
+                }
+                Err(e) => {
+                    eprintln!("Upstream {} failed: {e}", upstream.addr);
+                    upstream.healthy.store(false, Ordering::SeqCst);
+                    if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) {
+                        Err(HttpError::Status(504, String::from("GATEWAY TIMEOUT")))
+                    } else {
+                        Err(HttpError::Status(502, String::from("BAD GATEWAY")))
+                    }
+                }
+            };
+        }
+        match tried.is_empty() {
+            true => Err(HttpError::Unavailable(String::from("no healthy upstream"))),
+            false => Err(HttpError::Status(502, String::from("BAD GATEWAY"))),
+        }
+    }
+}
+
+/// Drop the headers in "HOP_BY_HOP"
+fn end_to_end(headers: &[(String, String)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| !HOP_BY_HOP.iter().any(|hop| hop.eq_ignore_ascii_case(name)))
+        .cloned()
+        .collect()
+}
+
+/// The request as the upstream gets it: addressed to the upstream, with the
+/// client appended to X-Forwarded-For, and on a connection of its own
+fn upstream_request(request: &Request, upstream: SocketAddr) -> Request {
+    let mut headers: Vec<(String, String)> = end_to_end(&request.headers)
+        .into_iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case("Host") && !name.eq_ignore_ascii_case("X-Forwarded-For"))
+        .collect();
+    headers.push((String::from("Host"), upstream.to_string()));
+    if let Some(host) = request.header("Host") {
+        headers.push((String::from("X-Forwarded-Host"), host.to_string()));
+    }
+    let client = request.peer_addr.map(|addr| addr.ip().to_string());
+    let forwarded_for = match (request.header("X-Forwarded-For"), client) {
+        (Some(chain), Some(client)) => Some(format!("{chain}, {client}")),
+        (chain, client) => client.or(chain.map(String::from)),
+    };
+    if let Some(forwarded_for) = forwarded_for {
+        headers.push((String::from("X-Forwarded-For"), forwarded_for));
+    }
+    headers.push((String::from("Connection"), String::from("close")));
+
+    Request {
+        version: String::from("HTTP/1.1"),
+        headers,
+        ..request.clone()
+    }
+}
+
+/// The upstream's response, minus what only applied to its connection
+fn downstream_response(response: Response) -> Response {
+    Response {
+        headers: end_to_end(&response.headers),
+        ..response
+    }
+}
+
+/// Check every upstream once per interval, until the proxy is dropped
+fn health_checks(inner: Weak<Inner>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let Some(inner) = inner.upgrade() else { return };
+        for upstream in &inner.upstreams {
+            let healthy = check(upstream.addr, &inner.config).is_ok_and(|response| response.status < 400);
+            let was_healthy = upstream.healthy.swap(healthy, Ordering::SeqCst);
+            if healthy != was_healthy {
+                let state = if healthy { "healthy" } else { "unhealthy" };
+                eprintln!("Upstream {} is {state}", upstream.addr);
+            }
+        }
+    }
+}
+
+fn check(addr: SocketAddr, config: &ProxyConfig) -> io::Result<Response> {
+    let mut client = Client::connect_timeout(addr, config.connect_timeout)?;
+    client.set_timeout(Some(config.timeout))?;
+    client.send(Request::new("GET", &config.health_check_path).with_header("Connection", "close"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_request_headers() {
+        let mut request = Request::new("POST", "/api/items?page=2")
+            .with_header("Host", "example.com")
+            .with_header("X-Forwarded-For", "10.0.0.1")
+            .with_header("Connection", "keep-alive")
+            .with_header("Transfer-Encoding", "chunked")
+            .with_header("Accept", "*/*")
+            .with_body("hello");
+        request.peer_addr = Some("192.168.1.7:51234".parse().unwrap());
+        let upstream: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+
+        let forwarded = upstream_request(&request, upstream);
+        assert_eq!(forwarded.target, "/api/items?page=2");
+        assert_eq!(forwarded.header("Host"), Some("127.0.0.1:9000"));
+        assert_eq!(forwarded.header("X-Forwarded-Host"), Some("example.com"));
+        assert_eq!(forwarded.header("X-Forwarded-For"), Some("10.0.0.1, 192.168.1.7"));
+        assert_eq!(forwarded.header("Connection"), Some("close"));
+        assert_eq!(forwarded.header("Transfer-Encoding"), None);
+        assert_eq!(forwarded.header("Accept"), Some("*/*"));
+        assert_eq!(forwarded.body, b"hello");
+    }
+}
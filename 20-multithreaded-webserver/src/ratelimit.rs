@@ -0,0 +1,219 @@
+//! Token-bucket rate limiting. Every client gets a bucket that holds up to
+//! "burst" tokens and refills at "per_second" tokens per second; a request
+//! takes one token, and a request that finds the bucket empty is turned away
+//! with a 429
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::http::{Request, Response};
+
+/// What identifies a client
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum RateKey {
+    /// The IP address the request came from
+    #[default]
+    ClientIp,
+    /// The value of this header, e.g. an API key. Requests without it are
+    /// keyed by client IP
+    Header(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// How many requests a client may send in a row
+    pub burst: u32,
+    /// How fast the allowance comes back afterwards; at 0 it never does
+    pub per_second: f64,
+    pub key: RateKey,
+    /// Upper bound on the number of buckets kept in memory
+    pub max_clients: usize,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            burst: 10,
+            per_second: 5.0,
+            key: RateKey::ClientIp,
+            max_clients: 10_000,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// The tokens there would be by now, with those earned since the last
+    /// update
+    fn tokens_at(&self, limit: &RateLimit, now: Instant) -> f64 {
+        let earned = now.duration_since(self.updated).as_secs_f64() * limit.per_second;
+        (self.tokens + earned).min(limit.burst as f64)
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        self.tokens = self.tokens_at(limit, now);
+        self.updated = now;
+    }
+
+    /// Whether the bucket would be full by now, in which case forgetting it
+    /// makes no difference
+    fn is_stale(&self, limit: &RateLimit, now: Instant) -> bool {
+        self.tokens_at(limit, now) >= limit.burst as f64
+    }
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{ip}")
+}
+
+/// The buckets of every client of one route
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter { limit, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    fn key(&self, request: &Request) -> String {
+        let header = match &self.limit.key {
+            RateKey::Header(name) => request.header(name),
+            RateKey::ClientIp => None,
+        };
+        match (header, request.peer_addr) {
+            (Some(value), _) => format!("header:{value}"),
+            (None, Some(addr)) => ip_key(addr.ip()),
+            (None, None) => String::from("unknown"),
+        }
+    }
+
+    /// Take a token for the client that sent "request", or say how long it
+    /// has to wait for the next one
+    pub fn check(&self, request: &Request) -> Result<(), Duration> {
+        let key = self.key(request);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&key) && buckets.len() >= self.limit.max_clients {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket { tokens: self.limit.burst as f64, updated: now });
+        bucket.refill(&self.limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.wait(bucket.tokens))
+        }
+    }
+
+    /// How long the client at "ip" has to wait for a token, if its bucket is
+    /// empty, without taking one. Only a limit keyed by client IP can tell
+    /// before the request has been read
+    pub fn exhausted(&self, ip: IpAddr) -> Option<Duration> {
+        if self.limit.key != RateKey::ClientIp {
+            return None;
+        }
+        let buckets = self.buckets.lock().unwrap();
+        let tokens = buckets.get(&ip_key(ip))?.tokens_at(&self.limit, Instant::now());
+        (tokens < 1.0).then(|| self.wait(tokens))
+    }
+
+    /// How long a bucket with "tokens" takes to get to one
+    fn wait(&self, tokens: f64) -> Duration {
+        // a rate of 0 (or less, or one too slow for a Duration) never brings
+        // the token back
+        let wait = (1.0 - tokens) / self.limit.per_second;
+        Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX)
+    }
+
+    /// Make room for a new client: forget every bucket that has refilled
+    /// completely, and if that is not enough, the one unused the longest
+    fn evict(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        buckets.retain(|_, bucket| !bucket.is_stale(&self.limit, now));
+        if buckets.len() >= self.limit.max_clients {
+            let oldest = buckets.iter().min_by_key(|(_, bucket)| bucket.updated).map(|(key, _)| key.clone());
+            if let Some(key) = oldest {
+                buckets.remove(&key);
+            }
+        }
+    }
+}
+
+/// The 429 for a client that has to wait "retry_after" before trying again
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    Response::new(429, "TOO MANY REQUESTS").with_header("Retry-After", &seconds.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(ip: &str) -> Request {
+        let mut request = Request::new("GET", "/");
+        request.peer_addr = Some(format!("{ip}:40000").parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let limiter = RateLimiter::new(RateLimit { burst: 2, per_second: 20.0, ..RateLimit::default() });
+        assert!(limiter.check(&from("10.0.0.1")).is_ok());
+        assert!(limiter.check(&from("10.0.0.1")).is_ok());
+        let wait = limiter.check(&from("10.0.0.1")).unwrap_err();
+        assert!(wait <= Duration::from_millis(50));
+        // other clients have their own bucket
+        assert!(limiter.check(&from("10.0.0.2")).is_ok());
+
+        std::thread::sleep(wait + Duration::from_millis(10));
+        assert!(limiter.check(&from("10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn exhausted_takes_no_token() {
+        let limiter = RateLimiter::new(RateLimit { burst: 1, per_second: 0.5, ..RateLimit::default() });
+        let ip = "10.0.0.1".parse().unwrap();
+        assert_eq!(limiter.exhausted(ip), None);
+        assert!(limiter.check(&from("10.0.0.1")).is_ok());
+        for _ in 0..3 {
+            assert!(limiter.exhausted(ip).is_some_and(|wait| wait > Duration::from_secs(1)));
+        }
+        assert_eq!(limiter.exhausted("10.0.0.2".parse().unwrap()), None);
+
+        let key = RateKey::Header(String::from("X-Key"));
+        let keyed = RateLimiter::new(RateLimit { burst: 0, key, ..RateLimit::default() });
+        assert!(keyed.check(&from("10.0.0.1")).is_err());
+        assert_eq!(keyed.exhausted(ip), None);
+    }
+
+    #[test]
+    fn buckets_are_evicted() {
+        let limit = RateLimit { burst: 1, per_second: 0.001, max_clients: 2, ..RateLimit::default() };
+        let limiter = RateLimiter::new(limit);
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            assert!(limiter.check(&from(ip)).is_ok());
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(!buckets.contains_key("ip:10.0.0.1"));
+    }
+
+    #[test]
+    fn rates_that_never_refill() {
+        for per_second in [0.0, -1.0, 1e-300] {
+            let limiter = RateLimiter::new(RateLimit { burst: 1, per_second, ..RateLimit::default() });
+            assert!(limiter.check(&from("10.0.0.1")).is_ok());
+            assert_eq!(limiter.check(&from("10.0.0.1")), Err(Duration::MAX));
+            let response = too_many_requests(Duration::MAX);
+            assert_eq!(response.header("Retry-After"), Some(u64::MAX.to_string().as_str()));
+        }
+    }
+}
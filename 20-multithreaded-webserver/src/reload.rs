@@ -0,0 +1,82 @@
+//! Reloading the config file of a running server, either when the file
+//! changes or when the process receives SIGHUP. A config that does not load
+//! is reported and the server keeps running with the one it has
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::server::Reloader;
+
+/// How many times the process has received SIGHUP. A count rather than a
+/// flag, so that every watcher notices each signal
+static HANGUPS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+extern "C" fn on_hangup(_: libc::c_int) {
+    // an atomic increment is all a signal handler may safely do here
+    HANGUPS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Count SIGHUPs instead of letting them end the process
+#[cfg(unix)]
+fn catch_hangups() {
+    let handler: extern "C" fn(libc::c_int) = on_hangup;
+    // SAFETY: the handler only touches an atomic
+    unsafe {
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn catch_hangups() {}
+
+/// Watches a config file on a background thread
+pub struct Watcher {
+    path: PathBuf,
+    reloader: Reloader,
+    interval: Duration,
+}
+
+/// What a change to the file looks like from the outside
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+impl Watcher {
+    /// Watch "path" and apply it through "reloader", checking twice a second
+    pub fn new(path: impl Into<PathBuf>, reloader: Reloader) -> Self {
+        Watcher { path: path.into(), reloader, interval: Duration::from_millis(500) }
+    }
+
+    /// How often to look at the file and for a SIGHUP
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Start watching. From here on SIGHUP reloads the config instead of
+    /// ending the process. The thread ends once the server shuts down
+    pub fn spawn(self) -> JoinHandle<()> {
+        catch_hangups();
+        let mut hangups = HANGUPS.load(Ordering::SeqCst);
+        let mut last = stamp(&self.path);
+        thread::spawn(move || {
+            while !self.reloader.is_shut_down() {
+                thread::sleep(self.interval);
+                let now = HANGUPS.load(Ordering::SeqCst);
+                let current = stamp(&self.path);
+                if now == hangups && current == last {
+                    continue;
+                }
+                (hangups, last) = (now, current);
+                match self.reloader.reload_from(&self.path) {
+                    Ok(()) => eprintln!("Reloaded {}", self.path.display()),
+                    Err(e) => eprintln!("Keeping the running config: {e}"),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,323 @@
+//! Dispatching requests to handlers by method and path
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::cgi::Cgi;
+use crate::error::{ErrorPages, HttpError};
+use crate::files::FileServer;
+use crate::http::{Request, Response};
+use crate::proxy::Proxy;
+use crate::ratelimit::{self, RateLimit, RateLimiter};
+use crate::template::Templates;
+use crate::websocket::{self, Runner, WebSocket};
+
+/// Type declaration for "a function that turns a request into a response
+/// and can be shared between workers". Errors are turned into error pages
+/// by the server
+pub type Handler = Arc<dyn Fn(&Request) -> Result<Response, HttpError> + Send + Sync>;
+
+/// What a route does with the requests it matches
+#[derive(Clone)]
+enum Endpoint {
+    Http(Handler),
+    WebSocket(websocket::Endpoint),
+}
+
+#[derive(Clone)]
+struct Route {
+    /// "*" matches every method
+    method: String,
+    path: String,
+    /// Also match every path below "path"
+    prefix: bool,
+    endpoint: Endpoint,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+/// The methods a route for any method is taken to allow
+const METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+impl Route {
+    fn matches(&self, method: &str, path: &str) -> bool {
+        (self.method == "*" || self.method == method) && self.matches_path(path)
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        if !self.prefix {
+            return path == self.path;
+        }
+        match path.strip_prefix(self.path.trim_end_matches('/')) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// The outcome of routing a request
+pub enum Dispatch {
+    /// Send the response, then carry on with the connection as usual
+    Respond(Response),
+    /// Send the "101 Switching Protocols" response, then hand the connection
+    /// over to the WebSocket endpoint
+    Upgrade(Response, websocket::Endpoint),
+}
+
+/// An ordered list of routes; the first route whose method and path match
+/// the request handles it. Cloning a router is cheap since the handlers are
+/// reference counted
+#[derive(Clone)]
+pub struct Router {
+    routes: Vec<Arc<Route>>,
+}
+
+/// Respond with the content of an HTML file. A file that cannot be read is
+/// an internal error: it is part of the site, not something the client asked
+/// for by name
+pub fn page(status: u16, reason: &str, html_path: &str) -> Result<Response, HttpError> {
+    let body = fs::read(html_path).map_err(|e| HttpError::internal(format!("{html_path}: {e}")))?;
+    Ok(Response::new(status, reason)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(body))
+}
+
+impl Router {
+    /// A router without any route; every request gets the 404 page
+    pub fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    /// Add a route for requests with exactly this method and path
+    pub fn route<F>(mut self, method: &str, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Result<Response, HttpError> + Send + Sync + 'static,
+    {
+        self.routes.push(Arc::new(Route {
+            method: method.to_string(),
+            path: path.to_string(),
+            prefix: false,
+            endpoint: Endpoint::Http(Arc::new(handler)),
+            limiter: None,
+        }));
+        self
+    }
+
+    /// Add a route that accepts WebSocket handshakes on this path and runs
+    /// "handler" on each upgraded connection
+    pub fn websocket<F>(mut self, path: &str, runner: Runner, handler: F) -> Self
+    where
+        F: Fn(WebSocket) + Send + Sync + 'static,
+    {
+        let endpoint = websocket::Endpoint { handler: Arc::new(handler), runner };
+        self.routes.push(Arc::new(Route {
+            method: String::from("GET"),
+            path: path.to_string(),
+            prefix: false,
+            endpoint: Endpoint::WebSocket(endpoint),
+            limiter: None,
+        }));
+        self
+    }
+
+    /// Forward requests of any method for "prefix" and every path below it
+    /// to the upstreams of "proxy". The target is passed on unchanged
+    pub fn proxy(mut self, prefix: &str, proxy: Proxy) -> Self {
+        self.routes.push(Arc::new(Route {
+            method: String::from("*"),
+            path: prefix.to_string(),
+            prefix: true,
+            endpoint: Endpoint::Http(Arc::new(move |request: &Request| proxy.forward(request))),
+            limiter: None,
+        }));
+        self
+    }
+
+    /// Serve GET requests for "prefix" and every path below it from the
+    /// files of "files", with the prefix stripped off
+    pub fn files(mut self, prefix: &str, files: FileServer) -> Self {
+        let strip = prefix.trim_end_matches('/').len();
+        self.routes.push(Arc::new(Route {
+            method: String::from("GET"),
+            path: prefix.to_string(),
+            prefix: true,
+            endpoint: Endpoint::Http(Arc::new(move |request: &Request| {
+                files.serve(request, &request.path()[strip..])
+            })),
+            limiter: None,
+        }));
+        self
+    }
+
+    /// Run the CGI program "cgi" for requests of any method to "path" and
+    /// every path below it; the part below becomes PATH_INFO
+    pub fn cgi(mut self, path: &str, cgi: Cgi) -> Self {
+        let script_name = path.trim_end_matches('/').to_string();
+        self.routes.push(Arc::new(Route {
+            method: String::from("*"),
+            path: path.to_string(),
+            prefix: true,
+            endpoint: Endpoint::Http(Arc::new(move |request: &Request| {
+                let path_info = &request.path()[script_name.len()..];
+                cgi.run(request, &script_name, path_info)
+            })),
+            limiter: None,
+        }));
+        self
+    }
+
+    /// Rate limit the route added last. Every route keeps its own buckets,
+    /// even if two of them use the same limit
+    pub fn limit(mut self, limit: RateLimit) -> Self {
+        let route = self.routes.last_mut().expect("limit() must follow the route it applies to");
+        Arc::make_mut(route).limiter = Some(Arc::new(RateLimiter::new(limit)));
+        self
+    }
+
+    pub fn get<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Result<Response, HttpError> + Send + Sync + 'static,
+    {
+        self.route("GET", path, handler)
+    }
+
+    pub fn post<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Result<Response, HttpError> + Send + Sync + 'static,
+    {
+        self.route("POST", path, handler)
+    }
+
+    /// The first route for the request. HEAD requests fall back to the GET
+    /// route of the path; the server drops the body of the response
+    fn find(&self, request: &Request) -> Option<&Route> {
+        let path = request.path();
+        let find = |method: &str| {
+            self.routes.iter().map(|route| route.as_ref()).find(|route| route.matches(method, path))
+        };
+        find(&request.method).or_else(|| if request.method == "HEAD" { find("GET") } else { None })
+    }
+
+    /// The methods accepted for "path", or across all routes for "*", as
+    /// the value of an Allow header. OPTIONS is always allowed
+    fn allow(&self, path: &str) -> String {
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in self.routes.iter().filter(|route| path == "*" || route.matches_path(path)) {
+            let methods = match route.method.as_str() {
+                "*" => METHODS.to_vec(),
+                "GET" => vec!["GET", "HEAD"],
+                method => vec![method],
+            };
+            for method in methods.into_iter().chain(["OPTIONS"]) {
+                if !allowed.contains(&method) {
+                    allowed.push(method);
+                }
+            }
+        }
+        if allowed.is_empty() {
+            allowed.push("OPTIONS");
+        }
+        allowed.join(", ")
+    }
+
+    /// Take a token from the rate limit of the route "request" goes to.
+    /// Returns the 429 to send if the client is over the limit. The server
+    /// checks this before the handler runs. In EventLoop mode that is before
+    /// the request gets anywhere near a worker; in Blocking mode the worker
+    /// holding the connection has read the request by then, which is why new
+    /// connections are also checked against "exhausted" first
+    pub fn throttle(&self, request: &Request) -> Option<Response> {
+        let limiter = self.find(request)?.limiter.as_ref()?;
+        limiter.check(request).err().map(ratelimit::too_many_requests)
+    }
+
+    /// How long the client at "ip" has to wait until no route limited by
+    /// client IP turns it away, if any route does now. The route a
+    /// connection will ask for is not known when it is accepted, so this
+    /// takes every such route into account
+    pub fn exhausted(&self, ip: IpAddr) -> Option<Duration> {
+        self.routes.iter().filter_map(|route| route.limiter.as_ref()?.exhausted(ip)).max()
+    }
+
+    /// Run the handler of the first matching route, or decide to upgrade
+    /// the connection if it is a WebSocket route. Without a route, OPTIONS
+    /// requests (including "OPTIONS *") learn the allowed methods, other
+    /// methods on a known path get a 405, and the rest a NotFound. Rate
+    /// limits are not checked here; see "throttle"
+    pub fn dispatch(&self, request: &Request) -> Result<Dispatch, HttpError> {
+        match self.find(request).map(|route| &route.endpoint) {
+            Some(Endpoint::Http(handler)) => handler(request).map(Dispatch::Respond),
+            Some(Endpoint::WebSocket(endpoint)) => match websocket::handshake(request) {
+                Ok(response) => Ok(Dispatch::Upgrade(response, endpoint.clone())),
+                Err(response) => Ok(Dispatch::Respond(response)),
+            },
+            None => self.unrouted(request).map(Dispatch::Respond),
+        }
+    }
+
+    /// The answer to a request no route takes
+    fn unrouted(&self, request: &Request) -> Result<Response, HttpError> {
+        let path = request.path();
+        let options = request.method == "OPTIONS";
+        let known = (options && path == "*") || self.routes.iter().any(|route| route.matches_path(path));
+        if !known {
+            return Err(HttpError::NotFound);
+        }
+        let response = if options {
+            Response::new(204, "NO CONTENT")
+        } else {
+            Response::new(405, "METHOD NOT ALLOWED")
+        };
+        Ok(response.with_header("Allow", &self.allow(path)))
+    }
+
+    /// Like "dispatch", for callers that cannot hand over the connection:
+    /// WebSocket routes answer with their handshake error instead, and
+    /// errors with the default error pages
+    pub fn handle(&self, request: &Request) -> Response {
+        match self.dispatch(request) {
+            Ok(Dispatch::Respond(response)) => response,
+            Ok(Dispatch::Upgrade(..)) => Response::new(426, "UPGRADE REQUIRED"),
+            Err(e) => ErrorPages::default().respond(request, &e),
+        }
+    }
+}
+
+/// The pages this server has always served, plus a rendered page that
+/// shows the request back. Debug builds pick up template edits right away
+impl Default for Router {
+    fn default() -> Self {
+        let templates = Templates::new("templates").hot_reload(cfg!(debug_assertions));
+        Router::new()
+            .get("/", |_| page(200, "OK", "index.html"))
+            .get("/busybox", |_| {
+                thread::sleep(Duration::from_secs(5));
+                page(200, "OK", "busybox.html")
+            })
+            .get("/hello", move |request| {
+                let context = serde_json::json!({
+                    "title": "Hello!",
+                    "method": request.method,
+                    "path": request.path(),
+                    "headers": request.headers,
+                });
+                templates.page(200, "OK", "hello.html", &context)
+            })
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.routes.iter().map(|route| {
+                if route.prefix {
+                    format!("{} {}/*", route.method, route.path.trim_end_matches('/'))
+                } else {
+                    format!("{} {}", route.method, route.path)
+                }
+            }))
+            .finish()
+    }
+}
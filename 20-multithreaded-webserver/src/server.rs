@@ -0,0 +1,574 @@
+//! Accepting connections and dispatching them to the thread pool. The same
+//! routing and the same pool serve both the plain HTTP and the HTTPS listener
+use std::error::Error;
+use std::io::{self, prelude::*, BufReader};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::config;
+use crate::error::ErrorPages;
+use crate::http::{self, Request, Response, Stream};
+use crate::ratelimit;
+use crate::router::{Dispatch, Router};
+use crate::threadpool::ThreadPool;
+use crate::tls::{TlsConfig, TlsStream};
+use crate::vhost::{self, VirtualHost};
+use crate::websocket::WebSocket;
+
+#[cfg(target_os = "linux")]
+mod event_loop;
+
+/// How the plain HTTP listener drives its connections
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerMode {
+    /// Every connection is served by one worker from accept to close, so an
+    /// idle kept-alive connection still holds on to its worker
+    #[default]
+    Blocking,
+    /// One thread multiplexes every connection with epoll and only hands
+    /// complete requests to the workers. HTTPS and the HTTP-to-HTTPS
+    /// redirect still use the blocking model
+    #[cfg(target_os = "linux")]
+    EventLoop,
+}
+
+/// Everything needed to start a server
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Address of the plain HTTP listener
+    pub addr: String,
+    pub workers: usize,
+    pub mode: ServerMode,
+    /// Stop accepting after this many connections; 0 means run indefinitely
+    pub max_requests: usize,
+    /// How long an idle kept-alive connection may hold on to a worker. In
+    /// the event-loop mode, also how long a client may take no part of its
+    /// response before the connection is dropped
+    pub keep_alive_timeout: Duration,
+    /// Requests with a larger body are rejected with a 413 before the body
+    /// is read
+    pub max_body_size: usize,
+    /// Optional HTTPS listener
+    pub tls: Option<TlsConfig>,
+    /// Sites picked by the Host header of each request
+    pub hosts: Vec<VirtualHost>,
+    /// Serves the requests that are not for any of the hosts
+    pub router: Router,
+    /// The pages for errors that handlers return
+    pub error_pages: ErrorPages,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: String::from("127.0.0.1:8080"),
+            workers: 4,
+            mode: ServerMode::Blocking,
+            max_requests: 0,
+            keep_alive_timeout: Duration::from_secs(5),
+            max_body_size: http::DEFAULT_MAX_BODY_SIZE,
+            tls: None,
+            hosts: Vec::new(),
+            router: Router::default(),
+            error_pages: ErrorPages::default(),
+        }
+    }
+}
+
+/// The part of the config that can be swapped while the server runs
+struct Settings {
+    hosts: Vec<VirtualHost>,
+    router: Router,
+    max_body_size: usize,
+    keep_alive_timeout: Duration,
+    error_pages: ErrorPages,
+}
+
+impl Settings {
+    fn new(config: ServerConfig) -> Self {
+        Settings {
+            hosts: config.hosts,
+            router: config.router,
+            max_body_size: config.max_body_size,
+            keep_alive_timeout: config.keep_alive_timeout,
+            error_pages: config.error_pages,
+        }
+    }
+
+    /// The router of the site the request is for
+    fn router(&self, request: &Request) -> &Router {
+        vhost::select(&self.hosts, &self.router, request)
+    }
+
+    /// Whether the client at "ip" is out of tokens on a route limited by
+    /// client IP, of any site, since the site is not known either before the
+    /// request is read; see "Router::exhausted"
+    fn exhausted(&self, ip: IpAddr) -> Option<Duration> {
+        let routers = self.hosts.iter().map(|host| &host.router).chain([&self.router]);
+        routers.filter_map(|router| router.exhausted(ip)).max()
+    }
+
+    /// Route the request, answering an error of its handler with the error
+    /// page for it
+    fn dispatch(&self, request: &Request) -> Dispatch {
+        match self.router(request).dispatch(request) {
+            Ok(dispatch) => dispatch,
+            Err(e) => Dispatch::Respond(self.error_pages.respond(request, &e)),
+        }
+    }
+}
+
+/// What every connection needs to know about the server it belongs to
+struct Shared {
+    settings: RwLock<Arc<Settings>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Shared {
+    /// The settings as of now. A request holds on to them until it has been
+    /// answered, so a reload never changes them under its feet
+    fn settings(&self) -> Arc<Settings> {
+        Arc::clone(&self.settings.read().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// The settings that are only read when the server starts, as name and value
+fn restart_only(config: &ServerConfig) -> Vec<(&'static str, String)> {
+    vec![
+        ("addr", config.addr.clone()),
+        ("workers", config.workers.to_string()),
+        ("mode", format!("{:?}", config.mode)),
+        ("max_requests", config.max_requests.to_string()),
+        ("tls", format!("{:?}", config.tls)),
+    ]
+}
+
+/// Swaps a new config into a running server. Connections accepted and
+/// requests read from then on use the new routes and settings, while the
+/// requests in flight finish with the ones they started with. The listeners,
+/// the workers and TLS stay as they were started
+#[derive(Clone)]
+pub struct Reloader {
+    shared: Arc<Shared>,
+    started_with: Arc<Vec<(&'static str, String)>>,
+}
+
+impl Reloader {
+    /// Switch to "config". Settings that need a restart are ignored, with a
+    /// warning if they differ from the running ones
+    pub fn apply(&self, config: ServerConfig) {
+        for ((name, old), (_, new)) in self.started_with.iter().zip(restart_only(&config)) {
+            if *old != new {
+                eprintln!("Ignoring the new {name} until the server restarts");
+            }
+        }
+        let settings = Arc::new(Settings::new(config));
+        *self.shared.settings.write().unwrap_or_else(|e| e.into_inner()) = settings;
+    }
+
+    /// Load the config file at "path" and switch to it. A file that cannot
+    /// be read or is invalid is reported and the running config kept
+    pub fn reload_from<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        self.apply(config::load(path)?);
+        Ok(())
+    }
+
+    /// Whether the server has been shut down, after which reloading is moot
+    pub fn is_shut_down(&self) -> bool {
+        self.shared.shutdown.load(Ordering::SeqCst)
+    }
+}
+
+/// A read that gave up because of the socket's read timeout
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// The response for a request that could not be read, if the client should
+/// be told about it before the connection is closed
+fn reject(e: &io::Error) -> Option<Response> {
+    let response = if http::is_body_too_large(e) {
+        Response::new(413, "PAYLOAD TOO LARGE")
+    } else if http::is_unsupported_version(e) {
+        Response::new(505, "HTTP VERSION NOT SUPPORTED")
+    } else if e.kind() == io::ErrorKind::InvalidData {
+        Response::new(400, "BAD REQUEST")
+    } else {
+        return None;
+    };
+    Some(response.with_header("Connection", "close"))
+}
+
+/// Decide whether the connection stays open after this response, and tell
+/// the client: HTTP/1.1 clients only need to hear about a close, HTTP/1.0
+/// clients only about a connection that stays open. HEAD requests get the
+/// headers alone
+fn finish(request: &Request, mut response: Response, shared: &Shared) -> (Response, bool) {
+    if request.method == "HEAD" {
+        response = response.into_head();
+    }
+    let keep_alive = request.keep_alive() && !shared.shutdown.load(Ordering::SeqCst);
+    if !keep_alive {
+        (response.with_header("Connection", "close"), false)
+    } else if request.version == "HTTP/1.0" {
+        (response.with_header("Connection", "keep-alive"), true)
+    } else {
+        (response, true)
+    }
+}
+
+/// Serve requests on one connection until the client asks to close it, goes
+/// quiet for longer than the read timeout, or the server shuts down. Works
+/// on a bare TCP stream and a TLS session alike. The stream is handed back
+/// when the connection is done with HTTP, or None if it was upgraded to a
+/// WebSocket and now belongs to the WebSocket handler
+fn handle_connection<S: Stream + 'static>(stream: S, shared: &Shared) -> io::Result<Option<S>> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let settings = shared.settings();
+        let mut request = match Request::read_limited(&mut reader, settings.max_body_size) {
+            Ok(Some(request)) => request,
+            Ok(None) => break, // client hung up between requests
+            Err(e) if is_timeout(&e) => break,
+            Err(e) => match reject(&e) {
+                Some(response) => {
+                    response.write_to(reader.get_mut())?;
+                    break;
+                }
+                None => return Err(e),
+            },
+        };
+        request.set_client(reader.get_ref().socket());
+
+        // this worker is already taken, e.g. by a kept-alive connection or
+        // a limit by header; a rejection only saves the handler
+        let dispatch = match settings.router(&request).throttle(&request) {
+            Some(response) => Dispatch::Respond(response),
+            None => settings.dispatch(&request),
+        };
+        let response = match dispatch {
+            Dispatch::Respond(response) => response,
+            Dispatch::Upgrade(response, endpoint) => {
+                response.write_to(reader.get_mut())?;
+                let pending = reader.buffer().to_vec();
+                let stream = reader.into_inner();
+                // the keep-alive timeout is for HTTP; the handler picks its own
+                stream.socket().set_read_timeout(None)?;
+                endpoint.start(WebSocket::from_upgraded(Box::new(stream), pending));
+                return Ok(None);
+            }
+        };
+        let (response, keep_alive) = finish(&request, response, shared);
+        response.write_to(reader.get_mut())?;
+        if !keep_alive {
+            break;
+        }
+    }
+    Ok(Some(reader.into_inner()))
+}
+
+/// Answer a plain HTTP request with a redirect to the same path on the HTTPS
+/// listener, keeping the host name the client asked for
+fn redirect_request<S: Read + Write>(stream: &mut S, https_port: u16) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let request = match Request::read_from(&mut reader)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    // drop the port of the plain listener, if any
+    let host = request.header("Host").unwrap_or("localhost");
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    let location = if https_port == 443 {
+        format!("https://{host}{}", request.target)
+    } else {
+        format!("https://{host}:{https_port}{}", request.target)
+    };
+    Response::new(301, "MOVED PERMANENTLY")
+        .with_header("Location", &location)
+        .with_header("Connection", "close")
+        .write_to(reader.get_mut())
+}
+
+/// What a worker does with a freshly accepted connection
+type ConnectionHandler = Arc<dyn Fn(TcpStream) + Send + Sync>;
+
+/// Who serves the connections of a listener
+enum Driver {
+    /// Every connection goes to a worker for its whole life
+    Blocking(ConnectionHandler),
+    /// The event loop reads requests and only hands them to the workers
+    EventLoop,
+}
+
+/// A server whose listeners are already bound, so callers can learn the
+/// actual addresses (e.g. after binding to port 0) before running it
+pub struct Server {
+    listener: TcpListener,
+    tls: Option<(TcpListener, Arc<rustls::ServerConfig>)>,
+    redirect_http: bool,
+    mode: ServerMode,
+    pool: ThreadPool,
+    max_requests: usize,
+    started_with: Vec<(&'static str, String)>,
+    shared: Arc<Shared>,
+}
+
+impl Server {
+    /// Bind every listener in the config and spawn the worker threads
+    pub fn bind(config: ServerConfig) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(&config.addr)?;
+        let tls = match &config.tls {
+            Some(tls) => Some((TcpListener::bind(&tls.addr)?, tls.load()?)),
+            None => None,
+        };
+        let redirect_http = config.tls.as_ref().is_some_and(|tls| tls.redirect_http);
+
+        Ok(Server {
+            listener,
+            tls,
+            redirect_http,
+            mode: config.mode,
+            pool: ThreadPool::new(config.workers),
+            max_requests: config.max_requests,
+            started_with: restart_only(&config),
+            shared: Arc::new(Shared {
+                settings: RwLock::new(Arc::new(Settings::new(config))),
+                shutdown: Arc::new(AtomicBool::new(false)),
+            }),
+        })
+    }
+
+    /// A handle for changing the config once the server runs
+    pub fn reloader(&self) -> Reloader {
+        Reloader { shared: Arc::clone(&self.shared), started_with: Arc::new(self.started_with.clone()) }
+    }
+
+    /// Address of the plain HTTP listener
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Address of the HTTPS listener, if there is one
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.tls.as_ref().and_then(|(listener, _)| listener.local_addr().ok())
+    }
+
+    /// Accept connections until the server is shut down or "max_requests"
+    /// connections have been accepted across all listeners. Returns once
+    /// every in-flight connection has been served
+    pub fn run(self) {
+        let shared = Arc::clone(&self.shared);
+        let plain = if let (true, Some(port)) = (self.redirect_http, self.tls_addr()) {
+            let port = port.port();
+            Driver::Blocking(Arc::new(move |mut stream| {
+                if let Err(e) = redirect_request(&mut stream, port) {
+                    eprintln!("Connection failed: {e}");
+                }
+            }))
+        } else if self.mode != ServerMode::Blocking {
+            Driver::EventLoop
+        } else {
+            let shared = Arc::clone(&shared);
+            Driver::Blocking(Arc::new(move |stream| {
+                if let Err(e) = handle_connection(stream, &shared) {
+                    eprintln!("Connection failed: {e}");
+                }
+            }))
+        };
+
+        let mut listeners = vec![(self.listener, plain, false)];
+        if let Some((listener, tls_config)) = self.tls {
+            let shared = Arc::clone(&shared);
+            let secure: ConnectionHandler = Arc::new(move |stream| {
+                let result = TlsStream::new(Arc::clone(&tls_config), stream).and_then(|stream| {
+                    match handle_connection(stream, &shared)? {
+                        Some(mut stream) => stream.close(),
+                        None => Ok(()),
+                    }
+                });
+                if let Err(e) = result {
+                    eprintln!("TLS connection failed: {e}");
+                }
+            });
+            listeners.push((listener, Driver::Blocking(secure), true));
+        }
+
+        let acceptor = Acceptor {
+            addrs: listeners.iter().filter_map(|(l, ..)| l.local_addr().ok()).collect(),
+            pool: self.pool,
+            accepted: AtomicUsize::new(0),
+            max_requests: self.max_requests,
+            shared: Arc::clone(&shared),
+        };
+        thread::scope(|scope| {
+            for (listener, driver, tls) in listeners {
+                let (acceptor, shared) = (&acceptor, &shared);
+                match driver {
+                    Driver::Blocking(handler) => scope.spawn(move || acceptor.accept(listener, handler, tls)),
+                    #[cfg(target_os = "linux")]
+                    Driver::EventLoop => scope.spawn(move || {
+                        if let Err(e) = event_loop::serve(listener, acceptor, shared) {
+                            eprintln!("Event loop failed: {e}");
+                            acceptor.stop();
+                        }
+                    }),
+                    #[cfg(not(target_os = "linux"))]
+                    Driver::EventLoop => unreachable!(),
+                };
+            }
+        });
+        // dropping the acceptor drops the pool, which waits for the workers
+    }
+
+    /// Run the server on a background thread and return a handle that can
+    /// stop it again
+    pub fn spawn(self) -> ServerHandle {
+        let addr = self.local_addr().ok();
+        let tls_addr = self.tls_addr();
+        let shutdown = Arc::clone(&self.shared.shutdown);
+        let thread = thread::spawn(move || self.run());
+        ServerHandle {
+            addr,
+            tls_addr,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// State shared by the accept loops of all listeners
+struct Acceptor {
+    addrs: Vec<SocketAddr>,
+    pool: ThreadPool,
+    accepted: AtomicUsize,
+    max_requests: usize,
+    shared: Arc<Shared>,
+}
+
+impl Acceptor {
+    fn is_shut_down(&self) -> bool {
+        self.shared.shutdown.load(Ordering::SeqCst)
+    }
+
+    fn stop(&self) {
+        stop(&self.shared.shutdown, &self.addrs);
+    }
+
+    /// Accept loop for one listener; each connection is served by "handler"
+    /// on one of the pool's workers, unless its client is over a rate limit
+    fn accept(&self, listener: TcpListener, handler: ConnectionHandler, tls: bool) {
+        for stream in listener.incoming() {
+            if self.is_shut_down() {
+                return;
+            }
+            if let Ok(stream) = stream {
+                let settings = self.shared.settings();
+                match stream.peer_addr().ok().and_then(|addr| settings.exhausted(addr.ip())) {
+                    Some(retry_after) => turn_away(stream, retry_after, tls),
+                    None => {
+                        if let Err(e) = stream.set_read_timeout(Some(settings.keep_alive_timeout)) {
+                            eprintln!("Could not set read timeout: {e}");
+                        }
+                        let handler = Arc::clone(&handler);
+                        self.pool.execute(move || handler(stream));
+                    }
+                }
+            }
+
+            let n = self.accepted.fetch_add(1, Ordering::SeqCst) + 1;
+            if self.max_requests > 0 && n >= self.max_requests {
+                self.stop();
+                return;
+            }
+        }
+    }
+}
+
+/// Answer a connection from a client over its rate limit right on the
+/// accept thread, so that it never takes a worker. What has arrived of the
+/// request is read first, since closing a socket with unread data resets the
+/// connection and can lose the 429. A TLS connection is just closed, as
+/// answering it would take a handshake
+fn turn_away(mut stream: TcpStream, retry_after: Duration, tls: bool) {
+    if tls || stream.set_nonblocking(true).is_err() {
+        return;
+    }
+    let mut buf = [0u8; 4096];
+    for _ in 0..16 {
+        if !matches!(stream.read(&mut buf), Ok(n) if n > 0) {
+            break;
+        }
+    }
+    let response = ratelimit::too_many_requests(retry_after).with_header("Connection", "close");
+    let _ = response.write_to(&mut stream);
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+/// Raise the shutdown flag, then wake every accept loop blocked in accept()
+/// with a throwaway connection so that it notices
+fn stop(shutdown: &AtomicBool, addrs: &[SocketAddr]) {
+    shutdown.store(true, Ordering::SeqCst);
+    for addr in addrs {
+        let _ = TcpStream::connect(addr);
+    }
+}
+
+/// Handle to a server running on a background thread. Dropping the handle
+/// shuts the server down
+pub struct ServerHandle {
+    addr: Option<SocketAddr>,
+    tls_addr: Option<SocketAddr>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// Address of the plain HTTP listener
+    pub fn addr(&self) -> SocketAddr {
+        self.addr.expect("listener has no local address")
+    }
+
+    /// Address of the HTTPS listener, if there is one
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.tls_addr
+    }
+
+    /// Stop accepting connections and wait for in-flight ones to finish.
+    /// Kept-alive connections are closed after their current request, or
+    /// once they have been idle for the keep-alive timeout
+    pub fn shutdown(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let addrs: Vec<SocketAddr> = self.addr.iter().chain(self.tls_addr.iter()).copied().collect();
+            stop(&self.shutdown, &addrs);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
+
+/// Will serve "max_request" number of requests; if max_request is 0, then
+/// the server will run definitely
+pub fn start_server(config: ServerConfig) -> Result<(), Box<dyn Error>> {
+    Server::bind(config)?.run();
+    Ok(())
+}
@@ -0,0 +1,408 @@
+//! The event-loop server mode: one thread owns every connection of the
+//! listener, reads and writes them without blocking, and hands complete
+//! requests to the thread pool only to run the handler. An idle kept-alive
+//! connection costs a buffer and a descriptor instead of a worker
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{finish, reject, Acceptor, Settings, Shared};
+use crate::http::{Request, Response, MAX_HEAD_SIZE};
+use crate::poll::{Events, Interest, Poll, Token, Waker};
+use crate::router::Dispatch;
+use crate::websocket::{self, WebSocket};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+/// Connection tokens start here and are never reused, so a late reply from
+/// a worker can never reach a different connection
+const FIRST_CONNECTION: usize = 2;
+
+/// What a worker sends back to the event loop
+enum Reply {
+    Response { token: Token, bytes: Vec<u8>, keep_alive: bool },
+    Upgrade { token: Token, bytes: Vec<u8>, endpoint: websocket::Endpoint },
+}
+
+#[derive(PartialEq)]
+enum State {
+    /// Waiting for (the rest of) a request
+    Reading,
+    /// A worker is running the handler
+    Processing,
+    /// Sending the response
+    Writing,
+}
+
+struct Connection {
+    stream: TcpStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    state: State,
+    close_after_write: bool,
+    /// The client shut down its side; the requests it sent before that are
+    /// still answered, and the connection closed once they are
+    read_closed: bool,
+    last_active: Instant,
+}
+
+struct EventLoop<'a> {
+    poll: Poll,
+    waker: Arc<Waker>,
+    replies: Receiver<Reply>,
+    sender: Sender<Reply>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    acceptor: &'a Acceptor,
+    shared: &'a Arc<Shared>,
+}
+
+/// Serve every connection of "listener" from the calling thread until the
+/// server shuts down and the requests in flight have been answered
+pub(super) fn serve(listener: TcpListener, acceptor: &Acceptor, shared: &Arc<Shared>) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let poll = Poll::new()?;
+    poll.register(&listener, LISTENER, Interest::READABLE)?;
+    let waker = Arc::new(Waker::new(&poll, WAKER)?);
+    let (sender, replies) = mpsc::channel();
+    let mut event_loop = EventLoop {
+        poll,
+        waker,
+        replies,
+        sender,
+        connections: HashMap::new(),
+        next_token: FIRST_CONNECTION,
+        acceptor,
+        shared,
+    };
+
+    let mut events = Events::with_capacity(1024);
+    let mut listener = Some(listener);
+    loop {
+        let timeout = shared.settings().keep_alive_timeout;
+        let tick = timeout.clamp(Duration::from_millis(10), Duration::from_secs(1));
+        event_loop.poll.poll(&mut events, Some(tick))?;
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => {
+                    if let Some(listener) = &listener {
+                        event_loop.accept(listener)?;
+                    }
+                }
+                WAKER => {
+                    event_loop.waker.reset();
+                    event_loop.collect_replies();
+                }
+                token => event_loop.ready(token, event.is_readable(), event.is_writable(), event.is_closed()),
+            }
+        }
+        event_loop.close_idle();
+
+        if acceptor.is_shut_down() {
+            if let Some(listener) = listener.take() {
+                event_loop.poll.deregister(&listener)?;
+            }
+            event_loop.close_idle_now();
+            if event_loop.connections.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl EventLoop<'_> {
+    fn accept(&mut self, listener: &TcpListener) -> io::Result<()> {
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // e.g. out of descriptors; drop this one and keep serving
+                Err(e) => {
+                    eprintln!("Accept failed: {e}");
+                    return Ok(());
+                }
+            };
+            if self.acceptor.is_shut_down() {
+                return Ok(());
+            }
+
+            stream.set_nonblocking(true)?;
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            self.poll.register(&stream, token, Interest::READABLE)?;
+            self.connections.insert(
+                token,
+                Connection {
+                    stream,
+                    input: Vec::new(),
+                    output: Vec::new(),
+                    written: 0,
+                    state: State::Reading,
+                    close_after_write: false,
+                    read_closed: false,
+                    last_active: Instant::now(),
+                },
+            );
+
+            let n = self.acceptor.accepted.fetch_add(1, Ordering::SeqCst) + 1;
+            if self.acceptor.max_requests > 0 && n >= self.acceptor.max_requests {
+                self.acceptor.stop();
+                return Ok(());
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(conn) = self.connections.remove(&token) {
+            let _ = self.poll.deregister(&conn.stream);
+        }
+    }
+
+    /// Drop connections that have been waiting for a request, or for the
+    /// client to take some of its response, for longer than the keep-alive
+    /// timeout
+    fn close_idle(&mut self) {
+        let timeout = self.shared.settings().keep_alive_timeout;
+        let idle: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.state != State::Processing && conn.last_active.elapsed() >= timeout)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            self.close(token);
+        }
+    }
+
+    /// On shutdown, drop every connection that is not in the middle of a
+    /// request
+    fn close_idle_now(&mut self) {
+        let idle: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.state == State::Reading && conn.input.is_empty())
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            self.close(token);
+        }
+    }
+
+    fn ready(&mut self, token: Token, readable: bool, writable: bool, closed: bool) {
+        let Some(conn) = self.connections.get_mut(&token) else { return };
+        match conn.state {
+            State::Reading if readable || closed => {
+                if read_available(conn, self.shared.settings().max_body_size) {
+                    self.parse(token);
+                } else {
+                    self.close(token);
+                }
+            }
+            State::Writing if writable => self.write(token),
+            // the client is gone both ways, so a reply has nowhere to go; that
+            // of a worker is dropped later
+            State::Processing | State::Writing if closed => self.close(token),
+            _ => {}
+        }
+    }
+
+    /// Take the next complete request off the input buffer and run it.
+    /// Requests over the rate limit are answered right here instead of
+    /// queueing a job, and the next one is looked at. Once no complete
+    /// request is left of what a client that shut down its side sent, the
+    /// connection is closed
+    fn parse(&mut self, token: Token) {
+        loop {
+            let Some(conn) = self.connections.get_mut(&token) else { return };
+            if conn.input.is_empty() {
+                break;
+            }
+            let settings = self.shared.settings();
+            let mut rest = &conn.input[..];
+            let mut request = match Request::read_limited(&mut rest, settings.max_body_size) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                // not all of it has arrived yet
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => {
+                    let response = reject(&e).unwrap_or_else(|| Response::new(400, "BAD REQUEST"));
+                    let mut bytes = Vec::new();
+                    let _ = response.write_to(&mut bytes);
+                    self.send(token, bytes, false);
+                    return;
+                }
+            };
+            request.set_client(&conn.stream);
+            let consumed = conn.input.len() - rest.len();
+            conn.input.drain(..consumed);
+            match settings.router(&request).throttle(&request) {
+                Some(response) => {
+                    let (response, keep_alive) = finish(&request, response, self.shared);
+                    let mut bytes = Vec::new();
+                    let _ = response.write_to(&mut bytes);
+                    if !self.send(token, bytes, keep_alive) {
+                        return;
+                    }
+                }
+                None => return self.run(token, request, settings),
+            }
+        }
+        if self.connections.get(&token).is_some_and(|conn| conn.read_closed) {
+            self.close(token);
+        }
+    }
+
+    /// Hand the request to a worker and wait for its reply
+    fn run(&mut self, token: Token, request: Request, settings: Arc<Settings>) {
+        let Some(conn) = self.connections.get_mut(&token) else { return };
+        conn.state = State::Processing;
+        if self.poll.reregister(&conn.stream, token, Interest::NONE).is_err() {
+            self.close(token);
+            return;
+        }
+
+        let (shared, sender, waker) = (Arc::clone(self.shared), self.sender.clone(), Arc::clone(&self.waker));
+        self.acceptor.pool.execute(move || {
+            let reply = match settings.dispatch(&request) {
+                Dispatch::Respond(response) => {
+                    let (response, keep_alive) = finish(&request, response, &shared);
+                    let mut bytes = Vec::new();
+                    let _ = response.write_to(&mut bytes);
+                    Reply::Response { token, bytes, keep_alive }
+                }
+                Dispatch::Upgrade(response, endpoint) => {
+                    let mut bytes = Vec::new();
+                    let _ = response.write_to(&mut bytes);
+                    Reply::Upgrade { token, bytes, endpoint }
+                }
+            };
+            if sender.send(reply).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+    }
+
+    fn collect_replies(&mut self) {
+        while let Ok(reply) = self.replies.try_recv() {
+            match reply {
+                Reply::Response { token, bytes, keep_alive } => self.respond(token, bytes, keep_alive),
+                Reply::Upgrade { token, bytes, endpoint } => self.upgrade(token, bytes, endpoint),
+            }
+        }
+    }
+
+    fn respond(&mut self, token: Token, bytes: Vec<u8>, keep_alive: bool) {
+        if self.send(token, bytes, keep_alive) {
+            self.parse(token);
+        }
+    }
+
+    /// Start sending a response. Returns true if all of it went out and the
+    /// connection is reading again
+    fn send(&mut self, token: Token, bytes: Vec<u8>, keep_alive: bool) -> bool {
+        let Some(conn) = self.connections.get_mut(&token) else { return false };
+        conn.output = bytes;
+        conn.written = 0;
+        conn.state = State::Writing;
+        conn.close_after_write = !keep_alive;
+        conn.last_active = Instant::now();
+        self.flush(token)
+    }
+
+    /// Hand the connection over to a WebSocket handler. The socket goes
+    /// back to blocking mode since the handler expects a blocking stream
+    fn upgrade(&mut self, token: Token, bytes: Vec<u8>, endpoint: websocket::Endpoint) {
+        let Some(conn) = self.connections.remove(&token) else { return };
+        let _ = self.poll.deregister(&conn.stream);
+        let mut stream = conn.stream;
+        let result = stream.set_nonblocking(false).and_then(|_| stream.write_all(&bytes));
+        if let Err(e) = result {
+            eprintln!("WebSocket upgrade failed: {e}");
+            return;
+        }
+        let pending = conn.input;
+        self.acceptor
+            .pool
+            .execute(move || endpoint.start(WebSocket::from_upgraded(Box::new(stream), pending)));
+    }
+
+    /// The socket can take more of the pending response. Once all of it is
+    /// out, a pipelined request may be waiting
+    fn write(&mut self, token: Token) {
+        if self.flush(token) {
+            self.parse(token);
+        }
+    }
+
+    /// Send as much of the pending response as the socket takes. Returns
+    /// true if all of it is out and the connection went back to reading
+    fn flush(&mut self, token: Token) -> bool {
+        let Some(conn) = self.connections.get_mut(&token) else { return false };
+        while conn.written < conn.output.len() {
+            match conn.stream.write(&conn.output[conn.written..]) {
+                Ok(0) => {
+                    self.close(token);
+                    return false;
+                }
+                Ok(n) => {
+                    conn.written += n;
+                    conn.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if self.poll.reregister(&conn.stream, token, Interest::WRITABLE).is_err() {
+                        self.close(token);
+                    }
+                    return false;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.close(token);
+                    return false;
+                }
+            }
+        }
+
+        if conn.close_after_write {
+            self.close(token);
+            return false;
+        }
+        conn.output = Vec::new();
+        conn.state = State::Reading;
+        conn.last_active = Instant::now();
+        if self.poll.reregister(&conn.stream, token, Interest::READABLE).is_err() {
+            self.close(token);
+            return false;
+        }
+        true
+    }
+}
+
+/// Read everything the socket has for us, and note if the peer shut down its
+/// side. Returns false if reading failed
+fn read_available(conn: &mut Connection, max_body_size: usize) -> bool {
+    // enough for the largest acceptable request; the parser rejects it then
+    let limit = MAX_HEAD_SIZE.saturating_add(max_body_size);
+    let mut buf = [0u8; 16 * 1024];
+    while conn.input.len() <= limit {
+        match conn.stream.read(&mut buf) {
+            Ok(0) => {
+                conn.read_closed = true;
+                break;
+            }
+            Ok(n) => {
+                conn.input.extend_from_slice(&buf[..n]);
+                conn.last_active = Instant::now();
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,479 @@
+//! A small template language for HTML pages:
+//!
+//! - `{{ user.name }}` inserts a value, HTML-escaped; `{{ html | raw }}`
+//!   inserts it as is
+//! - `{% if user.admin %}...{% elif user %}...{% else %}...{% endif %}`,
+//!   where a condition may start with `not`
+//! - `{% for item in items %}...{% endfor %}`, with `loop.index` (from 1),
+//!   `loop.first` and `loop.last` inside the loop
+//! - `{% include "header.html" %}` renders another template in place
+//! - `{# ... #}` is a comment
+//!
+//! Values come from any "Serialize" type. Missing values render as nothing
+//! and are false in conditions; null, false, 0, "" and empty lists and
+//! objects are false as well
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::HttpError;
+use crate::http::Response;
+
+/// Includes nested deeper than this are assumed to be a cycle
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io(String, io::Error),
+    Syntax { name: String, line: usize, message: String },
+    Render(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Io(name, e) => write!(f, "could not read template {name}: {e}"),
+            TemplateError::Syntax { name, line, message } => write!(f, "{name}:{line}: {message}"),
+            TemplateError::Render(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// A dotted path like "user.name", split at the dots
+type Key = Vec<String>;
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Value { path: Key, raw: bool },
+    If { negate: bool, path: Key, then: Vec<Node>, otherwise: Vec<Node> },
+    For { var: String, path: Key, body: Vec<Node> },
+    Include(String),
+}
+
+/// A parsed template
+#[derive(Debug)]
+pub struct Template {
+    name: String,
+    nodes: Vec<Node>,
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Value(&'a str, usize),
+    Tag(&'a str, usize),
+}
+
+/// Parsed nodes, and the tag that ended them with its argument and line
+type Block = (Vec<Node>, Option<(String, String, usize)>);
+
+struct Parser<'a> {
+    name: &'a str,
+    tokens: std::vec::IntoIter<Token<'a>>,
+}
+
+impl Parser<'_> {
+    fn error(&self, line: usize, message: impl Into<String>) -> TemplateError {
+        TemplateError::Syntax { name: self.name.to_string(), line, message: message.into() }
+    }
+
+    fn path(&self, line: usize, expr: &str) -> Result<Key, TemplateError> {
+        let path: Key = expr.split('.').map(String::from).collect();
+        let valid = |part: &String| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if path.iter().all(valid) {
+            Ok(path)
+        } else {
+            Err(self.error(line, format!("invalid name \"{expr}\"")))
+        }
+    }
+
+    /// Parse nodes up to one of the tags in "ends", which is returned along
+    /// with the rest of the tag and its line. At the top level "ends" is
+    /// empty and the input must run out instead
+    fn block(&mut self, ends: &[&str]) -> Result<Block, TemplateError> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.next() {
+            let (tag, line) = match token {
+                Token::Text(text) => {
+                    nodes.push(Node::Text(text.to_string()));
+                    continue;
+                }
+                Token::Value(expr, line) => {
+                    let (expr, raw) = match expr.split_once('|') {
+                        Some((expr, filter)) if filter.trim() == "raw" => (expr.trim(), true),
+                        Some((_, filter)) => {
+                            return Err(self.error(line, format!("unknown filter \"{}\"", filter.trim())));
+                        }
+                        None => (expr, false),
+                    };
+                    nodes.push(Node::Value { path: self.path(line, expr)?, raw });
+                    continue;
+                }
+                Token::Tag(tag, line) => (tag, line),
+            };
+
+            let (keyword, rest) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+            let rest = rest.trim();
+            if ends.contains(&keyword) {
+                return Ok((nodes, Some((keyword.to_string(), rest.to_string(), line))));
+            }
+            match keyword {
+                "if" => nodes.push(self.conditional(rest, line)?),
+                "for" => {
+                    let mut parts = rest.split_whitespace();
+                    let (var, path) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                        (Some(var), Some("in"), Some(path), None) => (var, path),
+                        _ => return Err(self.error(line, "expected \"for <name> in <value>\"")),
+                    };
+                    let (body, end) = self.block(&["endfor"])?;
+                    if end.is_none() {
+                        return Err(self.error(line, "for without endfor"));
+                    }
+                    nodes.push(Node::For { var: var.to_string(), path: self.path(line, path)?, body });
+                }
+                "include" => {
+                    let name = rest
+                        .strip_prefix('"')
+                        .and_then(|rest| rest.strip_suffix('"'))
+                        .ok_or_else(|| self.error(line, "expected a quoted template name"))?;
+                    nodes.push(Node::Include(name.to_string()));
+                }
+                _ => return Err(self.error(line, format!("unexpected tag \"{keyword}\""))),
+            }
+        }
+        Ok((nodes, None))
+    }
+
+    /// The rest of an "if" (or "elif") tag up to its "endif"
+    fn conditional(&mut self, condition: &str, line: usize) -> Result<Node, TemplateError> {
+        let (negate, expr) = match condition.strip_prefix("not ") {
+            Some(expr) => (true, expr.trim()),
+            None => (false, condition),
+        };
+        let path = self.path(line, expr)?;
+        let (then, end) = self.block(&["elif", "else", "endif"])?;
+        let otherwise = match end {
+            Some((keyword, rest, line)) if keyword == "elif" => vec![self.conditional(&rest, line)?],
+            Some((keyword, _, _)) if keyword == "else" => match self.block(&["endif"])? {
+                (nodes, Some(_)) => nodes,
+                (_, None) => return Err(self.error(line, "if without endif")),
+            },
+            Some(_) => Vec::new(),
+            None => return Err(self.error(line, "if without endif")),
+        };
+        Ok(Node::If { negate, path, then, otherwise })
+    }
+}
+
+/// Split the source into text, "{{ }}" and "{% %}"; comments are dropped
+fn tokenize<'a>(name: &str, source: &'a str) -> Result<Vec<Token<'a>>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    let mut line = 1;
+    while let Some(start) = rest.find('{') {
+        let close = match rest[start..].get(..2) {
+            Some("{{") => "}}",
+            Some("{%") => "%}",
+            Some("{#") => "#}",
+            _ => {
+                tokens.push(Token::Text(&rest[..start + 1]));
+                line += rest[..start + 1].matches('\n').count();
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+        if start > 0 {
+            tokens.push(Token::Text(&rest[..start]));
+            line += rest[..start].matches('\n').count();
+        }
+        let inner = &rest[start + 2..];
+        let end = inner.find(close).ok_or_else(|| TemplateError::Syntax {
+            name: name.to_string(),
+            line,
+            message: format!("missing \"{close}\""),
+        })?;
+        let content = inner[..end].trim();
+        match close {
+            "}}" => tokens.push(Token::Value(content, line)),
+            "%}" => tokens.push(Token::Tag(content, line)),
+            _ => {}
+        }
+        line += inner[..end].matches('\n').count();
+        rest = &inner[end + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Ok(tokens)
+}
+
+/// Replace the characters that mean something in HTML
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn is_truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Object(fields)) => !fields.is_empty(),
+    }
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// The data a template is rendered with: the context plus the variables of
+/// the loops around the current node, innermost last
+struct Scope<'a> {
+    context: &'a Value,
+    locals: Vec<(String, Value)>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        let mut value = match self.locals.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => value,
+            None => self.context.get(first)?,
+        };
+        for part in rest {
+            value = match value {
+                Value::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+                other => other.get(part)?,
+            };
+        }
+        Some(value)
+    }
+}
+
+impl Template {
+    pub fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let tokens = tokenize(name, source)?;
+        let mut parser = Parser { name, tokens: tokens.into_iter() };
+        match parser.block(&[])? {
+            (nodes, None) => Ok(Template { name: name.to_string(), nodes }),
+            (_, Some((keyword, _, line))) => Err(parser.error(line, format!("unexpected tag \"{keyword}\""))),
+        }
+    }
+
+    /// Render a template that does not include any others
+    pub fn render<T: Serialize>(&self, context: &T) -> Result<String, TemplateError> {
+        let context = serde_json::to_value(context).map_err(|e| TemplateError::Render(e.to_string()))?;
+        let mut out = String::new();
+        let mut scope = Scope { context: &context, locals: Vec::new() };
+        self.render_nodes(&self.nodes, &mut scope, &mut out, None, 0)?;
+        Ok(out)
+    }
+
+    fn render_nodes(
+        &self,
+        nodes: &[Node],
+        scope: &mut Scope,
+        out: &mut String,
+        templates: Option<&Templates>,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Value { path, raw } => {
+                    let text = scope.lookup(path).map(to_text).unwrap_or_default();
+                    if *raw {
+                        out.push_str(&text);
+                    } else {
+                        out.push_str(&escape_html(&text));
+                    }
+                }
+                Node::If { negate, path, then, otherwise } => {
+                    let branch = if is_truthy(scope.lookup(path)) != *negate { then } else { otherwise };
+                    self.render_nodes(branch, scope, out, templates, depth)?;
+                }
+                Node::For { var, path, body } => {
+                    let items = match scope.lookup(path) {
+                        Some(Value::Array(items)) => items.clone(),
+                        Some(Value::Object(fields)) => fields.values().cloned().collect(),
+                        _ => Vec::new(),
+                    };
+                    for (i, item) in items.iter().enumerate() {
+                        let info = serde_json::json!({
+                            "index": i + 1,
+                            "first": i == 0,
+                            "last": i + 1 == items.len(),
+                        });
+                        scope.locals.push((String::from("loop"), info));
+                        scope.locals.push((var.clone(), item.clone()));
+                        let result = self.render_nodes(body, scope, out, templates, depth);
+                        scope.locals.truncate(scope.locals.len() - 2);
+                        result?;
+                    }
+                }
+                Node::Include(name) => {
+                    let templates = templates.ok_or_else(|| {
+                        TemplateError::Render(format!("{}: nowhere to include {name} from", self.name))
+                    })?;
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(TemplateError::Render(format!("{}: includes nested too deeply", self.name)));
+                    }
+                    let included = templates.get(name)?;
+                    included.render_nodes(&included.nodes, scope, out, Some(templates), depth + 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Cached {
+    template: Arc<Template>,
+    modified: Option<SystemTime>,
+}
+
+/// Templates loaded from a directory on first use and cached. With hot
+/// reload on, a template whose file has changed since it was cached is
+/// parsed again, so pages can be edited without restarting the server
+pub struct Templates {
+    dir: PathBuf,
+    hot_reload: bool,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+impl Templates {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Templates { dir: dir.into(), hot_reload: false, cache: Mutex::new(HashMap::new()) }
+    }
+
+    /// Check the files for changes on every render; meant for development
+    pub fn hot_reload(mut self, enabled: bool) -> Self {
+        self.hot_reload = enabled;
+        self
+    }
+
+    /// The template at "name", relative to the templates directory
+    pub fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let relative = Path::new(name);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(TemplateError::Render(format!("invalid template name {name}")));
+        }
+        let path = self.dir.join(relative);
+
+        let modified = || fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+        if let Some(cached) = self.cache.lock().unwrap().get(name) {
+            if !self.hot_reload || cached.modified == modified() {
+                return Ok(Arc::clone(&cached.template));
+            }
+        }
+
+        let modified = modified();
+        let source = fs::read_to_string(&path).map_err(|e| TemplateError::Io(name.to_string(), e))?;
+        let template = Arc::new(Template::parse(name, &source)?);
+        let cached = Cached { template: Arc::clone(&template), modified };
+        self.cache.lock().unwrap().insert(name.to_string(), cached);
+        Ok(template)
+    }
+
+    pub fn render<T: Serialize>(&self, name: &str, context: &T) -> Result<String, TemplateError> {
+        let template = self.get(name)?;
+        let context = serde_json::to_value(context).map_err(|e| TemplateError::Render(e.to_string()))?;
+        let mut out = String::new();
+        let mut scope = Scope { context: &context, locals: Vec::new() };
+        template.render_nodes(&template.nodes, &mut scope, &mut out, Some(self), 0)?;
+        Ok(out)
+    }
+
+    /// Respond with a rendered page; a page that fails to render is an
+    /// internal error
+    pub fn page<T: Serialize>(
+        &self,
+        status: u16,
+        reason: &str,
+        name: &str,
+        context: &T,
+    ) -> Result<Response, HttpError> {
+        let html = self.render(name, context)?;
+        Ok(Response::new(status, reason).with_header("Content-Type", "text/html; charset=utf-8").with_body(html))
+    }
+}
+
+impl fmt::Debug for Templates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Templates")
+            .field("dir", &self.dir)
+            .field("hot_reload", &self.hot_reload)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, context: Value) -> String {
+        Template::parse("test", source).unwrap().render(&context).unwrap()
+    }
+
+    #[test]
+    fn values_are_escaped() {
+        let context = json!({"user": {"name": "<Ann & \"Bob\">"}, "html": "<b>hi</b>", "n": 3});
+        assert_eq!(
+            render("{{ user.name }} {{ html | raw }} {{n}}{{ missing }}", context),
+            "&lt;Ann &amp; &quot;Bob&quot;&gt; <b>hi</b> 3"
+        );
+        assert_eq!(render("{ not a tag } {}", json!({})), "{ not a tag } {}");
+    }
+
+    #[test]
+    fn conditionals_and_loops() {
+        let source = "{% for item in items %}{{ loop.index }}:{{ item.name }}\
+                      {% if item.done %}+{% elif item.started %}~{% else %}-{% endif %}\
+                      {% if not loop.last %}, {% endif %}{% endfor %}{# the end #}";
+        let context = json!({"items": [
+            {"name": "a", "done": true},
+            {"name": "b", "started": 1},
+            {"name": "c", "done": false},
+        ]});
+        assert_eq!(render(source, context), "1:a+, 2:b~, 3:c-");
+        assert_eq!(render("{% for x in nothing %}x{% endfor %}", json!({})), "");
+    }
+
+    #[test]
+    fn syntax_errors_have_a_line() {
+        let err = Template::parse("page.html", "<p>\n{% if x %}\n{{ y }}").unwrap_err();
+        assert_eq!(err.to_string(), "page.html:2: if without endif");
+        let err = Template::parse("page.html", "\n\n{{ y").unwrap_err();
+        assert_eq!(err.to_string(), "page.html:3: missing \"}}\"");
+        assert!(Template::parse("page.html", "{% endfor %}").is_err());
+        assert!(Template::parse("page.html", "{{ a b }}").is_err());
+    }
+}
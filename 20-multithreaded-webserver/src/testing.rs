@@ -0,0 +1,53 @@
+//! Helpers for running the server in-process, e.g. from integration tests.
+//! Listeners are bound to ephemeral ports so that tests can run in parallel
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::server::{Server, ServerConfig, ServerHandle};
+
+/// Start a server for "config" on 127.0.0.1 with the OS picking the ports,
+/// and return the handle that knows the bound addresses
+///
+/// # Panic
+///
+/// This function will panic if the server cannot be bound
+pub fn spawn_test_server(mut config: ServerConfig) -> ServerHandle {
+    config.addr = String::from("127.0.0.1:0");
+    if let Some(tls) = config.tls.as_mut() {
+        tls.addr = String::from("127.0.0.1:0");
+    }
+    Server::bind(config).expect("failed to bind test server").spawn()
+}
+
+/// A fresh directory for one test, e.g. for the files a server is to serve.
+/// It is removed again when dropped, also when the test fails
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Create an empty directory named after "name" and this process in the
+    /// system's temporary directory, replacing one left over from before
+    ///
+    /// # Panic
+    ///
+    /// This function will panic if the directory cannot be created
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("webserver-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("failed to create temporary directory");
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -29,7 +29,7 @@ impl ThreadPool {
         }
         println!("Spawned {n} workers");
 
-        return ThreadPool {
+        ThreadPool {
             workers,
             sender: Some(sender),
         }
@@ -84,11 +84,11 @@ impl Worker {
                     },
                     Err(_) => {
                         println!("Channel closed. Worker {id} exiting");
-                        return ();
+                        return;
                     },
                 }
             }
         });
-        return Worker{ id, handle: Some(handle) };
+        Worker{ id, handle: Some(handle) }
     }
 }
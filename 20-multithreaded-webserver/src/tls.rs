@@ -0,0 +1,90 @@
+//! HTTPS support built on rustls, which is written in pure Rust and does not
+//! need a system OpenSSL. Certificates and private keys are read from PEM
+//! files when the server starts
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConnection, StreamOwned};
+
+use crate::http::Stream;
+
+/// Where the HTTPS listener binds and which certificate it presents
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub addr: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// If set, the plain HTTP listener answers every request with a redirect
+    /// to the same path on the HTTPS listener instead of serving it
+    pub redirect_http: bool,
+}
+
+impl TlsConfig {
+    /// Read the certificate chain and the private key from their PEM files
+    /// and build the rustls configuration shared by every TLS connection
+    pub fn load(&self) -> Result<Arc<rustls::ServerConfig>, Box<dyn Error>> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .map_err(|e| format!("{}: {e}", self.cert_path.display()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{}: {e}", self.cert_path.display()))?;
+        if certs.is_empty() {
+            return Err(format!("{}: no certificate found", self.cert_path.display()).into());
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| format!("{}: {e}", self.key_path.display()))?;
+
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// A TCP stream wrapped in a server-side TLS session. The handshake happens
+/// lazily on the first read, so it runs on the worker that serves the
+/// connection rather than on the accepting thread
+pub struct TlsStream {
+    inner: StreamOwned<ServerConnection, TcpStream>,
+}
+
+impl TlsStream {
+    pub fn new(config: Arc<rustls::ServerConfig>, sock: TcpStream) -> io::Result<Self> {
+        let conn = ServerConnection::new(config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(TlsStream { inner: StreamOwned::new(conn, sock) })
+    }
+
+    /// Tell the client that no more data will be sent so that it can tell a
+    /// complete response from a truncated one
+    pub fn close(&mut self) -> io::Result<()> {
+        self.inner.conn.send_close_notify();
+        self.inner.flush()
+    }
+}
+
+impl Stream for TlsStream {
+    fn socket(&self) -> &TcpStream {
+        self.inner.get_ref()
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,85 @@
+//! Name-based virtual hosting: one server, several sites, told apart by the
+//! Host header of each request
+use crate::http::Request;
+use crate::router::Router;
+
+/// A site served under one or more host names. A name may start with "*."
+/// to match every subdomain of the rest, e.g. "*.example.com" matches
+/// "www.example.com" and "a.b.example.com" but not "example.com"
+#[derive(Clone, Debug)]
+pub struct VirtualHost {
+    pub names: Vec<String>,
+    pub router: Router,
+}
+
+impl VirtualHost {
+    pub fn new(names: &[&str], router: Router) -> Self {
+        VirtualHost { names: names.iter().map(|name| name.to_ascii_lowercase()).collect(), router }
+    }
+}
+
+/// The host name of a Host header: lowercased, without the port and
+/// without a trailing dot
+pub fn host_name(host: &str) -> String {
+    let name = if host.starts_with('[') {
+        // an IPv6 literal, e.g. "[::1]:8080"
+        host.split_once(']').map_or(host, |(name, _)| name).trim_start_matches('[')
+    } else {
+        host.split_once(':').map_or(host, |(name, _)| name)
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The router for the site the request is addressed to: an exact name
+/// wins over a wildcard, and a longer wildcard over a shorter one. Requests
+/// for unknown hosts, or without a Host header, go to "default"
+pub fn select<'a>(hosts: &'a [VirtualHost], default: &'a Router, request: &Request) -> &'a Router {
+    let Some(name) = request.header("Host").map(host_name) else { return default };
+    let exact = hosts.iter().find(|host| host.names.contains(&name));
+    if let Some(host) = exact {
+        return &host.router;
+    }
+    hosts
+        .iter()
+        .flat_map(|host| host.names.iter().map(move |pattern| (pattern, host)))
+        .filter_map(|(pattern, host)| {
+            let suffix = pattern.strip_prefix("*.")?;
+            let subdomain = name.strip_suffix(suffix)?.strip_suffix('.')?;
+            (!subdomain.is_empty()).then_some((suffix.len(), host))
+        })
+        .max_by_key(|(len, _)| *len)
+        .map_or(default, |(_, host)| &host.router)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Response;
+
+    fn site(name: &'static str) -> Router {
+        Router::new().get("/", move |_| Ok(Response::new(200, "OK").with_body(name)))
+    }
+
+    fn pick(hosts: &[VirtualHost], default: &Router, host: &str) -> String {
+        let request = Request::new("GET", "/").with_header("Host", host);
+        select(hosts, default, &request).handle(&request).text()
+    }
+
+    #[test]
+    fn exact_wildcard_and_default() {
+        let hosts = [
+            VirtualHost::new(&["docs.example.com", "*.docs.example.com"], site("docs")),
+            VirtualHost::new(&["*.example.com"], site("any")),
+            VirtualHost::new(&["api.docs.example.com"], site("api")),
+        ];
+        let default = site("default");
+        assert_eq!(pick(&hosts, &default, "docs.example.com:8080"), "docs");
+        assert_eq!(pick(&hosts, &default, "v2.docs.example.com"), "docs");
+        assert_eq!(pick(&hosts, &default, "API.docs.example.com."), "api");
+        assert_eq!(pick(&hosts, &default, "www.example.com"), "any");
+        assert_eq!(pick(&hosts, &default, "example.com"), "default");
+        assert_eq!(pick(&hosts, &default, "[::1]:8080"), "default");
+        let no_host = Request::new("GET", "/");
+        assert_eq!(select(&hosts, &default, &no_host).handle(&no_host).text(), "default");
+    }
+}
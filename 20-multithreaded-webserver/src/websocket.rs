@@ -0,0 +1,481 @@
+//! WebSockets (RFC 6455): the opening handshake, frame encoding and
+//! decoding, ping/pong and the closing handshake. A WebSocket route hands
+//! the upgraded connection to its handler, either on the worker that read
+//! the handshake or on a thread of its own so that long-lived sockets do not
+//! take workers away from ordinary requests
+use std::fmt;
+use std::io::{self, prelude::*, BufReader, Cursor};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::http::{Request, Response, Stream};
+
+/// Appended to the client's key before hashing, as fixed by the RFC
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xA;
+
+/// Status codes sent in close frames
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+/// The value of Sec-WebSocket-Accept for a given Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.digest().bytes())
+}
+
+fn header_has_token(request: &Request, name: &str, token: &str) -> bool {
+    request
+        .header(name)
+        .is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Check that the request is a valid opening handshake and build the
+/// "101 Switching Protocols" response, or the error to send instead
+pub fn handshake(request: &Request) -> Result<Response, Response> {
+    if request.method != "GET"
+        || !header_has_token(request, "Upgrade", "websocket")
+        || !header_has_token(request, "Connection", "upgrade")
+    {
+        return Err(Response::new(426, "UPGRADE REQUIRED")
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Version", "13"));
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(Response::new(426, "UPGRADE REQUIRED").with_header("Sec-WebSocket-Version", "13"));
+    }
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16) => key,
+        _ => return Err(Response::new(400, "BAD REQUEST")),
+    };
+    Ok(Response::new(101, "SWITCHING PROTOCOLS")
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key)))
+}
+
+/// One frame on the wire, with the payload already unmasked
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read one frame, refusing payloads longer than "max_payload". Returns the
+/// frame and whether the peer masked it
+pub fn read_frame<R: Read>(reader: &mut R, max_payload: usize) -> io::Result<(Frame, bool)> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    if head[0] & 0x70 != 0 {
+        return Err(protocol_error("reserved bits set"));
+    }
+    let opcode = head[0] & 0x0F;
+    let masked = head[1] & 0x80 != 0;
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if opcode >= OP_CLOSE && (!fin || len > 125) {
+        return Err(protocol_error("malformed control frame"));
+    }
+    if len > max_payload as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, MessageTooBig));
+    }
+
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok((Frame { fin, opcode, payload }, masked))
+}
+
+/// Write one frame. Clients must mask what they send, servers must not
+pub fn write_frame<W: Write>(writer: &mut W, frame: &Frame, mask: Option<[u8; 4]>) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(frame.payload.len() + 14);
+    buf.push(if frame.fin { 0x80 } else { 0 } | frame.opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match frame.payload.len() {
+        len if len < 126 => buf.push(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            buf.push(mask_bit | 126);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            buf.push(mask_bit | 127);
+            buf.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            buf.extend_from_slice(&mask);
+            buf.extend(frame.payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        }
+        None => buf.extend_from_slice(&frame.payload),
+    }
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+/// The error wrapped in the io::Error returned for a message larger than
+/// the socket's limit
+#[derive(Debug)]
+pub struct MessageTooBig;
+
+impl fmt::Display for MessageTooBig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "message too big")
+    }
+}
+
+impl std::error::Error for MessageTooBig {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Pings are answered automatically; they are still reported so that
+    /// handlers can see the peer is alive
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The peer started or completed the closing handshake
+    Close(Option<(u16, String)>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Server,
+    Client,
+}
+
+/// Cheap masking keys for the client side. The masks only need to vary from
+/// frame to frame, they protect intermediaries rather than the data
+fn next_mask() -> [u8; 4] {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    let mut x = STATE.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed) ^ seed;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    (x as u32).to_be_bytes()
+}
+
+/// An open WebSocket connection
+pub struct WebSocket {
+    stream: Box<dyn Stream>,
+    /// Bytes read from the stream while parsing the handshake that already
+    /// belong to the first frames
+    pending: Cursor<Vec<u8>>,
+    role: Role,
+    max_message_size: usize,
+    /// The opcode and data so far of a fragmented message, kept across the
+    /// control frames that may come between its fragments
+    fragments: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl WebSocket {
+    /// Take over a connection after the 101 response has been sent
+    pub fn from_upgraded(stream: Box<dyn Stream>, pending: Vec<u8>) -> Self {
+        WebSocket::new(stream, pending, Role::Server)
+    }
+
+    fn new(stream: Box<dyn Stream>, pending: Vec<u8>, role: Role) -> Self {
+        WebSocket {
+            stream,
+            pending: Cursor::new(pending),
+            role,
+            max_message_size: 1024 * 1024,
+            fragments: None,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// Messages (and each of their fragments) larger than this are refused
+    /// and the connection is closed with status 1009
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max;
+    }
+
+    /// Make "recv" fail with a timeout if nothing arrives for this long
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.socket().set_read_timeout(timeout)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.socket().peer_addr()
+    }
+
+    /// Whether both sides have sent a close frame
+    pub fn is_closed(&self) -> bool {
+        self.close_sent && self.close_received
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: Vec<u8>) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "close frame already sent"));
+        }
+        let mask = match self.role {
+            Role::Client => Some(next_mask()),
+            Role::Server => None,
+        };
+        let frame = Frame { fin: true, opcode, payload };
+        write_frame(&mut self.stream, &frame, mask)?;
+        if opcode == OP_CLOSE {
+            self.close_sent = true;
+        }
+        Ok(())
+    }
+
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.send_frame(OP_TEXT, text.into_bytes()),
+            Message::Binary(data) => self.send_frame(OP_BINARY, data),
+            Message::Ping(data) => self.send_frame(OP_PING, data),
+            Message::Pong(data) => self.send_frame(OP_PONG, data),
+            Message::Close(None) => self.send_frame(OP_CLOSE, Vec::new()),
+            Message::Close(Some((code, reason))) => self.close(code, &reason),
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send_frame(OP_TEXT, text.as_bytes().to_vec())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_frame(OP_BINARY, data.to_vec())
+    }
+
+    pub fn ping(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_frame(OP_PING, data.to_vec())
+    }
+
+    /// Start the closing handshake. Keep calling "recv" until it returns
+    /// "Message::Close" to let the peer finish it
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        payload.truncate(125);
+        self.send_frame(OP_CLOSE, payload)
+    }
+
+    /// Answer a broken peer with a close frame and report the error
+    fn fail(&mut self, code: u16, e: io::Error) -> io::Error {
+        if !self.close_sent {
+            let _ = self.close(code, "");
+        }
+        e
+    }
+
+    /// Wait for the next message. Fragmented messages are reassembled, pings
+    /// are answered and an incoming close frame is echoed. A ping or pong
+    /// between the fragments of a message is returned, and the next call
+    /// goes on with the message
+    pub fn recv(&mut self) -> io::Result<Message> {
+        if self.close_received {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "connection closed"));
+        }
+        loop {
+            let result = {
+                let mut reader = (&mut self.pending).chain(&mut self.stream);
+                read_frame(&mut reader, self.max_message_size)
+            };
+            let (frame, masked) = match result {
+                Ok(frame) => frame,
+                Err(e) if e.get_ref().is_some_and(|inner| inner.is::<MessageTooBig>()) => {
+                    return Err(self.fail(CLOSE_TOO_BIG, e));
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, e));
+                }
+                Err(e) => return Err(e),
+            };
+            if masked != (self.role == Role::Server) {
+                return Err(self.fail(CLOSE_PROTOCOL_ERROR, protocol_error("wrong masking")));
+            }
+
+            match frame.opcode {
+                OP_PING => {
+                    if !self.close_sent {
+                        self.send_frame(OP_PONG, frame.payload.clone())?;
+                    }
+                    return Ok(Message::Ping(frame.payload));
+                }
+                OP_PONG => return Ok(Message::Pong(frame.payload)),
+                OP_CLOSE => {
+                    self.close_received = true;
+                    let status = match frame.payload.len() {
+                        0 => None,
+                        1 => return Err(self.fail(CLOSE_PROTOCOL_ERROR, protocol_error("bad close frame"))),
+                        _ => {
+                            let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                            let reason = String::from_utf8_lossy(&frame.payload[2..]).into_owned();
+                            Some((code, reason))
+                        }
+                    };
+                    if !self.close_sent {
+                        let code = status.as_ref().map_or(CLOSE_NORMAL, |(code, _)| *code);
+                        self.close(code, "")?;
+                    }
+                    return Ok(Message::Close(status));
+                }
+                OP_TEXT | OP_BINARY if self.fragments.is_none() => {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+                OP_CONTINUATION if self.fragments.is_some() => {
+                    let (_, data) = self.fragments.as_mut().unwrap();
+                    if data.len() + frame.payload.len() > self.max_message_size {
+                        let e = io::Error::new(io::ErrorKind::InvalidData, MessageTooBig);
+                        return Err(self.fail(CLOSE_TOO_BIG, e));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                }
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, protocol_error("unexpected opcode"))),
+            }
+
+            if frame.fin {
+                let (opcode, data) = self.fragments.take().unwrap();
+                if opcode == OP_BINARY {
+                    return Ok(Message::Binary(data));
+                }
+                return match String::from_utf8(data) {
+                    Ok(text) => Ok(Message::Text(text)),
+                    Err(_) => Err(self.fail(CLOSE_INVALID_DATA, protocol_error("text is not UTF-8"))),
+                };
+            }
+        }
+    }
+}
+
+/// Where the handler of an upgraded connection runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Runner {
+    /// On the pool worker that read the handshake. The worker is busy until
+    /// the handler returns, so keep these handlers short
+    Worker,
+    /// On a new thread, releasing the worker immediately
+    DedicatedThread,
+}
+
+/// Type declaration for "a function that takes over a WebSocket connection"
+pub type Handler = Arc<dyn Fn(WebSocket) + Send + Sync>;
+
+/// What a router stores for a WebSocket route
+#[derive(Clone)]
+pub struct Endpoint {
+    pub handler: Handler,
+    pub runner: Runner,
+}
+
+impl Endpoint {
+    /// Run the handler on the upgraded connection
+    pub fn start(&self, ws: WebSocket) {
+        let handler = Arc::clone(&self.handler);
+        match self.runner {
+            Runner::Worker => handler(ws),
+            Runner::DedicatedThread => {
+                thread::spawn(move || handler(ws));
+            }
+        }
+    }
+}
+
+/// Open a client connection to a WebSocket route, e.g. to test handlers
+pub fn connect(addr: SocketAddr, path: &str) -> io::Result<WebSocket> {
+    let stream = TcpStream::connect(addr)?;
+    let mut nonce = next_mask().to_vec();
+    nonce.extend(next_mask());
+    nonce.extend(next_mask());
+    nonce.extend(next_mask());
+    let key = BASE64.encode(&nonce);
+
+    let mut reader = BufReader::new(stream);
+    Request::new("GET", path)
+        .with_header("Host", &addr.to_string())
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Key", &key)
+        .with_header("Sec-WebSocket-Version", "13")
+        .write_to(reader.get_mut())?;
+
+    let response = Response::read_head(&mut reader)?;
+    if response.status != 101 || response.header("Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        let msg = format!("handshake refused: {} {}", response.status, response.reason);
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, msg));
+    }
+    let pending = reader.buffer().to_vec();
+    Ok(WebSocket::new(Box::new(reader.into_inner()), pending, Role::Client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc_accept_key() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let frame = Frame { fin: len % 2 == 0, opcode: OP_BINARY, payload: vec![7; len] };
+            for mask in [None, Some([1, 2, 3, 4])] {
+                let mut wire = Vec::new();
+                write_frame(&mut wire, &frame, mask).unwrap();
+                let (parsed, masked) = read_frame(&mut &wire[..], usize::MAX).unwrap();
+                assert_eq!(parsed, frame);
+                assert_eq!(masked, mask.is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn rfc_masked_hello() {
+        let wire = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let (frame, masked) = read_frame(&mut &wire[..], 125).unwrap();
+        assert!(masked && frame.fin);
+        assert_eq!(frame.payload, b"Hello");
+        assert!(read_frame(&mut &wire[..], 4).is_err());
+    }
+}
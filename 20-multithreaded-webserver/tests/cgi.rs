@@ -0,0 +1,156 @@
+#![cfg(unix)]
+/** End-to-end tests for CGI routes, running small shell scripts
+ */
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use webserver::cgi::Cgi;
+use webserver::client;
+use webserver::http::Request;
+use webserver::router::Router;
+use webserver::server::{ServerConfig, ServerHandle};
+use webserver::testing::{spawn_test_server, TempDir};
+
+const ECHO: &str = r#"#!/bin/sh
+printf 'Content-Type: text/plain\r\nX-Script: echo\r\n\r\n'
+echo "$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING"
+echo "from $REMOTE_ADDR to $SERVER_NAME:$SERVER_PORT via $GATEWAY_INTERFACE"
+echo "agent $HTTP_USER_AGENT type $CONTENT_TYPE length $CONTENT_LENGTH"
+cat
+"#;
+
+const CREATED: &str = "#!/bin/sh\nprintf 'Status: 201 Created\\nContent-Type: text/plain\\n\\nmade it'\n";
+
+const BROKEN: &str = "#!/bin/sh\necho 'no headers here'\n";
+
+/// Records its pid, then takes far too long
+const SLOW: &str = "#!/bin/sh\necho $$ > slow.pid\nsleep 30\necho 'Content-Type: text/plain'\necho\n";
+
+/// Answers at once, but leaves a child behind that holds on to its stdout
+const BACKGROUND: &str = "#!/bin/sh\nsleep 30 &\necho $! > child.pid\nprintf 'Content-Type: text/plain\\n\\nbye'\n";
+
+fn script_dir(test: &str) -> TempDir {
+    let dir = TempDir::new(&format!("cgi-{test}"));
+    let scripts = [
+        ("echo.sh", ECHO),
+        ("created.sh", CREATED),
+        ("broken.sh", BROKEN),
+        ("slow.sh", SLOW),
+        ("background.sh", BACKGROUND),
+    ];
+    for (name, source) in scripts {
+        let path = dir.path().join(name);
+        fs::write(&path, source).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    dir
+}
+
+fn spawn(dir: &Path, timeout: Duration) -> ServerHandle {
+    let router = Router::new()
+        .cgi("/cgi/echo", Cgi::new(dir.join("echo.sh")))
+        .cgi("/cgi/created", Cgi::new(dir.join("created.sh")))
+        .cgi("/cgi/broken", Cgi::new(dir.join("broken.sh")))
+        .cgi("/cgi/slow", Cgi::new(dir.join("slow.sh")).timeout(timeout))
+        .cgi("/cgi/background", Cgi::new(dir.join("background.sh")).timeout(timeout))
+        .cgi("/cgi/missing", Cgi::new(dir.join("missing.sh")));
+    spawn_test_server(ServerConfig { router, ..ServerConfig::default() })
+}
+
+#[test]
+fn request_in_environment_and_stdin() {
+    let dir = script_dir("echo");
+    let server = spawn(dir.path(), Duration::from_secs(5));
+    let request = Request::new("POST", "/cgi/echo/some/path?x=1")
+        .with_header("User-Agent", "tests")
+        .with_header("Content-Type", "text/plain")
+        .with_body("the body");
+    let resp = client::send(server.addr(), request).unwrap();
+
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("X-Script"), Some("echo"));
+    let port = server.addr().port();
+    assert_eq!(
+        resp.text(),
+        format!(
+            "POST /cgi/echo /some/path x=1\n\
+             from 127.0.0.1 to 127.0.0.1:{port} via CGI/1.1\n\
+             agent tests type text/plain length 8\n\
+             the body"
+        )
+    );
+}
+
+#[test]
+fn status_and_errors() {
+    let dir = script_dir("status");
+    let server = spawn(dir.path(), Duration::from_secs(5));
+    let resp = client::get(server.addr(), "/cgi/created").unwrap();
+    assert_eq!((resp.status, resp.text().as_str()), (201, "made it"));
+    assert_eq!(client::get(server.addr(), "/cgi/broken").unwrap().status, 502);
+    assert_eq!(client::get(server.addr(), "/cgi/missing").unwrap().status, 500);
+}
+
+#[test]
+fn slow_script_times_out() {
+    let dir = script_dir("timeout");
+    let server = spawn(dir.path(), Duration::from_millis(300));
+    let start = Instant::now();
+    assert_eq!(client::get(server.addr(), "/cgi/slow").unwrap().status, 504);
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn script_killed_when_client_leaves() {
+    let dir = script_dir("disconnect");
+    let server = spawn(dir.path(), Duration::from_secs(30));
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.write_all(b"GET /cgi/slow HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    let pid_file = dir.path().join("slow.pid");
+    let pid = loop {
+        if let Some(pid) = fs::read_to_string(&pid_file).ok().filter(|pid| pid.ends_with('\n')) {
+            break pid.trim().to_string();
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(Path::new(&format!("/proc/{pid}")).exists());
+    drop(stream);
+
+    let start = Instant::now();
+    while Path::new(&format!("/proc/{pid}")).exists() {
+        assert!(start.elapsed() < Duration::from_secs(5), "script still running");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Whether the process is alive; one that was killed may linger as a zombie
+/// until whoever inherited it reaps it
+#[cfg(target_os = "linux")]
+fn is_running(pid: &str) -> bool {
+    fs::read_to_string(format!("/proc/{pid}/stat"))
+        .is_ok_and(|stat| stat.rsplit_once(')').is_some_and(|(_, rest)| !rest.trim_start().starts_with('Z')))
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn timeout_covers_what_the_script_left_running() {
+    let dir = script_dir("background");
+    let server = spawn(dir.path(), Duration::from_millis(300));
+    let start = Instant::now();
+    assert_eq!(client::get(server.addr(), "/cgi/background").unwrap().status, 504);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let pid = fs::read_to_string(dir.path().join("child.pid")).unwrap();
+    let pid = pid.trim();
+    while is_running(pid) {
+        assert!(start.elapsed() < Duration::from_secs(5), "child still running");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
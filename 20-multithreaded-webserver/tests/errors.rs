@@ -0,0 +1,61 @@
+/** End-to-end tests for handlers that fail, and the error pages they get
+ */
+use std::fs;
+
+use webserver::client;
+use webserver::error::{ErrorPages, HttpError};
+use webserver::http::{Request, Response};
+use webserver::router::Router;
+use webserver::server::{ServerConfig, ServerHandle};
+use webserver::testing::{spawn_test_server, TempDir};
+
+/// A directory with a custom page for 500 only
+fn pages_dir(test: &str) -> TempDir {
+    let dir = TempDir::new(&format!("errors-{test}"));
+    fs::write(dir.path().join("500.html"), "<p>Broken, sorry. Quote {{ request_id }}.</p>").unwrap();
+    dir
+}
+
+fn spawn(pages: ErrorPages) -> ServerHandle {
+    let router = Router::new()
+        .get("/file", |_| Ok(Response::new(200, "OK").with_body(fs::read("no-such-file.txt")?)))
+        .get("/crash", |_| Err(HttpError::internal("database password rejected")))
+        .get("/down", |_| Err(HttpError::Unavailable(String::from("backend restarting"))))
+        .get("/teapot", |_| Err(HttpError::Status(418, String::from("I'M A TEAPOT"))));
+    spawn_test_server(ServerConfig { router, error_pages: pages, ..ServerConfig::default() })
+}
+
+#[test]
+fn errors_map_to_statuses() {
+    let dir = pages_dir("statuses");
+    let server = spawn(ErrorPages::new(dir.path()));
+    let status = |path| client::get(server.addr(), path).unwrap().status;
+    assert_eq!(status("/file"), 404);
+    assert_eq!(status("/crash"), 500);
+    assert_eq!(status("/down"), 503);
+    assert_eq!(status("/teapot"), 418);
+    assert_eq!(status("/nowhere"), 404);
+}
+
+#[test]
+fn custom_page_with_request_id() {
+    let dir = pages_dir("custom");
+    let server = spawn(ErrorPages::new(dir.path()));
+    let resp = client::get(server.addr(), "/crash").unwrap();
+    let id = resp.header("X-Request-Id").unwrap();
+    assert_eq!(resp.text(), format!("<p>Broken, sorry. Quote {id}.</p>"));
+
+    // a status without a page of its own gets the built-in one
+    let resp = client::get(server.addr(), "/down").unwrap();
+    assert!(resp.text().contains("503 SERVICE UNAVAILABLE"));
+    assert!(!resp.text().contains("backend restarting"));
+}
+
+#[test]
+fn request_id_from_proxy_is_kept() {
+    let server = spawn(ErrorPages::new("no-such-dir"));
+    let request = Request::new("GET", "/crash").with_header("X-Request-Id", "edge-42");
+    let resp = client::send(server.addr(), request).unwrap();
+    assert_eq!(resp.header("X-Request-Id"), Some("edge-42"));
+    assert!(resp.text().contains("Request id: edge-42"));
+}
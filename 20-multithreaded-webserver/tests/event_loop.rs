@@ -0,0 +1,171 @@
+#![cfg(target_os = "linux")]
+/** End-to-end tests for the epoll event-loop server mode
+ */
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use webserver::client::{self, Client};
+use webserver::http::{Request, Response};
+use webserver::router::Router;
+use webserver::server::{ServerConfig, ServerHandle, ServerMode};
+use webserver::testing::spawn_test_server;
+use webserver::websocket::{self, Message, Runner};
+
+const BIG: usize = 64 << 20;
+
+fn spawn(keep_alive_timeout: Duration) -> ServerHandle {
+    let router = Router::default()
+        .get("/big", |_| Ok(Response::new(200, "OK").with_body(vec![b'x'; BIG])))
+        .post("/echo", |request| Ok(Response::new(200, "OK").with_body(request.body.clone())))
+        .websocket("/ws", Runner::Worker, |mut ws| {
+            while let Ok(Message::Text(text)) = ws.recv() {
+                ws.send_text(&text).unwrap();
+            }
+        });
+    let config = ServerConfig {
+        mode: ServerMode::EventLoop,
+        workers: 4,
+        keep_alive_timeout,
+        router,
+        ..ServerConfig::default()
+    };
+    spawn_test_server(config)
+}
+
+#[test]
+fn routes_and_keep_alive() {
+    let server = spawn(Duration::from_secs(5));
+    assert_eq!(client::get(server.addr(), "/nowhere").unwrap().status, 404);
+
+    let mut client = Client::connect(server.addr()).unwrap();
+    for _ in 0..3 {
+        let resp = client.get("/").unwrap();
+        assert_eq!(resp.status, 200);
+        assert!(resp.text().contains("Hi from Rust"));
+    }
+}
+
+#[test]
+fn thousands_of_idle_connections_with_four_workers() {
+    let server = spawn(Duration::from_secs(30));
+    let mut clients = Vec::new();
+    for _ in 0..2000 {
+        // one at a time, so that the listen backlog never overflows
+        let mut client = Client::connect(server.addr()).unwrap();
+        assert_eq!(client.get("/").unwrap().status, 200);
+        clients.push(client);
+    }
+    // every connection is still open and usable after the others were served
+    for client in clients.iter_mut().rev() {
+        assert_eq!(client.get("/").unwrap().status, 200);
+        assert!(!client.is_closed());
+    }
+}
+
+#[test]
+fn slow_handler_does_not_block_other_connections() {
+    let server = spawn(Duration::from_secs(5));
+    let addr = server.addr();
+    let slow = thread::spawn(move || client::get(addr, "/busybox").unwrap().status);
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    assert_eq!(client::get(addr, "/").unwrap().status, 200);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(slow.join().unwrap(), 200);
+}
+
+#[test]
+fn pipelined_and_fragmented_requests() {
+    let server = spawn(Duration::from_secs(5));
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    let requests = "POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nfirstPOST /echo HTTP/1.1\r\n\
+                    Content-Length: 6\r\nConnection: close\r\n\r\nsecond";
+    for chunk in requests.as_bytes().chunks(7) {
+        stream.write_all(chunk).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert_eq!(
+        resp,
+        "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst\
+         HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 6\r\n\r\nsecond"
+    );
+}
+
+#[test]
+fn head_and_post_share_the_connection() {
+    let server = spawn(Duration::from_secs(5));
+    let mut client = Client::connect(server.addr()).unwrap();
+    let head = client.send(Request::new("HEAD", "/")).unwrap();
+    assert_eq!(head.status, 200);
+    assert!(head.body.is_empty());
+    let echo = client.send(Request::new("POST", "/echo").with_body("after head")).unwrap();
+    assert_eq!(echo.text(), "after head");
+    let len = client.get("/").unwrap().body.len().to_string();
+    assert_eq!(head.header("Content-Length"), Some(len.as_str()));
+}
+
+#[test]
+fn idle_connection_times_out() {
+    let server = spawn(Duration::from_millis(100));
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(stream.read_to_end(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn half_closed_client_is_answered() {
+    let server = spawn(Duration::from_secs(5));
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+        .write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\nonePOST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\ntwo")
+        .unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert_eq!(
+        resp,
+        "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none\
+         HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\ntwo"
+    );
+}
+
+#[test]
+fn client_that_never_reads_times_out() {
+    let server = spawn(Duration::from_millis(200));
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+    // Far more than the socket buffers hold, so the server is left writing
+    thread::sleep(Duration::from_secs(1));
+
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = Vec::new();
+    // Closed with part of the response unsent, which may reset the connection
+    let _ = stream.read_to_end(&mut buf);
+    assert!(buf.len() < BIG);
+}
+
+#[test]
+fn websocket_upgrade() {
+    let server = spawn(Duration::from_secs(5));
+    let mut ws = websocket::connect(server.addr(), "/ws").unwrap();
+    ws.send_text("over epoll").unwrap();
+    assert_eq!(ws.recv().unwrap(), Message::Text(String::from("over epoll")));
+}
+
+#[test]
+fn shutdown_answers_requests_in_flight() {
+    let server = spawn(Duration::from_secs(5));
+    let addr = server.addr();
+    let slow = thread::spawn(move || client::get(addr, "/busybox"));
+    thread::sleep(Duration::from_millis(200));
+    server.shutdown();
+    assert_eq!(slow.join().unwrap().unwrap().status, 200);
+    assert!(client::get(addr, "/").is_err());
+}
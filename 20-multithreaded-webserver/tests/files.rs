@@ -0,0 +1,116 @@
+/** End-to-end tests for serving files and generated directory listings
+ */
+use std::fs;
+
+use serde_json::Value;
+use webserver::client;
+use webserver::files::FileServer;
+use webserver::http::Request;
+use webserver::router::Router;
+use webserver::server::{ServerConfig, ServerHandle};
+use webserver::testing::{spawn_test_server, TempDir};
+
+/// A fresh directory tree for one test:
+///
+/// artifacts/ (listed): build.log, release.bin, nightly/, .cache
+/// private/ (not listed): notes.txt
+/// site/: index.html
+fn tree(test: &str) -> TempDir {
+    let root = TempDir::new(&format!("files-{test}"));
+    for dir in ["artifacts/nightly", "private", "site"] {
+        fs::create_dir_all(root.path().join(dir)).unwrap();
+    }
+    fs::write(root.path().join("artifacts/build.log"), "ok\n").unwrap();
+    fs::write(root.path().join("artifacts/release.bin"), vec![0u8; 2048]).unwrap();
+    fs::write(root.path().join("artifacts/.cache"), "secret").unwrap();
+    fs::write(root.path().join("private/notes.txt"), "notes").unwrap();
+    fs::write(root.path().join("site/index.html"), "<h1>site</h1>").unwrap();
+    root
+}
+
+fn spawn(files: FileServer) -> ServerHandle {
+    let router = Router::new().files("/files", files);
+    spawn_test_server(ServerConfig { router, ..ServerConfig::default() })
+}
+
+fn names(listing: &Value) -> Vec<&str> {
+    listing["entries"].as_array().unwrap().iter().map(|entry| entry["name"].as_str().unwrap()).collect()
+}
+
+#[test]
+fn html_listing() {
+    let root = tree("html");
+    let server = spawn(FileServer::new(root.path()).list("artifacts"));
+
+    let resp = client::get(server.addr(), "/files/artifacts").unwrap();
+    assert_eq!(resp.status, 301);
+    assert_eq!(resp.header("Location"), Some("/files/artifacts/"));
+
+    let resp = client::get(server.addr(), "/files/artifacts/").unwrap();
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("Content-Type"), Some("text/html; charset=utf-8"));
+    let html = resp.text();
+    assert!(html.contains("<title>Index of /files/artifacts/</title>"));
+    assert!(html.contains(r#"<a href="nightly/">nightly/</a>"#));
+    assert!(html.contains(r#"<a href="build.log">build.log</a>"#));
+    assert!(html.contains("2.0 KiB"));
+    assert!(!html.contains(".cache"));
+
+    let resp = client::get(server.addr(), "/files/artifacts/build.log").unwrap();
+    assert_eq!(resp.header("Content-Type"), Some("text/plain; charset=utf-8"));
+    assert_eq!(resp.text(), "ok\n");
+}
+
+#[test]
+fn json_listing_and_sorting() {
+    let root = tree("json");
+    let server = spawn(FileServer::new(root.path()).list("artifacts"));
+
+    let resp = client::get(server.addr(), "/files/artifacts/?format=json").unwrap();
+    assert_eq!(resp.header("Content-Type"), Some("application/json"));
+    let listing: Value = serde_json::from_slice(&resp.body).unwrap();
+    assert_eq!(listing["path"], "/files/artifacts/");
+    assert_eq!(names(&listing), ["nightly", "build.log", "release.bin"]);
+    let release = &listing["entries"][2];
+    assert_eq!(release["size"], 2048);
+    assert_eq!(release["dir"], false);
+    assert!(release["modified"].as_str().unwrap().ends_with('Z'));
+
+    let request =
+        Request::new("GET", "/files/artifacts/?sort=size&order=desc").with_header("Accept", "application/json");
+    let listing: Value = serde_json::from_slice(&client::send(server.addr(), request).unwrap().body).unwrap();
+    assert_eq!(names(&listing), ["nightly", "release.bin", "build.log"]);
+}
+
+#[test]
+fn listings_are_opt_in() {
+    let root = tree("opt-in");
+    let server = spawn(FileServer::new(root.path()).list("artifacts"));
+    let get = |path: &str| client::get(server.addr(), path).unwrap();
+
+    assert_eq!(get("/files/private/").status, 404);
+    assert_eq!(get("/files/").status, 404);
+    assert_eq!(get("/files/private/notes.txt").text(), "notes");
+    // listing a directory covers the ones below it
+    assert_eq!(get("/files/artifacts/nightly/").status, 200);
+    assert_eq!(get("/files/site/").text(), "<h1>site</h1>");
+
+    assert_eq!(get("/files/artifacts/.cache").status, 404);
+    assert_eq!(get("/files/../Cargo.toml").status, 404);
+    assert_eq!(get("/files/artifacts/%2e%2e/private/notes.txt").status, 404);
+    let missing = get("/files/missing.txt");
+    assert_eq!(missing.status, 404);
+    assert!(missing.text().contains(&format!("Request id: {}", missing.header("X-Request-Id").unwrap())));
+}
+
+#[test]
+fn hidden_files_when_allowed() {
+    let root = tree("hidden");
+    let server = spawn(FileServer::new(root.path()).list("").show_hidden(true));
+
+    let resp = client::get(server.addr(), "/files/artifacts/?format=json").unwrap();
+    let listing: Value = serde_json::from_slice(&resp.body).unwrap();
+    assert_eq!(names(&listing), ["nightly", ".cache", "build.log", "release.bin"]);
+    assert_eq!(client::get(server.addr(), "/files/artifacts/.cache").unwrap().text(), "secret");
+    assert_eq!(client::get(server.addr(), "/files/").unwrap().status, 200);
+}
@@ -0,0 +1,75 @@
+/** End-to-end tests for form submissions and file uploads
+ */
+use webserver::client;
+use webserver::form::{self, FormLimits};
+use webserver::http::{Request, Response};
+use webserver::router::Router;
+use webserver::server::{ServerConfig, ServerHandle};
+use webserver::testing::spawn_test_server;
+
+/// Echo every field and every file back as "name=value" lines
+fn spawn() -> ServerHandle {
+    let limits = FormLimits { max_part_size: 1024, memory_threshold: 16, ..FormLimits::default() };
+    let router = Router::new().post("/upload", form::handler(limits, |_, form| {
+        let mut body = String::new();
+        for (name, value) in &form.fields {
+            body.push_str(&format!("{name}={value}\n"));
+        }
+        for file in &form.files {
+            let content = String::from_utf8_lossy(&file.bytes().unwrap()).into_owned();
+            body.push_str(&format!("{}:{}={content}\n", file.name, file.filename));
+        }
+        Ok(Response::new(200, "OK").with_body(body))
+    }));
+    spawn_test_server(ServerConfig { router, ..ServerConfig::default() })
+}
+
+fn post(server: &ServerHandle, content_type: &str, body: &str) -> Response {
+    let request = Request::new("POST", "/upload")
+        .with_header("Content-Type", content_type)
+        .with_body(body);
+    client::send(server.addr(), request).unwrap()
+}
+
+#[test]
+fn urlencoded_form() {
+    let server = spawn();
+    let resp = post(&server, "application/x-www-form-urlencoded", "name=Ferris+the+crab&lang=Rust%21");
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "name=Ferris the crab\nlang=Rust!\n");
+}
+
+#[test]
+fn multipart_upload() {
+    let server = spawn();
+    let large = "x".repeat(100);
+    let body = format!(
+        "--boundary\r\n\
+         Content-Disposition: form-data; name=\"comment\"\r\n\r\n\
+         two files\r\n\
+         --boundary\r\n\
+         Content-Disposition: form-data; name=\"small\"; filename=\"small.txt\"\r\n\
+         Content-Type: text/plain\r\n\r\n\
+         tiny\r\n\
+         --boundary\r\n\
+         Content-Disposition: form-data; name=\"large\"; filename=\"large.txt\"\r\n\r\n\
+         {large}\r\n\
+         --boundary--\r\n"
+    );
+    let resp = post(&server, "multipart/form-data; boundary=boundary", &body);
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), format!("comment=two files\nsmall:small.txt=tiny\nlarge:large.txt={large}\n"));
+}
+
+#[test]
+fn rejected_forms() {
+    let server = spawn();
+
+    let body = format!("--b\r\nContent-Disposition: form-data; name=\"f\"\r\n\r\n{}\r\n--b--\r\n", "x".repeat(2000));
+    assert_eq!(post(&server, "multipart/form-data; boundary=b", &body).status, 413);
+
+    let body = "--b\r\nContent-Disposition: form-data; name=\"f\"\r\n\r\nunterminated";
+    assert_eq!(post(&server, "multipart/form-data; boundary=b", body).status, 400);
+    assert_eq!(post(&server, "multipart/form-data", "").status, 400);
+    assert_eq!(post(&server, "text/plain", "a=1").status, 415);
+}
@@ -0,0 +1,79 @@
+/** End-to-end tests for handlers that take and return JSON
+ */
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use webserver::client;
+use webserver::http::{Request, Response};
+use webserver::json;
+use webserver::router::Router;
+use webserver::server::{ServerConfig, ServerHandle};
+use webserver::testing::spawn_test_server;
+
+#[derive(Deserialize)]
+struct Greeting {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct Reply {
+    message: String,
+}
+
+fn spawn() -> ServerHandle {
+    let router = Router::new()
+        .post("/greet", json::handler(|_, greeting: Greeting| {
+            let message = format!("Hello, {}!", greeting.name);
+            Ok(Response::json(200, "OK", &Reply { message }))
+        }))
+        .get("/status", |_| Ok(Response::json(200, "OK", &serde_json::json!({ "up": true }))));
+    spawn_test_server(ServerConfig { router, max_body_size: 64, ..ServerConfig::default() })
+}
+
+fn post(server: &ServerHandle, content_type: &str, body: &str) -> Response {
+    let request = Request::new("POST", "/greet")
+        .with_header("Content-Type", content_type)
+        .with_body(body);
+    client::send(server.addr(), request).unwrap()
+}
+
+#[test]
+fn json_round_trip() {
+    let server = spawn();
+    let resp = post(&server, "application/json; charset=utf-8", r#"{"name": "Ferris"}"#);
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("Content-Type"), Some("application/json"));
+    let reply: Value = serde_json::from_slice(&resp.body).unwrap();
+    assert_eq!(reply["message"], "Hello, Ferris!");
+
+    let resp = client::get(server.addr(), "/status").unwrap();
+    assert_eq!(resp.text(), r#"{"up":true}"#);
+}
+
+#[test]
+fn malformed_json_is_a_bad_request() {
+    let server = spawn();
+    for body in [r#"{"name": "#, r#"{"nom": "Ferris"}"#, "[]"] {
+        let resp = post(&server, "application/json", body);
+        assert_eq!(resp.status, 400, "{body}");
+        assert_eq!(resp.header("Content-Type"), Some("application/json"));
+        let error: Value = serde_json::from_slice(&resp.body).unwrap();
+        assert!(error["error"].as_str().unwrap().starts_with("malformed JSON"));
+    }
+}
+
+#[test]
+fn wrong_content_type() {
+    let server = spawn();
+    let resp = post(&server, "text/plain", r#"{"name": "Ferris"}"#);
+    assert_eq!(resp.status, 415);
+}
+
+#[test]
+fn oversized_body() {
+    let server = spawn();
+    let body = format!(r#"{{"name": "{}"}}"#, "a".repeat(100));
+    let resp = post(&server, "application/json", &body);
+    assert_eq!(resp.status, 413);
+    assert_eq!(resp.header("Connection"), Some("close"));
+}
@@ -0,0 +1,191 @@
+/** End-to-end tests for proxy routes, with stand-in upstreams on their own
+ * ephemeral ports
+ */
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use webserver::client;
+use webserver::http::{Request, Response};
+use webserver::proxy::{Balance, Proxy, ProxyConfig, MIN_HEALTH_CHECK_INTERVAL};
+use webserver::router::Router;
+use webserver::server::{Server, ServerConfig, ServerHandle};
+use webserver::testing::spawn_test_server;
+
+/// An upstream that tells who it is and what it received
+fn upstream_router(name: &'static str) -> Router {
+    let describe = move |request: &Request| {
+        let body = format!(
+            "{name} {} {} host={} xff={}",
+            request.method,
+            request.target,
+            request.header("Host").unwrap_or("-"),
+            request.header("X-Forwarded-For").unwrap_or("-"),
+        );
+        Ok(Response::new(200, "OK").with_header("X-Upstream", name).with_body(body))
+    };
+    Router::new()
+        .get("/health", |_| Ok(Response::new(200, "OK")))
+        .get("/api/whoami", describe)
+        .post("/api/echo", |request| Ok(Response::new(201, "CREATED").with_body(request.body.clone())))
+        .get("/api/big", |_| Ok(Response::new(200, "OK").with_body(vec![b'x'; 4096])))
+        .get("/api/slow", move |_| {
+            thread::sleep(Duration::from_millis(500));
+            Ok(Response::new(200, "OK").with_header("X-Upstream", name))
+        })
+}
+
+fn spawn_upstream(name: &'static str) -> ServerHandle {
+    spawn_test_server(ServerConfig { router: upstream_router(name), ..ServerConfig::default() })
+}
+
+fn spawn_proxy(config: ProxyConfig) -> (ServerHandle, Proxy) {
+    let proxy = Proxy::new(ProxyConfig { health_check_path: String::from("/health"), ..config });
+    let router = Router::default().proxy("/api", proxy.clone());
+    (spawn_test_server(ServerConfig { router, ..ServerConfig::default() }), proxy)
+}
+
+/// An address nothing listens on
+fn dead_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn upstream_of(resp: &Response) -> String {
+    resp.header("X-Upstream").unwrap_or("none").to_string()
+}
+
+#[test]
+fn round_robin_with_rewritten_headers() {
+    let (a, b) = (spawn_upstream("a"), spawn_upstream("b"));
+    let config = ProxyConfig { upstreams: vec![a.addr(), b.addr()], ..ProxyConfig::default() };
+    let (server, _proxy) = spawn_proxy(config);
+
+    let names: Vec<String> = (0..4)
+        .map(|_| upstream_of(&client::get(server.addr(), "/api/whoami?x=1").unwrap()))
+        .collect();
+    assert_eq!(names, ["a", "b", "a", "b"]);
+
+    let request = Request::new("GET", "/api/whoami").with_header("X-Forwarded-For", "10.0.0.1");
+    let resp = client::send(server.addr(), request).unwrap();
+    let upstream = if upstream_of(&resp) == "a" { a.addr() } else { b.addr() };
+    assert!(resp.text().ends_with(&format!("host={upstream} xff=10.0.0.1, 127.0.0.1")));
+
+    let resp = client::send(server.addr(), Request::new("POST", "/api/echo").with_body("payload")).unwrap();
+    assert_eq!(resp.status, 201);
+    assert_eq!(resp.text(), "payload");
+
+    // only paths below the prefix are proxied
+    assert_eq!(client::get(server.addr(), "/apiary").unwrap().status, 404);
+}
+
+#[test]
+fn least_connections_avoids_busy_upstream() {
+    let (a, b) = (spawn_upstream("a"), spawn_upstream("b"));
+    let config = ProxyConfig {
+        upstreams: vec![a.addr(), b.addr()],
+        balance: Balance::LeastConnections,
+        ..ProxyConfig::default()
+    };
+    let (server, _proxy) = spawn_proxy(config);
+
+    let addr = server.addr();
+    let slow = thread::spawn(move || upstream_of(&client::get(addr, "/api/slow").unwrap()));
+    thread::sleep(Duration::from_millis(100));
+    let quick: Vec<String> = (0..3)
+        .map(|_| upstream_of(&client::get(addr, "/api/whoami").unwrap()))
+        .collect();
+    let busy = slow.join().unwrap();
+    assert!(quick.iter().all(|name| *name != busy), "{busy} served {quick:?}");
+}
+
+#[test]
+fn failed_upstream_is_skipped_until_healthy() {
+    let live = spawn_upstream("live");
+    let dead = dead_addr();
+    let config = ProxyConfig {
+        upstreams: vec![dead, live.addr()],
+        health_check_interval: Duration::from_millis(100),
+        ..ProxyConfig::default()
+    };
+    let (server, proxy) = spawn_proxy(config);
+
+    for _ in 0..3 {
+        let resp = client::get(server.addr(), "/api/whoami").unwrap();
+        assert_eq!(upstream_of(&resp), "live");
+    }
+    assert_eq!(proxy.healthy_upstreams(), [live.addr()]);
+
+    let config = ServerConfig {
+        addr: dead.to_string(),
+        router: upstream_router("revived"),
+        ..ServerConfig::default()
+    };
+    let _revived = Server::bind(config).unwrap().spawn();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(proxy.healthy_upstreams(), [dead, live.addr()]);
+    let names: Vec<String> = (0..2)
+        .map(|_| upstream_of(&client::get(server.addr(), "/api/whoami").unwrap()))
+        .collect();
+    assert!(names.contains(&String::from("revived")));
+}
+
+#[test]
+fn slow_upstream_times_out() {
+    let upstream = spawn_upstream("slow");
+    let config = ProxyConfig {
+        upstreams: vec![upstream.addr()],
+        timeout: Duration::from_millis(100),
+        ..ProxyConfig::default()
+    };
+    let (server, _proxy) = spawn_proxy(config);
+    assert_eq!(client::get(server.addr(), "/api/slow").unwrap().status, 504);
+}
+
+#[test]
+fn oversized_response_is_a_bad_gateway() {
+    let upstream = spawn_upstream("big");
+    let config = ProxyConfig {
+        upstreams: vec![upstream.addr()],
+        max_response_size: 1024,
+        ..ProxyConfig::default()
+    };
+    let (server, proxy) = spawn_proxy(config);
+    assert_eq!(client::get(server.addr(), "/api/big").unwrap().status, 502);
+    assert_eq!(proxy.healthy_upstreams(), [upstream.addr()]);
+    assert_eq!(client::get(server.addr(), "/api/whoami").unwrap().status, 200);
+}
+
+#[test]
+fn zero_health_check_interval_is_clamped() {
+    let checks = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&checks);
+    let router = Router::new().get("/health", move |_| {
+        counted.fetch_add(1, Ordering::SeqCst);
+        Ok(Response::new(200, "OK"))
+    });
+    let upstream = spawn_test_server(ServerConfig { router, ..ServerConfig::default() });
+    let config = ProxyConfig {
+        upstreams: vec![upstream.addr()],
+        health_check_interval: Duration::ZERO,
+        ..ProxyConfig::default()
+    };
+    let (_server, _proxy) = spawn_proxy(config);
+    thread::sleep(Duration::from_millis(500));
+    let max = (Duration::from_millis(500).as_millis() / MIN_HEALTH_CHECK_INTERVAL.as_millis()) as usize;
+    assert!((1..=max).contains(&checks.load(Ordering::SeqCst)));
+}
+
+#[test]
+fn no_reachable_upstream() {
+    let config = ProxyConfig { upstreams: vec![dead_addr(), dead_addr()], ..ProxyConfig::default() };
+    let (server, proxy) = spawn_proxy(config);
+    assert_eq!(client::get(server.addr(), "/api/whoami").unwrap().status, 502);
+    assert!(proxy.healthy_upstreams().is_empty());
+    // the 503 is the site's own page, from 503.html
+    let resp = client::get(server.addr(), "/api/whoami").unwrap();
+    assert_eq!(resp.status, 503);
+    let id = resp.header("X-Request-Id").unwrap();
+    assert!(resp.text().contains("Be right back") && resp.text().contains(&format!("Request id: {id}")));
+}
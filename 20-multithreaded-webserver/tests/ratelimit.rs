@@ -0,0 +1,112 @@
+/** End-to-end tests for per-route rate limits
+ */
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use webserver::client::{self, Client};
+use webserver::http::{Request, Response};
+use webserver::ratelimit::{RateKey, RateLimit};
+use webserver::router::Router;
+use webserver::server::{ServerConfig, ServerHandle};
+use webserver::testing::spawn_test_server;
+
+fn router() -> Router {
+    Router::new()
+        .get("/free", |_| Ok(Response::new(200, "OK")))
+        .get("/limited", |_| Ok(Response::new(200, "OK")))
+        .limit(RateLimit { burst: 2, per_second: 0.5, ..RateLimit::default() })
+        .get("/keyed", |_| Ok(Response::new(200, "OK")))
+        .limit(RateLimit {
+            burst: 1,
+            per_second: 0.5,
+            key: RateKey::Header(String::from("X-Api-Key")),
+            ..RateLimit::default()
+        })
+        .get("/slow", |_| {
+            thread::sleep(Duration::from_secs(1));
+            Ok(Response::new(200, "OK"))
+        })
+}
+
+fn spawn(config: ServerConfig) -> ServerHandle {
+    spawn_test_server(ServerConfig { router: router(), ..config })
+}
+
+fn keyed(key: &str) -> Request {
+    Request::new("GET", "/keyed").with_header("X-Api-Key", key)
+}
+
+#[test]
+fn limit_per_client_ip() {
+    let server = spawn(ServerConfig::default());
+    let mut client = Client::connect(server.addr()).unwrap();
+    assert_eq!(client.get("/limited").unwrap().status, 200);
+    assert_eq!(client.get("/limited").unwrap().status, 200);
+
+    let resp = client.get("/limited").unwrap();
+    assert_eq!(resp.status, 429);
+    assert_eq!(resp.header("Retry-After"), Some("2"));
+    // the connection stays usable and other routes are not limited
+    for _ in 0..5 {
+        assert_eq!(client.get("/free").unwrap().status, 200);
+    }
+}
+
+#[test]
+fn limit_per_header() {
+    let server = spawn(ServerConfig::default());
+    assert_eq!(client::send(server.addr(), keyed("alice")).unwrap().status, 200);
+    assert_eq!(client::send(server.addr(), keyed("alice")).unwrap().status, 429);
+    assert_eq!(client::send(server.addr(), keyed("bob")).unwrap().status, 200);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn rejected_requests_skip_the_pool() {
+    use webserver::server::ServerMode;
+
+    let server = spawn(ServerConfig { workers: 1, mode: ServerMode::EventLoop, ..ServerConfig::default() });
+    let addr = server.addr();
+    assert_eq!(client::send(addr, keyed("carol")).unwrap().status, 200);
+
+    // keep the only worker busy
+    let slow = thread::spawn(move || client::get(addr, "/slow").unwrap().status);
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    assert_eq!(client::send(addr, keyed("carol")).unwrap().status, 429);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(slow.join().unwrap(), 200);
+}
+
+/// In Blocking mode a worker holds a connection for as long as it lasts, so
+/// a client out of tokens has its new connections answered on the accept
+/// thread. Requests on a connection it already has are still checked by
+/// its worker, before the handler
+#[test]
+fn blocking_mode_turns_away_at_accept() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&runs);
+    let router = router()
+        .get("/counted", move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(Response::new(200, "OK"))
+        })
+        .limit(RateLimit { burst: 1, per_second: 0.5, ..RateLimit::default() });
+    let server = spawn_test_server(ServerConfig { workers: 1, router, ..ServerConfig::default() });
+    let addr = server.addr();
+
+    // the kept-alive connection keeps the only worker busy
+    let mut held = Client::connect(addr).unwrap();
+    assert_eq!(held.get("/counted").unwrap().status, 200);
+    let start = Instant::now();
+    for path in ["/counted", "/free"] {
+        let resp = client::get(addr, path).unwrap();
+        assert_eq!(resp.status, 429);
+        assert_eq!(resp.header("Retry-After"), Some("2"));
+    }
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(held.get("/counted").unwrap().status, 429);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
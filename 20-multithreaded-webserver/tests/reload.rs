@@ -0,0 +1,127 @@
+/** End-to-end tests for swapping the config of a running server
+ */
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use webserver::client;
+use webserver::config;
+use webserver::http::Response;
+use webserver::reload::Watcher;
+use webserver::router::Router;
+use webserver::server::{Server, ServerConfig};
+use webserver::testing::TempDir;
+
+/// A config file serving "sites/a", next to a second root "sites/b"
+fn config_file(test: &str) -> TempDir {
+    let dir = TempDir::new(&format!("reload-{test}"));
+    for site in ["a", "b"] {
+        fs::create_dir_all(dir.path().join("sites").join(site)).unwrap();
+        fs::write(dir.path().join("sites").join(site).join("index.html"), format!("site {site}")).unwrap();
+    }
+    write_config(dir.path(), "a");
+    dir
+}
+
+fn write_config(dir: &Path, site: &str) {
+    let text = format!(
+        "addr = \"127.0.0.1:0\"\n[[hosts]]\nnames = [\"x.test\"]\nroot = \"sites/{site}\"\ndefault = true\n"
+    );
+    fs::write(dir.join("server.toml"), text).unwrap();
+}
+
+fn home(addr: std::net::SocketAddr) -> String {
+    client::get(addr, "/").unwrap().text()
+}
+
+/// Poll until the server answers "/" with "expected"
+fn wait_for(addr: std::net::SocketAddr, expected: &str) {
+    let start = Instant::now();
+    while home(addr) != expected {
+        assert!(start.elapsed() < Duration::from_secs(5), "still not serving {expected:?}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn requests_in_flight_finish_on_old_config() {
+    let (entered, started) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let (entered, released) = (Mutex::new(entered), Arc::new(Mutex::new(released)));
+    let old = Router::new().get("/", move |_| {
+        entered.lock().unwrap().send(()).unwrap();
+        released.lock().unwrap().recv().unwrap();
+        Ok(Response::new(200, "OK").with_body("old"))
+    });
+    let config = ServerConfig { addr: String::from("127.0.0.1:0"), router: old, ..ServerConfig::default() };
+    let server = Server::bind(config).unwrap();
+    let reloader = server.reloader();
+    let server = server.spawn();
+    let addr = server.addr();
+
+    let slow = thread::spawn(move || home(addr));
+    started.recv().unwrap();
+    let new = Router::new().get("/", |_| Ok(Response::new(200, "OK").with_body("new")));
+    let config = ServerConfig { addr: String::from("127.0.0.1:0"), router: new, ..ServerConfig::default() };
+    reloader.apply(config);
+    assert_eq!(home(addr), "new");
+
+    release.send(()).unwrap();
+    assert_eq!(slow.join().unwrap(), "old");
+}
+
+#[test]
+fn file_changes_are_picked_up() {
+    let dir = config_file("watch");
+    let path = dir.path().join("server.toml");
+    let server = Server::bind(config::load(&path).unwrap()).unwrap();
+    Watcher::new(&path, server.reloader()).interval(Duration::from_millis(20)).spawn();
+    let server = server.spawn();
+    assert_eq!(home(server.addr()), "site a");
+
+    write_config(dir.path(), "b");
+    wait_for(server.addr(), "site b");
+}
+
+#[test]
+fn invalid_config_keeps_running_one() {
+    let dir = config_file("invalid");
+    let path = dir.path().join("server.toml");
+    let server = Server::bind(config::load(&path).unwrap()).unwrap();
+    let reloader = server.reloader();
+    let server = server.spawn();
+
+    fs::write(&path, "[[hosts]]\nnames = [\"x.test\"]\nroot = \"sites/missing\"\n").unwrap();
+    let err = reloader.reload_from(&path).unwrap_err();
+    assert!(err.to_string().contains("sites/missing"), "{err}");
+    fs::write(&path, "workers = \"many\"").unwrap();
+    assert!(reloader.reload_from(&path).is_err());
+    assert_eq!(home(server.addr()), "site a");
+}
+
+#[cfg(unix)]
+#[test]
+fn sighup_reloads() {
+    let dir = config_file("sighup");
+    let path = dir.path().join("server.toml");
+    let server = Server::bind(config::load(&path).unwrap()).unwrap();
+    Watcher::new(&path, server.reloader()).interval(Duration::from_millis(20)).spawn();
+    let server = server.spawn();
+
+    // same length and modification time, so only the signal gives it away
+    let modified = fs::metadata(&path).unwrap().modified().unwrap();
+    write_config(dir.path(), "b");
+    fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(home(server.addr()), "site a");
+
+    // SAFETY: the watcher has replaced the default handler, which would
+    // end the test process
+    unsafe {
+        libc::raise(libc::SIGHUP);
+    }
+    wait_for(server.addr(), "site b");
+}
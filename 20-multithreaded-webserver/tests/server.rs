@@ -0,0 +1,150 @@
+/** End-to-end tests that start the server in-process on an ephemeral port
+ * and talk to it with the crate's own HTTP client
+ */
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use webserver::client::{self, Client};
+use webserver::http::{Request, Response};
+use webserver::server::ServerConfig;
+use webserver::testing::spawn_test_server;
+
+#[test]
+fn routes() {
+    let server = spawn_test_server(ServerConfig::default());
+
+    let resp = client::get(server.addr(), "/").unwrap();
+    assert_eq!(resp.status, 200);
+    assert!(resp.text().contains("Hi from Rust"));
+
+    let resp = client::get(server.addr(), "/nowhere").unwrap();
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.reason, "NOT FOUND");
+    assert!(resp.text().contains("Oops!"));
+}
+
+#[test]
+fn keep_alive() {
+    let server = spawn_test_server(ServerConfig::default());
+    let mut client = Client::connect(server.addr()).unwrap();
+
+    for _ in 0..3 {
+        let resp = client.get("/").unwrap();
+        assert_eq!(resp.status, 200);
+        assert!(!client.is_closed());
+    }
+
+    let resp = client.send(Request::new("GET", "/").with_header("Connection", "close")).unwrap();
+    assert_eq!(resp.header("Connection"), Some("close"));
+    assert!(client.is_closed());
+}
+
+#[test]
+fn idle_connection_times_out() {
+    let config = ServerConfig {
+        keep_alive_timeout: Duration::from_millis(100),
+        ..ServerConfig::default()
+    };
+    let server = spawn_test_server(config);
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+
+    thread::sleep(Duration::from_millis(300));
+    let mut buf = Vec::new();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(stream.read_to_end(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn malformed_request() {
+    let server = spawn_test_server(ServerConfig::default());
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.write_all(b"nonsense\r\n\r\n").unwrap();
+
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 400 BAD REQUEST\r\n"));
+}
+
+#[test]
+fn unsupported_version() {
+    let server = spawn_test_server(ServerConfig::default());
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.write_all(b"GET / HTTP/2.0\r\n\r\n").unwrap();
+
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 505 HTTP VERSION NOT SUPPORTED\r\n"), "{resp}");
+}
+
+/// What the legacy monitoring probes send
+#[test]
+fn http10_head_probe() {
+    let server = spawn_test_server(ServerConfig::default());
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.write_all(b"HEAD / HTTP/1.0\r\n\r\n").unwrap();
+
+    // no keep-alive for HTTP/1.0, so the server closes after the headers
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    let full = client::get(server.addr(), "/").unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+    assert!(resp.contains(&format!("Content-Length: {}\r\n", full.body.len())), "{resp}");
+    assert!(resp.ends_with("Connection: close\r\n\r\n"), "{resp}");
+}
+
+#[test]
+fn http10_keep_alive() {
+    let server = spawn_test_server(ServerConfig::default());
+    let mut reader = BufReader::new(TcpStream::connect(server.addr()).unwrap());
+    for _ in 0..2 {
+        let request = b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+        reader.get_mut().write_all(request).unwrap();
+        let resp = Response::read_from(&mut reader).unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.header("Connection"), Some("keep-alive"));
+    }
+}
+
+#[test]
+fn options_and_allow() {
+    let server = spawn_test_server(ServerConfig::default());
+    let resp = client::send(server.addr(), Request::new("OPTIONS", "*")).unwrap();
+    assert_eq!(resp.status, 204);
+    assert_eq!(resp.header("Allow"), Some("GET, HEAD, OPTIONS"));
+
+    let resp = client::send(server.addr(), Request::new("DELETE", "/hello")).unwrap();
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("Allow"), Some("GET, HEAD, OPTIONS"));
+    let resp = client::send(server.addr(), Request::new("OPTIONS", "/nowhere")).unwrap();
+    assert_eq!(resp.status, 404);
+}
+
+#[test]
+fn shutdown_waits_for_in_flight_requests() {
+    let server = spawn_test_server(ServerConfig::default());
+    let addr = server.addr();
+
+    let slow = thread::spawn(move || client::get(addr, "/busybox"));
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    server.shutdown();
+    assert!(start.elapsed() >= Duration::from_secs(3));
+
+    let resp = slow.join().unwrap().unwrap();
+    assert_eq!(resp.status, 200);
+    assert!(client::get(addr, "/").is_err());
+}
+
+#[test]
+fn max_requests() {
+    let config = ServerConfig { max_requests: 2, ..ServerConfig::default() };
+    let server = spawn_test_server(config);
+
+    assert_eq!(client::get(server.addr(), "/").unwrap().status, 200);
+    assert_eq!(client::get(server.addr(), "/").unwrap().status, 200);
+    thread::sleep(Duration::from_millis(100));
+    assert!(client::get(server.addr(), "/").is_err());
+}
@@ -0,0 +1,77 @@
+/** End-to-end tests for rendering templates from a directory
+ */
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::json;
+use webserver::client;
+use webserver::http::Request;
+use webserver::router::Router;
+use webserver::server::ServerConfig;
+use webserver::template::Templates;
+use webserver::testing::{spawn_test_server, TempDir};
+
+/// A fresh templates directory for one test
+fn template_dir(test: &str) -> TempDir {
+    let dir = TempDir::new(&format!("templates-{test}"));
+    fs::create_dir_all(dir.path().join("partials")).unwrap();
+    let nav = "<nav>{% for link in links %}[{{ link }}]{% endfor %}</nav>";
+    fs::write(dir.path().join("partials/nav.html"), nav).unwrap();
+    fs::write(
+        dir.path().join("page.html"),
+        "{% include \"partials/nav.html\" %}<p>{{ greeting }}, {{ name }}!</p>",
+    )
+    .unwrap();
+    dir
+}
+
+#[test]
+fn render_with_includes() {
+    let dir = template_dir("includes");
+    let templates = Templates::new(dir.path());
+    let context = json!({"links": ["a", "b"], "greeting": "Hi", "name": "<you>"});
+    assert_eq!(
+        templates.render("page.html", &context).unwrap(),
+        "<nav>[a][b]</nav><p>Hi, &lt;you&gt;!</p>"
+    );
+    assert!(templates.render("missing.html", &context).is_err());
+    assert!(templates.render("../page.html", &context).is_err());
+}
+
+#[test]
+fn hot_reload_picks_up_changes() {
+    let dir = template_dir("reload");
+    let cached = Templates::new(dir.path());
+    let reloading = Templates::new(dir.path()).hot_reload(true);
+    let context = json!({"greeting": "Hi", "name": "there"});
+    assert!(cached.render("page.html", &context).unwrap().ends_with("<p>Hi, there!</p>"));
+    assert!(reloading.render("page.html", &context).unwrap().ends_with("<p>Hi, there!</p>"));
+
+    // make sure the modification time moves on
+    thread::sleep(Duration::from_millis(20));
+    fs::write(dir.path().join("partials/nav.html"), "<nav>changed</nav>").unwrap();
+    fs::write(dir.path().join("page.html"), "{% include \"partials/nav.html\" %}{{ name }}").unwrap();
+    assert!(cached.render("page.html", &context).unwrap().ends_with("<p>Hi, there!</p>"));
+    assert_eq!(reloading.render("page.html", &context).unwrap(), "<nav>changed</nav>there");
+}
+
+#[test]
+fn handler_renders_request_data() {
+    let dir = template_dir("handler");
+    let templates = Arc::new(Templates::new(dir.path()));
+    let router = Router::new()
+        .get("/greet", move |request: &Request| {
+            let name = request.header("X-Name").unwrap_or("stranger");
+            templates.page(200, "OK", "page.html", &json!({"greeting": "Hello", "name": name}))
+        })
+        .get("/broken", |_| Templates::new("no-such-dir").page(200, "OK", "page.html", &json!({})));
+    let server = spawn_test_server(ServerConfig { router, ..ServerConfig::default() });
+
+    let resp = client::send(server.addr(), Request::new("GET", "/greet").with_header("X-Name", "Ferris")).unwrap();
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(resp.text(), "<nav></nav><p>Hello, Ferris!</p>");
+    assert_eq!(client::get(server.addr(), "/broken").unwrap().status, 500);
+}
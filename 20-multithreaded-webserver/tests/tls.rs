@@ -0,0 +1,112 @@
+/** End-to-end tests for the HTTPS listener. Each test generates its own
+ * self-signed certificate for "localhost", starts a server on ephemeral
+ * ports in a background thread, and talks to it over real sockets
+ */
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, ServerName};
+use webserver::server::{Server, ServerConfig, ServerHandle};
+use webserver::testing::{spawn_test_server, TempDir};
+use webserver::tls::TlsConfig;
+
+/// Write a fresh self-signed certificate and its key to a temporary
+/// directory and return the certificate in DER form for the client to trust.
+/// The directory has to outlive binding the server, which loads them
+fn self_signed(name: &str) -> (TempDir, TlsConfig, CertificateDer<'static>) {
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+    let dir = TempDir::new(&format!("tls-{name}"));
+    let cert_path = dir.path().join("cert.pem");
+    let key_path = dir.path().join("key.pem");
+    fs::write(&cert_path, cert.pem()).unwrap();
+    fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+
+    let tls = TlsConfig {
+        addr: String::from("127.0.0.1:0"),
+        cert_path,
+        key_path,
+        redirect_http: false,
+    };
+    (dir, tls, cert.der().clone())
+}
+
+fn spawn(tls: TlsConfig) -> ServerHandle {
+    spawn_test_server(ServerConfig { tls: Some(tls), ..ServerConfig::default() })
+}
+
+fn https_get(addr: SocketAddr, cert: CertificateDer<'static>, path: &str) -> String {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from("localhost").unwrap();
+    let conn = rustls::ClientConnection::new(Arc::new(config), name).unwrap();
+    let sock = TcpStream::connect(addr).unwrap();
+    let mut stream = rustls::StreamOwned::new(conn, sock);
+
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+fn http_get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n", addr.port()).unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+#[test]
+fn serves_https() {
+    let (_dir, tls, cert) = self_signed("https");
+    let server = spawn(tls);
+    let https = server.tls_addr().unwrap();
+
+    let resp = https_get(https, cert.clone(), "/");
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.contains("Hi from Rust"));
+
+    let resp = https_get(https, cert, "/nowhere");
+    assert!(resp.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+}
+
+#[test]
+fn serves_http_alongside_https() {
+    let (_dir, tls, _) = self_signed("both");
+    let server = spawn(tls);
+
+    let resp = http_get(server.addr(), "/");
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.contains("Hi from Rust"));
+}
+
+#[test]
+fn redirects_http_to_https() {
+    let (_dir, tls, _) = self_signed("redirect");
+    let server = spawn(TlsConfig { redirect_http: true, ..tls });
+    let https = server.tls_addr().unwrap();
+
+    let resp = http_get(server.addr(), "/busybox");
+    assert!(resp.starts_with("HTTP/1.1 301 MOVED PERMANENTLY\r\n"));
+    let location = format!("Location: https://localhost:{}/busybox\r\n", https.port());
+    assert!(resp.contains(&location), "{resp}");
+}
+
+#[test]
+fn rejects_missing_certificate() {
+    let (_dir, tls, _) = self_signed("missing");
+    let tls = TlsConfig { cert_path: tls.cert_path.with_file_name("nope.pem"), ..tls };
+    let config = ServerConfig {
+        addr: String::from("127.0.0.1:0"),
+        tls: Some(tls),
+        ..ServerConfig::default()
+    };
+    assert!(Server::bind(config).is_err());
+}
@@ -0,0 +1,81 @@
+/** End-to-end tests for virtual hosts, set up through a config file
+ */
+use std::fs;
+
+use webserver::client;
+use webserver::config;
+use webserver::http::{Request, Response};
+use webserver::router::Router;
+use webserver::server::ServerConfig;
+use webserver::testing::{spawn_test_server, TempDir};
+use webserver::vhost::VirtualHost;
+
+const CONFIG: &str = r#"
+workers = 2
+
+[[hosts]]
+names = ["docs.test", "*.docs.test"]
+root = "sites/docs"
+
+[[hosts]]
+names = ["artifacts.test"]
+root = "sites/artifacts"
+list = [""]
+default = true
+"#;
+
+/// A config file next to two document roots
+fn config_file(test: &str) -> TempDir {
+    let dir = TempDir::new(&format!("vhost-{test}"));
+    fs::create_dir_all(dir.path().join("sites/docs")).unwrap();
+    fs::create_dir_all(dir.path().join("sites/artifacts")).unwrap();
+    fs::write(dir.path().join("sites/docs/index.html"), "docs home").unwrap();
+    fs::write(dir.path().join("sites/artifacts/build.log"), "built").unwrap();
+    fs::write(dir.path().join("server.toml"), CONFIG).unwrap();
+    dir
+}
+
+fn get(addr: std::net::SocketAddr, host: &str, path: &str) -> Response {
+    client::send(addr, Request::new("GET", path).with_header("Host", host)).unwrap()
+}
+
+#[test]
+fn hosts_from_config_file() {
+    let dir = config_file("file");
+    let config = config::load(dir.path().join("server.toml")).unwrap();
+    assert_eq!(config.workers, 2);
+    let server = spawn_test_server(config);
+    let addr = server.addr();
+
+    assert_eq!(get(addr, "docs.test", "/").text(), "docs home");
+    assert_eq!(get(addr, "DOCS.test:8080", "/").text(), "docs home");
+    assert_eq!(get(addr, "v2.docs.test", "/").text(), "docs home");
+    assert_eq!(get(addr, "docs.test", "/build.log").status, 404);
+
+    assert_eq!(get(addr, "artifacts.test", "/build.log").text(), "built");
+    assert!(get(addr, "artifacts.test", "/").text().contains("build.log"));
+    // unknown hosts go to the default host
+    assert_eq!(get(addr, "elsewhere.test", "/build.log").text(), "built");
+}
+
+#[test]
+fn hosts_with_their_own_routers() {
+    let api = Router::new().get("/", |_| Ok(Response::new(200, "OK").with_body("api")));
+    let config = ServerConfig {
+        hosts: vec![VirtualHost::new(&["api.test"], api)],
+        ..ServerConfig::default()
+    };
+    let server = spawn_test_server(config);
+    assert_eq!(get(server.addr(), "api.test", "/").text(), "api");
+    // the built-in pages remain the fallback
+    assert!(get(server.addr(), "other.test", "/").text().contains("Hi from Rust"));
+}
+
+#[test]
+fn invalid_config_file() {
+    let dir = config_file("invalid");
+    let config = "[[hosts]]\nnames = [\"x.test\"]\nroot = \"sites/missing\"\n";
+    fs::write(dir.path().join("server.toml"), config).unwrap();
+    let err = config::load(dir.path().join("server.toml")).unwrap_err();
+    assert!(err.to_string().contains("sites/missing"), "{err}");
+}
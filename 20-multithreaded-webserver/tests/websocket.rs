@@ -0,0 +1,152 @@
+/** End-to-end tests for WebSocket routes, using the crate's own WebSocket
+ * client
+ */
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use webserver::client;
+use webserver::router::Router;
+use webserver::server::{ServerConfig, ServerHandle};
+use webserver::testing::spawn_test_server;
+use webserver::websocket::{self, Frame, Message, Runner, WebSocket, OP_CONTINUATION, OP_PING, OP_PONG, OP_TEXT};
+
+/// Echo text and binary messages until the client closes
+fn echo(mut ws: WebSocket) {
+    while let Ok(message) = ws.recv() {
+        let reply = match message {
+            Message::Text(text) => Message::Text(text),
+            Message::Binary(data) => Message::Binary(data),
+            Message::Close(_) => return,
+            _ => continue,
+        };
+        if ws.send(reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn spawn(workers: usize) -> ServerHandle {
+    let router = Router::default()
+        .websocket("/echo", Runner::DedicatedThread, echo)
+        .websocket("/countdown", Runner::Worker, |mut ws| {
+            for n in (1..=3).rev() {
+                ws.send_text(&n.to_string()).unwrap();
+            }
+            ws.close(websocket::CLOSE_NORMAL, "liftoff").unwrap();
+            while !matches!(ws.recv(), Ok(Message::Close(_)) | Err(_)) {}
+        });
+    spawn_test_server(ServerConfig { workers, router, ..ServerConfig::default() })
+}
+
+#[test]
+fn echo_messages() {
+    let server = spawn(4);
+    let mut ws = websocket::connect(server.addr(), "/echo").unwrap();
+
+    ws.send_text("hello").unwrap();
+    assert_eq!(ws.recv().unwrap(), Message::Text(String::from("hello")));
+    let large = vec![42u8; 70_000];
+    ws.send_binary(&large).unwrap();
+    assert_eq!(ws.recv().unwrap(), Message::Binary(large));
+
+    ws.ping(b"are you there").unwrap();
+    assert_eq!(ws.recv().unwrap(), Message::Pong(b"are you there".to_vec()));
+
+    ws.close(websocket::CLOSE_NORMAL, "bye").unwrap();
+    assert_eq!(ws.recv().unwrap(), Message::Close(Some((websocket::CLOSE_NORMAL, String::new()))));
+    assert!(ws.is_closed());
+}
+
+#[test]
+fn server_initiated_close() {
+    let server = spawn(4);
+    let mut ws = websocket::connect(server.addr(), "/countdown").unwrap();
+    for n in ["3", "2", "1"] {
+        assert_eq!(ws.recv().unwrap(), Message::Text(n.to_string()));
+    }
+    assert_eq!(
+        ws.recv().unwrap(),
+        Message::Close(Some((websocket::CLOSE_NORMAL, String::from("liftoff"))))
+    );
+    assert!(ws.is_closed());
+}
+
+/// Send the opening handshake on a raw connection and read the 101, so that
+/// frames can be written by hand
+fn upgrade(stream: &mut TcpStream) {
+    let key = "dGhlIHNhbXBsZSBub25jZQ==";
+    write!(
+        stream,
+        "GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+         Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )
+    .unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101 SWITCHING PROTOCOLS\r\n"));
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+}
+
+#[test]
+fn fragmented_message() {
+    let server = spawn(4);
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    upgrade(&mut stream);
+    let first = Frame { fin: false, opcode: OP_TEXT, payload: b"Hel".to_vec() };
+    let rest = Frame { fin: true, opcode: OP_CONTINUATION, payload: b"lo".to_vec() };
+    websocket::write_frame(&mut stream, &first, Some([1, 2, 3, 4])).unwrap();
+    websocket::write_frame(&mut stream, &rest, Some([5, 6, 7, 8])).unwrap();
+
+    let (frame, masked) = websocket::read_frame(&mut stream, 1024).unwrap();
+    assert!(!masked);
+    assert_eq!(frame, Frame { fin: true, opcode: OP_TEXT, payload: b"Hello".to_vec() });
+}
+
+#[test]
+fn ping_between_fragments() {
+    let server = spawn(4);
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    upgrade(&mut stream);
+    let frames = [
+        Frame { fin: false, opcode: OP_TEXT, payload: b"Hel".to_vec() },
+        Frame { fin: true, opcode: OP_PING, payload: b"still there?".to_vec() },
+        Frame { fin: true, opcode: OP_CONTINUATION, payload: b"lo".to_vec() },
+    ];
+    for frame in &frames {
+        websocket::write_frame(&mut stream, frame, Some([1, 2, 3, 4])).unwrap();
+    }
+
+    let (pong, _) = websocket::read_frame(&mut stream, 1024).unwrap();
+    assert_eq!(pong, Frame { fin: true, opcode: OP_PONG, payload: b"still there?".to_vec() });
+    let (frame, _) = websocket::read_frame(&mut stream, 1024).unwrap();
+    assert_eq!(frame, Frame { fin: true, opcode: OP_TEXT, payload: b"Hello".to_vec() });
+}
+
+#[test]
+fn plain_request_to_websocket_route() {
+    let server = spawn(4);
+    let resp = client::get(server.addr(), "/echo").unwrap();
+    assert_eq!(resp.status, 426);
+    assert_eq!(resp.header("Upgrade"), Some("websocket"));
+}
+
+#[test]
+fn dedicated_threads_leave_workers_free() {
+    let server = spawn(1);
+    let sockets: Vec<WebSocket> = (0..5)
+        .map(|_| websocket::connect(server.addr(), "/echo").unwrap())
+        .collect();
+
+    let resp = client::get(server.addr(), "/").unwrap();
+    assert_eq!(resp.status, 200);
+
+    for mut ws in sockets {
+        ws.send_text("still here").unwrap();
+        assert_eq!(ws.recv().unwrap(), Message::Text(String::from("still here")));
+    }
+}